@@ -3,18 +3,32 @@ use std::path::PathBuf;
 use color_eyre::eyre::Context;
 use once_cell::sync::Lazy;
 use reqwest::Url;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
+
+use crate::notify::SinkConfig;
+use crate::scraper::DetailStrategy;
+use crate::session::SessionConfig;
+use crate::store::StorageBackend;
 
 #[derive(Deserialize)]
 pub struct Config {
-    pub cookie: String,
-    pub webhook_url: Url,
+    pub cookie: Option<String>,
+    /// JSON list of extra sessions, see `session`.
+    #[serde(default, deserialize_with = "from_json")]
+    pub cookies: Vec<SessionConfig>,
+    pub webhook_url: Option<Url>,
+    pub discord_webhook_url: Option<Url>,
+    /// JSON list of notification sinks, see `notify`.
+    #[serde(default, deserialize_with = "from_json")]
+    pub notifiers: Vec<SinkConfig>,
     #[serde(default = "default_user_agent")]
     pub user_agent: String,
     #[serde(default = "default_base_url")]
     pub base_url: Url,
     #[serde(default = "default_storage_path")]
     pub storage_path: PathBuf,
+    #[serde(default)]
+    pub storage_backend: StorageBackend,
     pub sentry_dsn: Option<String>,
     pub openai_api_key: Option<String>,
     pub openai_base_url: Option<Url>,
@@ -23,6 +37,68 @@ pub struct Config {
     pub cdn_key: String,
     #[serde(default = "default_cdn_base_url")]
     pub cdn_base_url: Url,
+    /// Saves every Flavortown request/response of the run into this directory.
+    pub record_dir: Option<PathBuf>,
+    /// Answers Flavortown requests from a directory written with `RECORD_DIR` instead of the
+    /// network. CDN uploads, AI summaries and webhooks are skipped.
+    pub replay_dir: Option<PathBuf>,
+    #[serde(default = "default_daemon_interval_secs")]
+    pub daemon_interval_secs: u64,
+    #[serde(default = "default_daemon_jitter_secs")]
+    pub daemon_jitter_secs: u64,
+    /// How many runs in a row may fail before the daemon backs off for `daemon_failure_cooldown_secs`.
+    #[serde(default = "default_daemon_max_failures")]
+    pub daemon_max_failures: u32,
+    #[serde(default = "default_daemon_failure_cooldown_secs")]
+    pub daemon_failure_cooldown_secs: u64,
+    /// How often the daemon runs `storage gc`. 0 leaves it to you.
+    #[serde(default = "default_daemon_gc_interval_secs")]
+    pub daemon_gc_interval_secs: u64,
+    #[serde(default = "default_http_addr")]
+    pub http_addr: String,
+    /// How many Flavortown requests may be in flight at once.
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    /// Flavortown requests per second, per host. 0 turns the limit off.
+    #[serde(default = "default_max_requests_per_second")]
+    pub max_requests_per_second: f64,
+    /// Minimum time between switching the shop to another region.
+    #[serde(default = "default_region_switch_delay_ms")]
+    pub region_switch_delay_ms: u64,
+    #[serde(default)]
+    pub detail_strategy: DetailStrategy,
+    /// `storage gc` keeps every snapshot younger than this...
+    #[serde(default = "default_retain_all_days")]
+    pub retain_all_days: u64,
+    /// ...then the last one of each hour up to this age...
+    #[serde(default = "default_retain_hourly_days")]
+    pub retain_hourly_days: u64,
+    /// ...then the last one of each day, up to this age if it's set.
+    pub retain_daily_days: Option<u64>,
+    /// Snapshots older than this are compressed by `storage gc`.
+    #[serde(default = "default_compress_after_days")]
+    pub compress_after_days: u64,
+    /// Also write an RSS 2.0 feed next to the Atom one.
+    #[serde(default)]
+    pub feed_rss: bool,
+    /// Where Slack sinks with a bot token send their API calls.
+    #[serde(default = "default_slack_api_url")]
+    pub slack_api_url: Url,
+    /// When a Slack sink with a bot token announced an item less than this many seconds ago and
+    /// it changes again, the announcement is edited instead of a new message posted. 0 turns
+    /// edits off.
+    #[serde(default = "default_slack_edit_window_secs")]
+    pub slack_edit_window_secs: i64,
+}
+
+/// Env vars are flat strings, so nested settings are passed as JSON.
+fn from_json<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    let raw = String::deserialize(deserializer)?;
+    serde_json::from_str(&raw).map_err(serde::de::Error::custom)
 }
 
 fn default_user_agent() -> String {
@@ -45,6 +121,62 @@ fn default_cdn_base_url() -> Url {
     Url::parse("https://cdn.hackclub.com/api/file").unwrap()
 }
 
+fn default_daemon_interval_secs() -> u64 {
+    100
+}
+
+fn default_daemon_jitter_secs() -> u64 {
+    20
+}
+
+fn default_daemon_max_failures() -> u32 {
+    5
+}
+
+fn default_daemon_failure_cooldown_secs() -> u64 {
+    30 * 60
+}
+
+fn default_daemon_gc_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_http_addr() -> String {
+    "0.0.0.0:8080".into()
+}
+
+fn default_max_concurrent_requests() -> usize {
+    4
+}
+
+fn default_max_requests_per_second() -> f64 {
+    5.0
+}
+
+fn default_region_switch_delay_ms() -> u64 {
+    2000
+}
+
+fn default_retain_all_days() -> u64 {
+    7
+}
+
+fn default_retain_hourly_days() -> u64 {
+    30
+}
+
+fn default_compress_after_days() -> u64 {
+    1
+}
+
+fn default_slack_api_url() -> Url {
+    Url::parse("https://slack.com/api").unwrap()
+}
+
+fn default_slack_edit_window_secs() -> i64 {
+    60 * 60
+}
+
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
     envy::from_env::<Config>()
         .wrap_err("failed to load config")
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::gc;
+use crate::store::{STORE, StorageBackend};
+use color_eyre::Result;
+use log::{error, info, warn};
+use tokio::signal;
+
+/// Resolves on the first SIGINT or SIGTERM. The handlers are installed straight away, so a signal
+/// during the first run isn't missed.
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    {
+        use signal::unix::{SignalKind, signal};
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+        Ok(async move {
+            tokio::select! {
+                _ = interrupt.recv() => {}
+                _ = terminate.recv() => {}
+            }
+        })
+    }
+    #[cfg(not(unix))]
+    Ok(async {
+        let _ = signal::ctrl_c().await;
+    })
+}
+
+/// Runs `storage gc` if it's due. It can't run as a command of its own while the daemon holds the
+/// storage lock, so the daemon does it between runs.
+async fn collect_garbage_if_due(last: &mut Option<Instant>) {
+    let interval = Duration::from_secs(CONFIG.daemon_gc_interval_secs);
+    if interval.is_zero()
+        || CONFIG.storage_backend != StorageBackend::Files
+        || last.is_some_and(|last| last.elapsed() < interval)
+    {
+        return;
+    }
+    *last = Some(Instant::now());
+    info!("Collecting garbage");
+    // it's all blocking file IO, which would hold up the runtime's other tasks.
+    match tokio::task::spawn_blocking(|| gc::collect_garbage(false)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => error!("Garbage collection failed: {e:?}"),
+        Err(e) => error!("Garbage collection panicked: {e}"),
+    }
+}
+
+/// Runs scrape jobs on an interval until SIGINT/SIGTERM, collecting garbage every
+/// `DAEMON_GC_INTERVAL_SECS`.
+///
+/// Everything that is expensive to set up - the HTTP client, the sled CDN cache, the CSRF token
+/// and the latest snapshot - stays in memory between runs. Runs never overlap: the next one is
+/// only scheduled once the previous one has finished, and the storage lock keeps other processes
+/// out. A signal during a run lets it finish first, so it never stops halfway through writing.
+pub async fn run() -> Result<()> {
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+
+    let mut latest = STORE.load_latest()?;
+    let mut consecutive_failures = 0;
+    let mut last_gc = None;
+
+    info!(
+        "Daemon started - scraping every {}s (+ up to {}s jitter)",
+        CONFIG.daemon_interval_secs, CONFIG.daemon_jitter_secs
+    );
+
+    loop {
+        match crate::run_once(&mut latest).await {
+            Ok(()) => consecutive_failures = 0,
+            Err(e) => {
+                consecutive_failures += 1;
+                error!("Scrape job failed ({consecutive_failures} in a row): {e:?}");
+            }
+        }
+        collect_garbage_if_due(&mut last_gc).await;
+
+        let delay = if consecutive_failures >= CONFIG.daemon_max_failures {
+            warn!(
+                "{consecutive_failures} scrape jobs failed in a row - backing off for {}s",
+                CONFIG.daemon_failure_cooldown_secs
+            );
+            consecutive_failures = 0;
+            Duration::from_secs(CONFIG.daemon_failure_cooldown_secs)
+        } else {
+            Duration::from_secs(
+                CONFIG.daemon_interval_secs + fastrand::u64(0..=CONFIG.daemon_jitter_secs),
+            )
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                return Ok(());
+            }
+        }
+    }
+}
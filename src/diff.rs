@@ -1,451 +1,520 @@
-use std::collections::HashMap;
-
-use crate::config::CONFIG;
-use crate::scraper::{Accessory, Region, ShopItem, ShopItems};
-use color_eyre::Result;
-use log::{debug, info};
-use slack_morphism::prelude::*;
-use strum::VariantArray;
-
-const EMOJI_COOKIES: &str = ":cookie:";
-const EMOJI_TROLLEY: &str = ":tw_shopping_trolley:";
-const EMOJI_NEW: &str = ":new:";
-const EMOJI_TRASH: &str = ":win10-trash:";
-const EMOJI_STAR: &str = ":star:";
-const EMOJI_ROBOT: &str = ":robot_face:";
-const EMOJI_MEDAL: &str = ":tw_medal:";
-
-fn prices_changed(old: &HashMap<Region, u32>, new: &HashMap<Region, u32>) -> bool {
-    old.len() != new.len() || old.iter().any(|(r, p)| new.get(r) != Some(p))
-}
+use std::collections::{BTreeSet, HashMap};
 
-fn escape_markdown(text: &str) -> String {
-    text.chars()
-        .flat_map(|c| match c {
-            '_' | '*' | '~' | '`' => vec!['\\', c],
-            _ => vec![c],
-        })
-        .collect()
-}
+use crate::scraper::{Accessory, Region, RegionInfo, ShopItem};
+use crate::storage::Snapshot;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
-fn format_prices_with_flags(prices: &HashMap<Region, u32>) -> String {
-    let price_entries: Vec<_> = prices.iter().collect();
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ItemDiff {
+    pub new_items: Vec<ShopItem>,
+    pub deleted_items: Vec<ShopItem>,
+    pub updated_items: Vec<UpdatedItem>,
+    /// Regions that showed up in the shop's region dropdown. Items' prices there aren't
+    /// reported as price changes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub new_regions: Vec<RegionInfo>,
+    /// Regions that disappeared from the dropdown, along with every price in them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_regions: Vec<RegionInfo>,
+}
 
-    match price_entries.as_slice() {
-        [(region, price)] => format!("{} {price}", region.flag()),
-        entries
-            if entries.len() == Region::VARIANTS.len()
-                && entries.iter().all(|(_, p)| **p == *entries[0].1) =>
-        {
-            format!(":earth_americas: {}", entries[0].1)
-        }
-        entries => entries
-            .iter()
-            .map(|(r, p)| format!("{} {p}", r.flag()))
-            .collect::<Vec<_>>()
-            .join(" "),
+impl ItemDiff {
+    pub const fn is_empty(&self) -> bool {
+        self.new_items.is_empty()
+            && self.deleted_items.is_empty()
+            && self.updated_items.is_empty()
+            && self.new_regions.is_empty()
+            && self.removed_regions.is_empty()
     }
 }
 
-fn item_header(emoji: &str, title: &str) -> String {
-    format!("{emoji} {title}")
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdatedItem {
+    pub old: ShopItem,
+    pub new: ShopItem,
+    /// Never empty - items without changes aren't updated.
+    pub changes: Vec<FieldChange>,
 }
 
-fn format_price_line(prices: &HashMap<Region, u32>) -> String {
-    format!(
-        "*Price:* {EMOJI_COOKIES} {}",
-        format_prices_with_flags(prices)
-    )
+/// One thing that differs between two versions of an item. Prices of regions where the item
+/// wasn't (or isn't any more) sold are `None`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum FieldChange {
+    Title {
+        old: String,
+        new: String,
+    },
+    Description {
+        old: String,
+        new: String,
+    },
+    LongDescription {
+        old: Option<String>,
+        new: Option<String>,
+        /// A short AI-written summary of what changed, filled in by [`crate::summary`].
+        #[serde(default)]
+        summary: Option<String>,
+    },
+    Price {
+        region: Region,
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    Stock {
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    AccessoryAdded {
+        accessory: Accessory,
+    },
+    AccessoryRemoved {
+        accessory: Accessory,
+    },
+    AccessoryRepriced {
+        id: usize,
+        name: String,
+        region: Region,
+        old: Option<u32>,
+        new: Option<u32>,
+    },
+    AchievementLock {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    Image {
+        old: Url,
+        new: Url,
+    },
 }
 
-fn item_description(desc: &str) -> String {
-    if desc.is_empty() {
-        String::new()
-    } else {
-        format!("_{}_\n", escape_markdown(desc))
+impl FieldChange {
+    pub const fn is_price(&self) -> bool {
+        matches!(self, Self::Price { .. })
     }
-}
 
-fn buy_button(url: &impl ToString) -> String {
-    format!("<{}|*{EMOJI_TROLLEY} Buy*>", url.to_string())
-}
-
-fn format_accessories(accessories: &[Accessory]) -> String {
-    if accessories.is_empty() {
-        "_none_".to_string()
-    } else {
-        accessories
-            .iter()
-            .map(|a| {
-                format!(
-                    "{} ({EMOJI_COOKIES} {})",
-                    escape_markdown(&a.name),
-                    format_prices_with_flags(&a.prices)
-                )
-            })
-            .collect::<Vec<_>>()
-            .join(", ")
+    pub const fn is_accessory(&self) -> bool {
+        matches!(
+            self,
+            Self::AccessoryAdded { .. }
+                | Self::AccessoryRemoved { .. }
+                | Self::AccessoryRepriced { .. }
+        )
     }
-}
 
-fn format_stock(stock: Option<u32>) -> String {
-    match stock {
-        Some(0) => "Out of stock".to_string(),
-        Some(n) => format!("{n} left"),
-        None => "Unlimited".to_string(),
+    /// Whether both change the same thing: the same field, in the same region and of the same
+    /// accessory where that applies.
+    pub fn same_field(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Price { region: a, .. }, Self::Price { region: b, .. }) => a == b,
+            (
+                Self::AccessoryRepriced {
+                    id: a, region: ra, ..
+                },
+                Self::AccessoryRepriced {
+                    id: b, region: rb, ..
+                },
+            ) => a == b && ra == rb,
+            (
+                Self::AccessoryAdded { accessory: a } | Self::AccessoryRemoved { accessory: a },
+                Self::AccessoryAdded { accessory: b } | Self::AccessoryRemoved { accessory: b },
+            ) => a.id == b.id,
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
     }
-}
 
-fn format_achievement_lock(achievement_lock: Option<String>) -> String {
-    match achievement_lock {
-        Some(s) if s == "Cooking".to_string() => "_Cooking (Black Market)_".to_string(),
-        Some(s) if !s.is_empty() => format!("{}", escape_markdown(s.as_str())),
-        _ => "_none_".to_string(),
+    /// How much the price went up (positive) or down (negative), if it existed before and after.
+    pub fn price_delta(&self) -> Option<i64> {
+        match self {
+            Self::Price {
+                old: Some(old),
+                new: Some(new),
+                ..
+            }
+            | Self::AccessoryRepriced {
+                old: Some(old),
+                new: Some(new),
+                ..
+            } => Some(i64::from(*new) - i64::from(*old)),
+            _ => None,
+        }
     }
 }
 
-fn format_long_description(desc: Option<&String>) -> String {
-    match desc {
-        Some(s) if !s.is_empty() => format!("_{}_", escape_markdown(s)),
-        _ => "_none_".to_string(),
+impl UpdatedItem {
+    pub fn find(&self, predicate: impl Fn(&FieldChange) -> bool) -> Option<&FieldChange> {
+        self.changes.iter().find(|c| predicate(c))
     }
-}
-
-fn render_new_item(item: &ShopItem) -> Vec<SlackBlock> {
-    let section_text = format!(
-        "{}{}\n*Stock:* {}\n\n{}",
-        item_description(&item.description),
-        format_price_line(&item.prices),
-        format_stock(item.remaining_stock),
-        buy_button(&item.buy_link())
-    );
-
-    vec![
-        SlackHeaderBlock::new(pt!(item_header(EMOJI_NEW, &item.title))).into(),
-        SlackSectionBlock::new().with_text(md!(section_text)).into(),
-        SlackImageBlock::new(
-            item.image_url.clone().into(),
-            format!("Image for {}", item.title),
-        )
-        .into(),
-    ]
-}
-
-fn render_deleted_item(item: &ShopItem) -> Vec<SlackBlock> {
-    let section_text = format!(
-        "{}{}\n",
-        item_description(&item.description),
-        format_price_line(&item.prices)
-    );
-
-    vec![
-        SlackHeaderBlock::new(pt!(item_header(EMOJI_TRASH, &item.title))).into(),
-        SlackSectionBlock::new().with_text(md!(section_text)).into(),
-        SlackImageBlock::new(
-            item.image_url.clone().into(),
-            format!("Image for {}", item.title),
-        )
-        .into(),
-    ]
-}
-
-fn summarize_long_description_change(
-    item_title: &str,
-    old_desc: Option<&String>,
-    new_desc: Option<&String>,
-) -> Option<String> {
-    let api_key = CONFIG.openai_api_key.as_ref()?;
-    let model = CONFIG.openai_model.as_ref()?;
-    let base_url = CONFIG.openai_base_url.as_ref()?;
-
-    let old_text = old_desc.map(|s| s.as_str()).unwrap_or("(empty)");
-    let new_text = new_desc.map(|s| s.as_str()).unwrap_or("(empty)");
-
-    let prompt = format!(
-        "An item called \"{item_title}\" in a shop had its description changed.\n\n\
-         OLD DESCRIPTION:\n{old_text}\n\n\
-         NEW DESCRIPTION:\n{new_text}\n\n\
-         Write 1-2 sentences summarizing what specifically changed. \
-         Be concrete: mention specific names, numbers, specs, vendors, etc. that were added, removed, or changed. \
-         Do NOT say \"the description was updated\" - say WHAT changed. \
-         Keep it short."
-    );
-
-    let url = format!(
-        "{}chat/completions",
-        base_url.as_str().trim_end_matches('/').to_owned() + "/"
-    );
 
-    let body = serde_json::json!({
-        "model": model,
-        "messages": [
-            {"role": "user", "content": prompt}
-        ],
-        "max_tokens": 150,
-        "temperature": 0.3
-    });
-
-    let response = crate::scraper::CLIENT
-        .post(&url)
-        .header("Authorization", format!("Bearer {api_key}"))
-        .json(&body)
-        .send()
-        .ok()?;
-
-    if !response.status().is_success() {
-        log::warn!("OpenAI API returned status {}", response.status());
-        return None;
+    pub fn has(&self, predicate: impl Fn(&FieldChange) -> bool) -> bool {
+        self.find(predicate).is_some()
     }
+}
 
-    let json: serde_json::Value = response.json().ok()?;
-    json["choices"][0]["message"]["content"]
-        .as_str()
-        .map(|s| s.trim().to_string())
+/// Per-region price changes, in region order.
+fn price_changes(
+    old: &HashMap<Region, u32>,
+    new: &HashMap<Region, u32>,
+) -> impl Iterator<Item = (Region, Option<u32>, Option<u32>)> {
+    let regions: BTreeSet<_> = old.keys().chain(new.keys()).cloned().collect();
+    regions.into_iter().filter_map(|region| {
+        let (old, new) = (old.get(&region).copied(), new.get(&region).copied());
+        (old != new).then_some((region, old, new))
+    })
 }
 
-fn render_updated_item(old: &ShopItem, new: &ShopItem) -> Vec<SlackBlock> {
-    let title = if old.title != new.title {
-        format!("{} → {}", old.title, new.title)
-    } else {
-        new.title.clone()
-    };
+fn accessory_changes(old: &[Accessory], new: &[Accessory]) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
 
-    let price_line = if prices_changed(&old.prices, &new.prices) {
-        format!(
-            "*Price:* {EMOJI_COOKIES} {} → {}",
-            format_prices_with_flags(&old.prices),
-            format_prices_with_flags(&new.prices)
-        )
-    } else {
-        format_price_line(&new.prices)
-    };
-
-    let description = match (old.description.is_empty(), new.description.is_empty()) {
-        (true, true) => String::new(),
-        (false, false) if old.description == new.description => item_description(&new.description),
-        _ => {
-            let old_desc = if old.description.is_empty() {
-                "_no description_"
-            } else {
-                &escape_markdown(&old.description)
-            };
-            let new_desc = if new.description.is_empty() {
-                "_no description_"
-            } else {
-                &escape_markdown(&new.description)
-            };
-            format!("{old_desc} → {new_desc}\n")
+    for accessory in old {
+        if !new.iter().any(|a| a.id == accessory.id) {
+            changes.push(FieldChange::AccessoryRemoved {
+                accessory: accessory.clone(),
+            });
         }
-    };
+    }
 
-    let long_desc_line = if old.long_description != new.long_description {
-        match summarize_long_description_change(
-            &new.title,
-            old.long_description.as_ref(),
-            new.long_description.as_ref(),
-        ) {
-            Some(s) => format!("*Long Description:* {}\n", escape_markdown(&s)),
-            None => format!(
-                "*Long Description:* {} → {}\n",
-                format_long_description(old.long_description.as_ref()),
-                format_long_description(new.long_description.as_ref())
-            ),
+    for accessory in new {
+        match old.iter().find(|a| a.id == accessory.id) {
+            None => changes.push(FieldChange::AccessoryAdded {
+                accessory: accessory.clone(),
+            }),
+            Some(before) => changes.extend(price_changes(&before.prices, &accessory.prices).map(
+                |(region, old, new)| FieldChange::AccessoryRepriced {
+                    id: accessory.id,
+                    name: accessory.name.clone(),
+                    region,
+                    old,
+                    new,
+                },
+            )),
         }
-    } else {
-        String::new()
-    };
+    }
 
-    let accessories_line = if old.accessories != new.accessories {
-        format!(
-            "*Accessories:* {} → {}\n",
-            format_accessories(&old.accessories),
-            format_accessories(&new.accessories)
-        )
-    } else {
-        String::new()
-    };
+    changes
+}
 
-    let stock_line = if old.remaining_stock != new.remaining_stock {
-        format!(
-            "*Stock:* {} → {}\n",
-            format_stock(old.remaining_stock),
-            format_stock(new.remaining_stock)
-        )
-    } else {
-        format!("*Stock:* {}\n", format_stock(new.remaining_stock))
-    };
+pub fn compute_changes(old: &ShopItem, new: &ShopItem) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
 
-    let achievement_line = if old.achievement_lock != new.achievement_lock {
-        format!(
-            "{EMOJI_MEDAL} *Requires achievement:* {} → {}\n",
-            format_achievement_lock(old.achievement_lock.clone()),
-            format_achievement_lock(new.achievement_lock.clone())
-        )
-    } else {
-        format!(
-            "{EMOJI_MEDAL} *Requires achievement:* {}\n",
-            format_achievement_lock(new.achievement_lock.clone())
-        )
-    };
-
-    let section_text = format!(
-        "{description}{price_line}\n{long_desc_line}{accessories_line}{stock_line}{achievement_line}\n{}",
-        buy_button(&new.buy_link())
+    if old.title != new.title {
+        changes.push(FieldChange::Title {
+            old: old.title.clone(),
+            new: new.title.clone(),
+        });
+    }
+    if old.description != new.description {
+        changes.push(FieldChange::Description {
+            old: old.description.clone(),
+            new: new.description.clone(),
+        });
+    }
+    if old.long_description != new.long_description {
+        changes.push(FieldChange::LongDescription {
+            old: old.long_description.clone(),
+            new: new.long_description.clone(),
+            summary: None,
+        });
+    }
+    changes.extend(
+        price_changes(&old.prices, &new.prices).map(|(region, old, new)| FieldChange::Price {
+            region,
+            old,
+            new,
+        }),
     );
-
-    let mut blocks = vec![
-        SlackHeaderBlock::new(pt!(title)).into(),
-        SlackSectionBlock::new().with_text(md!(section_text)).into(),
-    ];
-
+    if old.remaining_stock != new.remaining_stock {
+        changes.push(FieldChange::Stock {
+            old: old.remaining_stock,
+            new: new.remaining_stock,
+        });
+    }
+    changes.extend(accessory_changes(&old.accessories, &new.accessories));
+    if old.achievement_lock != new.achievement_lock {
+        changes.push(FieldChange::AchievementLock {
+            old: old.achievement_lock.clone(),
+            new: new.achievement_lock.clone(),
+        });
+    }
     if old.image_url != new.image_url {
-        blocks.push(
-            SlackImageBlock::new(
-                old.image_url.clone().into(),
-                format!("Old image for {}", new.title),
-            )
-            .into(),
-        );
+        changes.push(FieldChange::Image {
+            old: old.image_url.clone(),
+            new: new.image_url.clone(),
+        });
     }
 
-    blocks.push(
-        SlackImageBlock::new(
-            new.image_url.clone().into(),
-            format!("New image for {}", new.title),
-        )
-        .into(),
-    );
-    blocks
+    changes
 }
 
-fn render_channel_ping() -> Vec<SlackBlock> {
-    vec![SlackContextBlock::new(vec![SlackContextBlockElement::MarkDown(md!(format!(
-        "pinging <!channel> · <https://github.com/skyfallwastaken/flavortown-tracker|{EMOJI_STAR} star the repo!> · <https://hackclub.slack.com/archives/C091UF79VDM|{EMOJI_ROBOT} discord/slackbot ysws>"
-    )))]).into()]
-}
-
-#[derive(Debug)]
-pub struct ItemDiff {
-    pub new_items: Vec<ShopItem>,
-    pub deleted_items: Vec<ShopItem>,
-    pub updated_items: Vec<(ShopItem, ShopItem)>,
+fn regions_missing_from(regions: &[RegionInfo], other: &[RegionInfo]) -> Vec<RegionInfo> {
+    regions
+        .iter()
+        .filter(|region| !other.iter().any(|o| o.code == region.code))
+        .cloned()
+        .collect()
 }
 
-impl ItemDiff {
-    pub const fn is_empty(&self) -> bool {
-        self.new_items.is_empty() && self.deleted_items.is_empty() && self.updated_items.is_empty()
-    }
-}
+pub fn compute_diff(old: &Snapshot, new: &Snapshot) -> ItemDiff {
+    let old_map: HashMap<_, _> = old.items.iter().map(|i| (i.id, i)).collect();
+    let new_map: HashMap<_, _> = new.items.iter().map(|i| (i.id, i)).collect();
 
-pub fn compute_diff(old_items: &ShopItems, new_items: &ShopItems) -> ItemDiff {
-    let old_map: HashMap<_, _> = old_items.iter().map(|i| (i.id, i)).collect();
-    let new_map: HashMap<_, _> = new_items.iter().map(|i| (i.id, i)).collect();
+    let new_regions = regions_missing_from(&new.regions, &old.regions);
+    let removed_regions = regions_missing_from(&old.regions, &new.regions);
+    // a region opening or closing is reported once, not as a price change on every item.
+    let changed_regions: Vec<Region> = new_regions
+        .iter()
+        .chain(&removed_regions)
+        .map(RegionInfo::region)
+        .collect();
 
     let mut diff = ItemDiff {
-        new_items: new_items
+        new_items: new
+            .items
             .iter()
             .filter(|item| !old_map.contains_key(&item.id))
             .cloned()
             .collect(),
-        deleted_items: old_items
+        deleted_items: old
+            .items
             .iter()
             .filter(|item| !new_map.contains_key(&item.id))
             .cloned()
             .collect(),
         updated_items: Vec::new(),
+        new_regions,
+        removed_regions,
     };
 
-    diff.updated_items = new_items
+    diff.updated_items = new
+        .items
         .iter()
         .filter_map(|new_item| {
-            old_map
-                .get(&new_item.id)
-                .filter(|&&old_item| old_item != new_item)
-                .map(|old_item| ((*old_item).clone(), new_item.clone()))
+            let old_item = old_map.get(&new_item.id)?;
+            let mut changes = compute_changes(old_item, new_item);
+            changes.retain(|change| match change {
+                FieldChange::Price { region, .. }
+                | FieldChange::AccessoryRepriced { region, .. } => {
+                    !changed_regions.contains(region)
+                }
+                _ => true,
+            });
+            (!changes.is_empty()).then(|| UpdatedItem {
+                old: (*old_item).clone(),
+                new: new_item.clone(),
+                changes,
+            })
         })
         .collect();
 
     diff
 }
 
-const MAX_BLOCKS_PER_MESSAGE: usize = 50;
-
-fn send_blocks(blocks: Vec<SlackBlock>, fallback_text: &str) -> Result<()> {
-    use crate::scraper::CLIENT;
-
-    let payload = SlackMessageContent::new()
-        .with_text(fallback_text.to_string())
-        .with_blocks(blocks);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::item;
 
-    debug!(
-        "Sending payload: {}",
-        serde_json::to_string_pretty(&payload).unwrap_or_default()
-    );
-
-    let response = CLIENT
-        .post(CONFIG.webhook_url.clone())
-        .json(&payload)
-        .send()?;
-
-    let status = response.status();
-    let body = response.text().unwrap_or_default();
-    if !status.is_success() {
-        return Err(color_eyre::eyre::eyre!(
-            "Slack API error {}: {}",
-            status,
-            body
-        ));
+    fn region(code: &str) -> Region {
+        Region::from_code(code).unwrap()
     }
 
-    Ok(())
-}
-
-pub fn send_webhook_notifications(diff: &ItemDiff) -> Result<()> {
-    let mut item_block_groups: Vec<Vec<SlackBlock>> = Vec::new();
-
-    for item in &diff.new_items {
-        info!("Sending notification for new item: {}", item.title);
-        item_block_groups.push(render_new_item(item));
+    fn region_info(code: &str) -> RegionInfo {
+        RegionInfo {
+            code: code.to_string(),
+            name: code.to_string(),
+        }
     }
 
-    for (old_item, new_item) in &diff.updated_items {
-        info!("Sending notification for updated item: {}", new_item.title);
-        item_block_groups.push(render_updated_item(old_item, new_item));
+    fn snapshot(regions: &[&str], items: Vec<ShopItem>) -> Snapshot {
+        Snapshot {
+            tracker_version: None,
+            scrape: None,
+            regions: regions.iter().map(|code| region_info(code)).collect(),
+            items,
+        }
     }
 
-    for item in &diff.deleted_items {
-        info!("Sending notification for deleted item: {}", item.title);
-        item_block_groups.push(render_deleted_item(item));
+    fn accessory(id: usize, name: &str, prices: &[(&str, u32)]) -> Accessory {
+        Accessory {
+            id,
+            name: name.to_string(),
+            prices: prices.iter().map(|(code, p)| (region(code), *p)).collect(),
+        }
     }
 
-    let fallback_text = format!(
-        "Shop update: {} new, {} updated, {} removed",
-        diff.new_items.len(),
-        diff.updated_items.len(),
-        diff.deleted_items.len()
-    );
+    /// Prices are listed in dropdown order, not alphabetically.
+    #[test]
+    fn changes_each_field() {
+        let old = item(1, "Pen", &[("US", 100), ("UK", 90)]);
+        let new = ShopItem {
+            title: "Fancy pen".to_string(),
+            prices: HashMap::from([(region("US"), 80), (region("EU"), 70)]),
+            remaining_stock: Some(3),
+            ..old.clone()
+        };
+        assert_eq!(
+            compute_changes(&old, &new),
+            [
+                FieldChange::Title {
+                    old: "Pen".to_string(),
+                    new: "Fancy pen".to_string(),
+                },
+                FieldChange::Price {
+                    region: region("US"),
+                    old: Some(100),
+                    new: Some(80),
+                },
+                FieldChange::Price {
+                    region: region("EU"),
+                    old: None,
+                    new: Some(70),
+                },
+                FieldChange::Price {
+                    region: region("UK"),
+                    old: Some(90),
+                    new: None,
+                },
+                FieldChange::Stock {
+                    old: None,
+                    new: Some(3),
+                },
+            ]
+        );
+        assert!(compute_changes(&old, &old).is_empty());
+    }
 
-    let mut current_blocks: Vec<SlackBlock> = Vec::new();
+    #[test]
+    fn accessories_are_added_removed_and_repriced() {
+        let old = ShopItem {
+            accessories: vec![
+                accessory(1, "Lid", &[("US", 10), ("UK", 12)]),
+                accessory(2, "Cap", &[("US", 5)]),
+            ],
+            ..item(1, "Mug", &[("US", 300)])
+        };
+        let new = ShopItem {
+            accessories: vec![
+                accessory(1, "Lid", &[("US", 15), ("UK", 12)]),
+                accessory(3, "Coaster", &[("US", 3)]),
+            ],
+            ..old.clone()
+        };
+        assert_eq!(
+            compute_changes(&old, &new),
+            [
+                FieldChange::AccessoryRemoved {
+                    accessory: accessory(2, "Cap", &[("US", 5)]),
+                },
+                FieldChange::AccessoryRepriced {
+                    id: 1,
+                    name: "Lid".to_string(),
+                    region: region("US"),
+                    old: Some(10),
+                    new: Some(15),
+                },
+                FieldChange::AccessoryAdded {
+                    accessory: accessory(3, "Coaster", &[("US", 3)]),
+                },
+            ]
+        );
+    }
 
-    for (i, group) in item_block_groups.into_iter().enumerate() {
-        let group_size = group.len() + 1; // +1 for divider
+    #[test]
+    fn incomplete_regions_are_not_a_change() {
+        let old = item(1, "Pen", &[("US", 100)]);
+        let new = ShopItem {
+            incomplete_regions: vec![region("US")],
+            ..old.clone()
+        };
+        assert!(compute_changes(&old, &new).is_empty());
+
+        let diff = compute_diff(
+            &snapshot(&["US"], vec![old.clone()]),
+            &snapshot(&["US"], vec![new]),
+        );
+        assert!(diff.is_empty());
+    }
 
-        if !current_blocks.is_empty()
-            && current_blocks.len() + group_size > MAX_BLOCKS_PER_MESSAGE - 1
-        {
-            send_blocks(current_blocks, &fallback_text)?;
-            current_blocks = Vec::new();
-        }
+    #[test]
+    fn region_changes_replace_price_changes() {
+        let old_mug = ShopItem {
+            accessories: vec![accessory(1, "Lid", &[("US", 10), ("UK", 12)])],
+            ..item(2, "Mug", &[("US", 300), ("UK", 320)])
+        };
+        let new_mug = ShopItem {
+            accessories: vec![accessory(1, "Lid", &[("US", 10), ("CA", 11)])],
+            ..item(2, "Mug", &[("US", 300), ("CA", 310)])
+        };
+        let old = snapshot(
+            &["US", "UK"],
+            vec![item(1, "Pen", &[("US", 100), ("UK", 90)]), old_mug],
+        );
+        let new = snapshot(
+            &["US", "CA"],
+            vec![item(1, "Pen", &[("US", 80), ("CA", 95)]), new_mug],
+        );
 
-        current_blocks.extend(group);
-        if i < diff.new_items.len() + diff.updated_items.len() + diff.deleted_items.len() - 1 {
-            current_blocks.push(SlackDividerBlock::new().into());
-        }
+        let diff = compute_diff(&old, &new);
+        assert_eq!(diff.new_regions, [region_info("CA")]);
+        assert_eq!(diff.removed_regions, [region_info("UK")]);
+        assert!(diff.new_items.is_empty() && diff.deleted_items.is_empty());
+        // only the pen's US price really changed, the mug not at all.
+        let [pen] = diff.updated_items.as_slice() else {
+            panic!("expected only the pen to change: {diff:?}");
+        };
+        assert_eq!(
+            pen.changes,
+            [FieldChange::Price {
+                region: region("US"),
+                old: Some(100),
+                new: Some(80),
+            }]
+        );
     }
 
-    current_blocks.extend(render_channel_ping());
-    send_blocks(current_blocks, &fallback_text)?;
+    #[test]
+    fn items_come_and_go() {
+        let pen = item(1, "Pen", &[("US", 100)]);
+        let hat = item(2, "Hat", &[("US", 50)]);
+        let diff = compute_diff(
+            &snapshot(&["US"], vec![pen.clone()]),
+            &snapshot(&["US"], vec![hat.clone()]),
+        );
+        assert_eq!(diff.new_items, [hat]);
+        assert_eq!(diff.deleted_items, [pen]);
+        assert!(diff.updated_items.is_empty());
+    }
 
-    info!("Successfully sent webhook notifications");
-    Ok(())
+    #[test]
+    fn same_field_tells_regions_and_accessories_apart() {
+        let price = |code: &str, new| FieldChange::Price {
+            region: region(code),
+            old: Some(1),
+            new: Some(new),
+        };
+        assert!(price("US", 2).same_field(&price("US", 3)));
+        assert!(!price("US", 2).same_field(&price("UK", 2)));
+        assert!(
+            FieldChange::Stock {
+                old: None,
+                new: Some(1)
+            }
+            .same_field(&FieldChange::Stock {
+                old: Some(1),
+                new: Some(0)
+            })
+        );
+        let lid = accessory(1, "Lid", &[]);
+        assert!(
+            FieldChange::AccessoryAdded {
+                accessory: lid.clone()
+            }
+            .same_field(&FieldChange::AccessoryRemoved { accessory: lid })
+        );
+        assert!(!price("US", 2).same_field(&FieldChange::Stock {
+            old: None,
+            new: None
+        }));
+    }
 }
@@ -0,0 +1,409 @@
+//! `export`: the snapshot history flattened into one row per observation, for notebooks and
+//! spreadsheets.
+//!
+//! Every snapshot gives a row per item and region it had a price in, plus a row per accessory
+//! priced in that region. Accessory rows carry the accessory's price and the item's other fields.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::scraper::ShopItemId;
+use crate::storage::{Snapshot, utc_timestamp};
+use crate::store::STORE;
+use clap::ValueEnum;
+use color_eyre::{Result, eyre::eyre};
+use log::{info, warn};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, DataType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::{Serialize, Serializer};
+use time_format::TimeStamp;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    /// One JSON object per line.
+    Ndjson,
+    Parquet,
+}
+
+/// Which observations to export.
+pub struct ExportFilter {
+    /// Inclusive.
+    pub since: Option<TimeStamp>,
+    /// Exclusive.
+    pub until: Option<TimeStamp>,
+    /// Every item if empty.
+    pub item_ids: Vec<ShopItemId>,
+}
+
+#[derive(Serialize)]
+struct Observation {
+    #[serde(serialize_with = "iso8601")]
+    taken_at: TimeStamp,
+    item_id: ShopItemId,
+    title: String,
+    region: String,
+    price: u32,
+    /// Empty for unlimited stock.
+    remaining_stock: Option<u32>,
+    achievement_lock: Option<String>,
+    /// Only on an accessory's row.
+    accessory_id: Option<usize>,
+    accessory: Option<String>,
+}
+
+fn iso8601<S: Serializer>(ts: &TimeStamp, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time_format::format_iso8601_utc(*ts).unwrap())
+}
+
+const fn days_in_month(year: i64, month: i64) -> i64 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parses `--since`/`--until`: a unix timestamp, `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SSZ`, in UTC.
+pub fn parse_time(s: &str) -> Result<TimeStamp> {
+    if let Ok(ts) = s.parse() {
+        return Ok(ts);
+    }
+    let invalid = || eyre!("expected a unix timestamp, YYYY-MM-DD or YYYY-MM-DDTHH:MM:SSZ");
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z').ok_or_else(invalid)?)),
+        None => (s, None),
+    };
+    let fields = |s: &str, sep| {
+        s.split(sep)
+            .map(str::parse::<i64>)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())
+    };
+    let [year, month, day] = fields(date, '-')?[..] else {
+        return Err(invalid());
+    };
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return Err(eyre!("{date} isn't a date"));
+    }
+    let seconds = match time {
+        Some(time) => {
+            let [hour, minute, second] = fields(time, ':')?[..] else {
+                return Err(invalid());
+            };
+            if !(0..24).contains(&hour) || !(0..60).contains(&minute) || !(0..60).contains(&second)
+            {
+                return Err(eyre!("{time} isn't a time of day"));
+            }
+            hour * 3600 + minute * 60 + second
+        }
+        None => 0,
+    };
+    Ok(utc_timestamp(year, month, day) + seconds)
+}
+
+fn observations(at: TimeStamp, snapshot: Snapshot, filter: &ExportFilter) -> Vec<Observation> {
+    let mut rows = Vec::new();
+    for item in snapshot.items {
+        if !filter.item_ids.is_empty() && !filter.item_ids.contains(&item.id) {
+            continue;
+        }
+        let mut prices: Vec<_> = item.prices.iter().collect();
+        prices.sort();
+        for (region, &price) in prices {
+            let row = |price, accessory: Option<(usize, &str)>| Observation {
+                taken_at: at,
+                item_id: item.id,
+                title: item.title.clone(),
+                region: region.code().to_string(),
+                price,
+                remaining_stock: item.remaining_stock,
+                achievement_lock: item.achievement_lock.clone(),
+                accessory_id: accessory.map(|(id, _)| id),
+                accessory: accessory.map(|(_, name)| name.to_string()),
+            };
+            rows.push(row(price, None));
+            for accessory in &item.accessories {
+                if let Some(&price) = accessory.prices.get(region) {
+                    rows.push(row(price, Some((accessory.id, &accessory.name))));
+                }
+            }
+        }
+    }
+    rows
+}
+
+const PARQUET_SCHEMA: &str = "
+message observation {
+    REQUIRED INT64 taken_at (TIMESTAMP(MILLIS, true));
+    REQUIRED INT64 item_id;
+    REQUIRED BYTE_ARRAY title (UTF8);
+    REQUIRED BYTE_ARRAY region (UTF8);
+    REQUIRED INT64 price;
+    OPTIONAL INT64 remaining_stock;
+    OPTIONAL BYTE_ARRAY achievement_lock (UTF8);
+    OPTIONAL INT64 accessory_id;
+    OPTIONAL BYTE_ARRAY accessory (UTF8);
+}
+";
+
+/// Rows are written out a row group at a time, so a long history doesn't have to fit in memory.
+const PARQUET_ROW_GROUP_SIZE: usize = 100_000;
+
+type Output = Box<dyn Write + Send>;
+
+enum Writer {
+    Csv(csv::Writer<Output>),
+    Ndjson(BufWriter<Output>),
+    Parquet {
+        writer: SerializedFileWriter<Output>,
+        rows: Vec<Observation>,
+    },
+}
+
+/// Writes one column of a row group. Optional columns need a definition level per row, saying
+/// whether it has a value.
+fn write_column<T: DataType>(
+    group: &mut SerializedRowGroupWriter<Output>,
+    values: impl Iterator<Item = Option<T::T>>,
+) -> Result<()> {
+    let mut column = group
+        .next_column()?
+        .ok_or_else(|| eyre!("more columns written than the schema has"))?;
+    let optional = column
+        .typed::<T>()
+        .get_descriptor()
+        .self_type()
+        .is_optional();
+    let mut levels = Vec::new();
+    let values: Vec<T::T> = values
+        .inspect(|value| levels.push(value.is_some() as i16))
+        .flatten()
+        .collect();
+    column
+        .typed::<T>()
+        .write_batch(&values, optional.then_some(&levels[..]), None)?;
+    column.close()?;
+    Ok(())
+}
+
+fn write_row_group(writer: &mut SerializedFileWriter<Output>, rows: &[Observation]) -> Result<()> {
+    let int = |n: usize| Some(n as i64);
+    let text = |s: &str| Some(ByteArray::from(s));
+    let mut group = writer.next_row_group()?;
+    write_column::<Int64Type>(&mut group, rows.iter().map(|r| Some(r.taken_at * 1000)))?;
+    write_column::<Int64Type>(&mut group, rows.iter().map(|r| int(r.item_id)))?;
+    write_column::<ByteArrayType>(&mut group, rows.iter().map(|r| text(&r.title)))?;
+    write_column::<ByteArrayType>(&mut group, rows.iter().map(|r| text(&r.region)))?;
+    write_column::<Int64Type>(&mut group, rows.iter().map(|r| Some(r.price.into())))?;
+    write_column::<Int64Type>(
+        &mut group,
+        rows.iter().map(|r| r.remaining_stock.map(i64::from)),
+    )?;
+    write_column::<ByteArrayType>(
+        &mut group,
+        rows.iter()
+            .map(|r| r.achievement_lock.as_deref().and_then(text)),
+    )?;
+    write_column::<Int64Type>(
+        &mut group,
+        rows.iter().map(|r| r.accessory_id.and_then(int)),
+    )?;
+    write_column::<ByteArrayType>(
+        &mut group,
+        rows.iter().map(|r| r.accessory.as_deref().and_then(text)),
+    )?;
+    group.close()?;
+    Ok(())
+}
+
+impl Writer {
+    fn new(format: ExportFormat, output: Output) -> Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => Self::Csv(csv::Writer::from_writer(output)),
+            ExportFormat::Ndjson => Self::Ndjson(BufWriter::new(output)),
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Self::Parquet {
+                    writer: SerializedFileWriter::new(
+                        output,
+                        Arc::new(parse_message_type(PARQUET_SCHEMA)?),
+                        Arc::new(properties),
+                    )?,
+                    rows: Vec::new(),
+                }
+            }
+        })
+    }
+
+    fn write(&mut self, row: Observation) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(row)?,
+            Self::Ndjson(writer) => {
+                serde_json::to_writer(&mut *writer, &row)?;
+                writer.write_all(b"\n")?;
+            }
+            Self::Parquet { writer, rows } => {
+                rows.push(row);
+                if rows.len() >= PARQUET_ROW_GROUP_SIZE {
+                    write_row_group(writer, rows)?;
+                    rows.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Csv(mut writer) => writer.flush()?,
+            Self::Ndjson(mut writer) => writer.flush()?,
+            Self::Parquet { mut writer, rows } => {
+                if !rows.is_empty() {
+                    write_row_group(&mut writer, &rows)?;
+                }
+                writer.close()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes every observation matching `filter` to `output`, or stdout.
+pub fn export(format: ExportFormat, output: Option<&Path>, filter: &ExportFilter) -> Result<()> {
+    let out: Output = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    let mut writer = Writer::new(format, out)?;
+
+    let mut snapshots = 0;
+    let mut rows = 0;
+    for at in STORE.list()? {
+        if filter.since.is_some_and(|since| at < since)
+            || filter.until.is_some_and(|until| at >= until)
+        {
+            continue;
+        }
+        let snapshot = match STORE.get(at) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Skipping a snapshot that can't be loaded: {e:#}");
+                continue;
+            }
+        };
+        for row in observations(at, snapshot, filter) {
+            writer.write(row)?;
+            rows += 1;
+        }
+        snapshots += 1;
+    }
+    writer.finish()?;
+
+    info!("Exported {rows} row(s) from {snapshots} snapshot(s)");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, RowAccessor};
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("1767225600").unwrap(), 1767225600);
+        assert_eq!(parse_time("2026-01-01").unwrap(), 1767225600);
+        assert_eq!(
+            parse_time("2026-01-01T01:02:03Z").unwrap(),
+            1767225600 + 3723
+        );
+        assert_eq!(parse_time("2024-02-29").unwrap(), 1709164800);
+        assert_eq!(parse_time("1970-01-01T00:00:00Z").unwrap(), 0);
+        assert_eq!(parse_time("2000-02-29").unwrap(), 951782400);
+        assert_eq!(parse_time("2026-12-31T23:59:59Z").unwrap(), 1798761599);
+    }
+
+    #[test]
+    fn refuses_other_times() {
+        for time in [
+            "",
+            "yesterday",
+            "2026-01",
+            "2026-01-01-01",
+            "2026-01-01T01:02:03",
+            "2026-01-01T01:02Z",
+            "2026/01/01",
+            "2024-13-45T99:99:99Z",
+            "2026-00-10",
+            "2026-01-00",
+            "2026-04-31",
+            "2025-02-29",
+            "2026-01-01T24:00:00Z",
+            "2026-01-01T12:60:00Z",
+            "2026-01-01T12:00:60Z",
+            "2026-01-01T-1:00:00Z",
+        ] {
+            assert!(parse_time(time).is_err(), "{time:?} was accepted");
+        }
+    }
+
+    fn observation(accessory: Option<(usize, &str)>) -> Observation {
+        Observation {
+            taken_at: 1767225600,
+            item_id: 42,
+            title: "Sticker".to_string(),
+            region: "US".to_string(),
+            price: if accessory.is_some() { 5 } else { 100 },
+            remaining_stock: None,
+            achievement_lock: Some("Shipwright".to_string()),
+            accessory_id: accessory.map(|(id, _)| id),
+            accessory: accessory.map(|(_, name)| name.to_string()),
+        }
+    }
+
+    #[test]
+    fn writes_parquet() {
+        let file = tempfile::tempfile().unwrap();
+        let mut writer =
+            Writer::new(ExportFormat::Parquet, Box::new(file.try_clone().unwrap())).unwrap();
+        writer.write(observation(None)).unwrap();
+        writer.write(observation(Some((7, "Holographic")))).unwrap();
+        writer.finish().unwrap();
+
+        let reader = SerializedFileReader::new(file).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        let item = &rows[0];
+        assert_eq!(item.get_timestamp_millis(0).unwrap(), 1767225600 * 1000);
+        assert_eq!(item.get_long(1).unwrap(), 42);
+        assert_eq!(item.get_string(2).unwrap(), "Sticker");
+        assert_eq!(item.get_string(3).unwrap(), "US");
+        assert_eq!(item.get_long(4).unwrap(), 100);
+        assert_eq!(item.get_string(6).unwrap(), "Shipwright");
+        let fields: Vec<_> = item.get_column_iter().map(|(_, field)| field).collect();
+        for column in [5, 7, 8] {
+            assert_eq!(fields[column], &Field::Null);
+        }
+
+        let accessory = &rows[1];
+        assert_eq!(accessory.get_long(4).unwrap(), 5);
+        assert_eq!(accessory.get_long(7).unwrap(), 7);
+        assert_eq!(accessory.get_string(8).unwrap(), "Holographic");
+    }
+}
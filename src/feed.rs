@@ -0,0 +1,303 @@
+//! Atom (and optionally RSS 2.0) feeds of the shop's changes, for feed readers. Each new,
+//! updated or deleted item in the stored diffs becomes an entry.
+//!
+//! The feeds are written to the storage folder whenever a snapshot is committed, and served by
+//! the HTTP API. An entry's ID is made from the diff's time, the kind of change and the item, so
+//! it never changes when the feed is written again.
+
+use std::collections::HashMap;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config::CONFIG;
+use crate::diff::{FieldChange, ItemDiff};
+use crate::scraper::{Region, ShopItem};
+use crate::storage;
+use crate::store::STORE;
+use color_eyre::Result;
+use log::warn;
+use time_format::TimeStamp;
+
+pub const ATOM_PATH: &str = "feed.atom";
+pub const RSS_PATH: &str = "feed.rss";
+
+/// Older changes fall off the end of the feed. `storage gc` keeps this many diffs, which is
+/// enough to fill it.
+pub const MAX_ENTRIES: usize = 100;
+
+#[derive(Clone, Copy)]
+enum Kind {
+    New,
+    Updated,
+    Deleted,
+}
+
+struct Entry {
+    at: TimeStamp,
+    kind: Kind,
+    item: ShopItem,
+    changes: Vec<FieldChange>,
+}
+
+impl Entry {
+    fn id(&self) -> String {
+        let kind = match self.kind {
+            Kind::New => "new",
+            Kind::Updated => "updated",
+            Kind::Deleted => "deleted",
+        };
+        format!("urn:flavortown-tracker:{}:{kind}:{}", self.at, self.item.id)
+    }
+
+    fn title(&self) -> String {
+        let kind = match self.kind {
+            Kind::New => "New",
+            Kind::Updated => "Updated",
+            Kind::Deleted => "Removed",
+        };
+        format!("{kind}: {}", self.item.title)
+    }
+
+    /// The entry's body, as HTML.
+    fn content(&self) -> String {
+        let item = &self.item;
+        let mut html = format!(
+            "<p><img src=\"{}\" alt=\"{}\"/></p>",
+            escape(item.image_url.as_str()),
+            escape(&item.title)
+        );
+        if !item.description.is_empty() {
+            html += &format!("<p>{}</p>", escape(&item.description));
+        }
+        if !self.changes.is_empty() {
+            html += "<p>Changes:</p><ul>";
+            for change in &self.changes {
+                html += &format!("<li>{}</li>", escape(&describe(change)));
+            }
+            html += "</ul>";
+        }
+        html += &format!("<p>Prices:</p>{}", price_list(&item.prices));
+        if !item.accessories.is_empty() {
+            html += "<p>Accessories:</p><ul>";
+            for accessory in &item.accessories {
+                html += &format!(
+                    "<li>{}{}</li>",
+                    escape(&accessory.name),
+                    price_list(&accessory.prices)
+                );
+            }
+            html += "</ul>";
+        }
+        html += &format!("<p>Stock: {}</p>", format_stock(item.remaining_stock));
+        if let Some(lock) = item.achievement_lock.as_deref().filter(|l| !l.is_empty()) {
+            html += &format!("<p>Requires achievement: {}</p>", escape(lock));
+        }
+        html += &format!(
+            "<p><a href=\"{}\">Buy in the shop</a></p>",
+            escape(item.buy_link().as_str())
+        );
+        html
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn price_list(prices: &HashMap<Region, u32>) -> String {
+    let mut prices: Vec<_> = prices.iter().collect();
+    prices.sort();
+    let items: String = prices
+        .iter()
+        .map(|(region, price)| format!("<li>{}: {price} 🍪</li>", escape(&region.to_string())))
+        .collect();
+    format!("<ul>{items}</ul>")
+}
+
+fn format_price(price: Option<u32>) -> String {
+    price.map_or("–".to_string(), |p| p.to_string())
+}
+
+fn format_stock(stock: Option<u32>) -> String {
+    match stock {
+        Some(0) => "out of stock".to_string(),
+        Some(n) => format!("{n} left"),
+        None => "unlimited".to_string(),
+    }
+}
+
+fn describe(change: &FieldChange) -> String {
+    match change {
+        FieldChange::Title { old, new } => format!("Title: {old} → {new}"),
+        FieldChange::Description { old, new } => format!("Description: {old} → {new}"),
+        FieldChange::LongDescription {
+            summary: Some(summary),
+            ..
+        } => format!("Long description: {summary}"),
+        FieldChange::LongDescription { .. } => "Long description changed".to_string(),
+        FieldChange::Price { region, old, new } => format!(
+            "Price in {region}: {} → {}",
+            format_price(*old),
+            format_price(*new)
+        ),
+        FieldChange::Stock { old, new } => {
+            format!("Stock: {} → {}", format_stock(*old), format_stock(*new))
+        }
+        FieldChange::AccessoryAdded { accessory } => format!("Accessory added: {}", accessory.name),
+        FieldChange::AccessoryRemoved { accessory } => {
+            format!("Accessory removed: {}", accessory.name)
+        }
+        FieldChange::AccessoryRepriced {
+            name,
+            region,
+            old,
+            new,
+            ..
+        } => format!(
+            "{name} in {region}: {} → {}",
+            format_price(*old),
+            format_price(*new)
+        ),
+        FieldChange::AchievementLock { old, new } => format!(
+            "Required achievement: {} → {}",
+            old.as_deref().unwrap_or("none"),
+            new.as_deref().unwrap_or("none")
+        ),
+        FieldChange::Image { .. } => "New image".to_string(),
+    }
+}
+
+/// The newest [`MAX_ENTRIES`] entries, newest first.
+fn entries() -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for at in STORE.list_diffs()?.into_iter().rev() {
+        let diff: ItemDiff = match STORE.get_diff(at) {
+            Ok(Some(diff)) => diff,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Leaving the diff from {} out of the feed: {e}", iso8601(at));
+                continue;
+            }
+        };
+        let entry = |kind, item, changes| Entry {
+            at,
+            kind,
+            item,
+            changes,
+        };
+        entries.extend(
+            diff.new_items
+                .into_iter()
+                .map(|item| entry(Kind::New, item, Vec::new())),
+        );
+        entries.extend(
+            diff.updated_items
+                .into_iter()
+                .map(|item| entry(Kind::Updated, item.new, item.changes)),
+        );
+        entries.extend(
+            diff.deleted_items
+                .into_iter()
+                .map(|item| entry(Kind::Deleted, item, Vec::new())),
+        );
+        if entries.len() >= MAX_ENTRIES {
+            break;
+        }
+    }
+    entries.truncate(MAX_ENTRIES);
+    Ok(entries)
+}
+
+fn iso8601(ts: TimeStamp) -> String {
+    time_format::format_iso8601_utc(ts).unwrap()
+}
+
+fn rfc822(ts: TimeStamp) -> String {
+    httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(ts.max(0) as u64))
+}
+
+pub fn render_atom() -> Result<String> {
+    let entries = entries()?;
+    let shop = CONFIG.base_url.join("shop")?;
+    let updated = entries.first().map_or(0, |e| e.at);
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <title>Flavortown shop changes</title>\n\
+         <id>{shop}</id>\n\
+         <link href=\"{shop}\"/>\n\
+         <updated>{}</updated>\n\
+         <author><name>Flavortown Tracker</name></author>\n",
+        iso8601(updated),
+        shop = escape(shop.as_str()),
+    );
+    for entry in &entries {
+        xml += &format!(
+            "<entry>\n\
+             <id>{}</id>\n\
+             <title>{}</title>\n\
+             <updated>{}</updated>\n\
+             <link href=\"{}\"/>\n\
+             <link rel=\"enclosure\" href=\"{}\"/>\n\
+             <content type=\"html\">{}</content>\n\
+             </entry>\n",
+            entry.id(),
+            escape(&entry.title()),
+            iso8601(entry.at),
+            escape(entry.item.buy_link().as_str()),
+            escape(entry.item.image_url.as_str()),
+            escape(&entry.content()),
+        );
+    }
+    xml += "</feed>\n";
+    Ok(xml)
+}
+
+pub fn render_rss() -> Result<String> {
+    let entries = entries()?;
+    let shop = CONFIG.base_url.join("shop")?;
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <rss version=\"2.0\">\n\
+         <channel>\n\
+         <title>Flavortown shop changes</title>\n\
+         <link>{}</link>\n\
+         <description>New, updated and removed items in the Flavortown shop</description>\n",
+        escape(shop.as_str()),
+    );
+    if let Some(newest) = entries.first() {
+        xml += &format!("<lastBuildDate>{}</lastBuildDate>\n", rfc822(newest.at));
+    }
+    for entry in &entries {
+        xml += &format!(
+            "<item>\n\
+             <title>{}</title>\n\
+             <link>{}</link>\n\
+             <guid isPermaLink=\"false\">{}</guid>\n\
+             <pubDate>{}</pubDate>\n\
+             <description>{}</description>\n\
+             </item>\n",
+            escape(&entry.title()),
+            escape(entry.item.buy_link().as_str()),
+            entry.id(),
+            rfc822(entry.at),
+            escape(&entry.content()),
+        );
+    }
+    xml += "</channel>\n</rss>\n";
+    Ok(xml)
+}
+
+/// Writes the feeds to the storage folder: always Atom, RSS too if `FEED_RSS` is set.
+pub fn write() -> Result<()> {
+    storage::write_atomically(&CONFIG.storage_path.join(ATOM_PATH), render_atom()?)?;
+    if CONFIG.feed_rss {
+        storage::write_atomically(&CONFIG.storage_path.join(RSS_PATH), render_rss()?)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,347 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use crate::config::CONFIG;
+use crate::retry::{self, RetryPolicy};
+use crate::scraper::CLIENT;
+use color_eyre::{Result, eyre::eyre};
+use dashmap::DashMap;
+use log::debug;
+use once_cell::sync::Lazy;
+use reqwest::{Method, Url, header};
+use serde::{Deserialize, Serialize};
+
+/// A request to Flavortown, described without any session state so it can be written to disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchRequest {
+    pub method: String,
+    pub url: Url,
+    #[serde(default, skip_serializing)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub form: Vec<(String, String)>,
+}
+
+impl FetchRequest {
+    pub fn get(url: Url) -> Self {
+        Self {
+            method: Method::GET.to_string(),
+            url,
+            headers: Vec::new(),
+            form: Vec::new(),
+        }
+    }
+
+    pub fn patch_form(url: Url, form: &[(&str, &str)]) -> Self {
+        Self {
+            method: Method::PATCH.to_string(),
+            url,
+            headers: Vec::new(),
+            form: form
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// A filesystem-safe name for this request. It only uses the path, query and form, so a
+    /// recording made against one `BASE_URL` can be replayed against another.
+    fn fixture_key(&self) -> String {
+        let mut key = format!("{}{}", self.method, self.url.path());
+        if let Some(query) = self.url.query() {
+            key.push('_');
+            key.push_str(query);
+        }
+        for (k, v) in &self.form {
+            key.push_str(&format!("_{k}_{v}"));
+        }
+        key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub location: Option<String>,
+    pub body: String,
+}
+
+impl FetchResponse {
+    /// Fails unless Flavortown answered with a page. The client never follows redirects, and
+    /// Flavortown only redirects (or answers 401) when the cookie is no longer logged in, so
+    /// those become [`SessionExpired`].
+    pub fn ensure_ok(self, url: &Url) -> Result<Self> {
+        match self.status {
+            200 => Ok(self),
+            300..=399 | 401 => Err(SessionExpired {
+                session: None,
+                url: url.clone(),
+                status: self.status,
+                location: self.location,
+            }
+            .into()),
+            status => Err(eyre!("HTTP status {status} for url ({url})")),
+        }
+    }
+}
+
+/// Flavortown sent us to the login page instead of the one we asked for.
+#[derive(Debug)]
+pub struct SessionExpired {
+    /// The session that was logged out, once known.
+    pub session: Option<String>,
+    pub url: Url,
+    pub status: u16,
+    pub location: Option<String>,
+}
+
+impl fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Flavortown session expired")?;
+        if let Some(session) = &self.session {
+            write!(f, " for {session}")?;
+        }
+        write!(f, " - {} answered {}", self.url, self.status)?;
+        if let Some(location) = &self.location {
+            write!(f, " (redirecting to {location})")?;
+        }
+        write!(f, ". Update the COOKIE or COOKIES env var.")
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    request: FetchRequest,
+    response: FetchResponse,
+}
+
+#[async_trait]
+pub trait Fetch: Send + Sync {
+    async fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse>;
+
+    /// Whether responses come from disk rather than Flavortown. Anything else that would touch
+    /// the network (CDN uploads, notifications) should stay quiet when this is set.
+    fn is_offline(&self) -> bool {
+        false
+    }
+
+    /// Whether requests are matched up with fixtures by the order they're made in, so they have
+    /// to be made in the same order every run.
+    fn records_order(&self) -> bool {
+        false
+    }
+}
+
+struct LiveFetcher;
+
+#[async_trait]
+impl Fetch for LiveFetcher {
+    async fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse> {
+        if !request
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Cookie"))
+        {
+            return Err(eyre!("COOKIE or COOKIES must be set to scrape Flavortown"));
+        }
+
+        let method = Method::from_bytes(request.method.as_bytes())?;
+        let res = retry::send(&RetryPolicy::FLAVORTOWN, || {
+            let mut builder = CLIENT.request(method.clone(), request.url.clone());
+            for (name, value) in &request.headers {
+                builder = builder.header(name, value);
+            }
+            if !request.form.is_empty() {
+                builder = builder.form(&request.form);
+            }
+            builder
+        })
+        .await?;
+        let status = res.status().as_u16();
+        let location = res
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        Ok(FetchResponse {
+            status,
+            location,
+            body: res.text().await?,
+        })
+    }
+}
+
+/// Numbers repeated requests for the same key, so the Nth `GET shop` of a run is stored and
+/// looked up separately from the first one.
+#[derive(Default)]
+struct Occurrences(DashMap<String, usize>);
+
+impl Occurrences {
+    fn next_path(&self, dir: &Path, request: &FetchRequest) -> PathBuf {
+        let key = request.fixture_key();
+        let mut count = self.0.entry(key.clone()).or_insert(0);
+        let path = dir.join(format!("{key}.{}.json", *count));
+        *count += 1;
+        path
+    }
+}
+
+struct RecordingFetcher {
+    dir: PathBuf,
+    occurrences: Occurrences,
+}
+
+#[async_trait]
+impl Fetch for RecordingFetcher {
+    async fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse> {
+        let response = LiveFetcher.fetch(request).await?;
+        let path = self.occurrences.next_path(&self.dir, request);
+        debug!(
+            "Recording {} {} to {}",
+            request.method,
+            request.url,
+            path.display()
+        );
+        tokio::fs::write(
+            path,
+            serde_json::to_string_pretty(&Exchange {
+                request: request.clone(),
+                response: response.clone(),
+            })?,
+        )
+        .await?;
+        Ok(response)
+    }
+
+    fn records_order(&self) -> bool {
+        true
+    }
+}
+
+struct ReplayFetcher {
+    dir: PathBuf,
+    occurrences: Occurrences,
+}
+
+#[async_trait]
+impl Fetch for ReplayFetcher {
+    async fn fetch(&self, request: &FetchRequest) -> Result<FetchResponse> {
+        let path = self.occurrences.next_path(&self.dir, request);
+        debug!(
+            "Replaying {} {} from {}",
+            request.method,
+            request.url,
+            path.display()
+        );
+        let contents = tokio::fs::read_to_string(&path).await.map_err(|e| {
+            eyre!(
+                "no recorded response for {} {} (expected {}): {e}",
+                request.method,
+                request.url,
+                path.display()
+            )
+        })?;
+        let exchange: Exchange = serde_json::from_str(&contents)?;
+        Ok(exchange.response)
+    }
+
+    fn is_offline(&self) -> bool {
+        true
+    }
+
+    fn records_order(&self) -> bool {
+        true
+    }
+}
+
+pub static FETCHER: Lazy<Box<dyn Fetch>> =
+    Lazy::new(|| match (&CONFIG.record_dir, &CONFIG.replay_dir) {
+        (Some(_), Some(_)) => panic!("RECORD_DIR and REPLAY_DIR can't both be set"),
+        (Some(dir), None) => {
+            fs::create_dir_all(dir).expect("failed to create RECORD_DIR");
+            Box::new(RecordingFetcher {
+                dir: dir.clone(),
+                occurrences: Occurrences::default(),
+            })
+        }
+        (None, Some(dir)) => Box::new(ReplayFetcher {
+            dir: dir.clone(),
+            occurrences: Occurrences::default(),
+        }),
+        (None, None) => Box::new(LiveFetcher),
+    });
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{FieldChange, compute_diff};
+    use crate::scraper::{self, Region};
+    use crate::testing;
+
+    /// `tests/fixtures/replay` is two runs against a small shop, recorded by one process like the
+    /// daemon would. Between them the shop opened in Canada, dropped a price, sold out of pens,
+    /// repriced and added accessories, and swapped the sticker for a hat.
+    #[tokio::test]
+    async fn replays_recorded_runs() {
+        testing::init();
+        let first = scraper::scrape(None).await.unwrap();
+        let second = scraper::scrape(Some(&first.snapshot)).await.unwrap();
+        assert!(first.errors.is_empty() && second.errors.is_empty());
+
+        let diff = compute_diff(&first.snapshot, &second.snapshot);
+        let ids = |items: &[scraper::ShopItem]| items.iter().map(|i| i.id).collect::<Vec<_>>();
+        assert_eq!(ids(&diff.new_items), [4]);
+        assert_eq!(ids(&diff.deleted_items), [3]);
+        assert_eq!(diff.new_regions.len(), 1);
+        assert_eq!(diff.new_regions[0].code, "CA");
+        assert!(diff.removed_regions.is_empty());
+
+        let us = Region::from_code("US").unwrap();
+        let changes = |id| {
+            let item = diff.updated_items.iter().find(|u| u.new.id == id).unwrap();
+            item.changes.clone()
+        };
+        let pen = changes(1);
+        assert_eq!(pen.len(), 3, "{pen:?}");
+        assert!(pen.contains(&FieldChange::Price {
+            region: us.clone(),
+            old: Some(100),
+            new: Some(90),
+        }));
+        assert!(pen.contains(&FieldChange::Stock {
+            old: Some(5),
+            new: Some(0),
+        }));
+        assert!(pen.iter().any(|c| matches!(
+            c,
+            FieldChange::LongDescription { new: Some(new), .. } if new == "Blue ink, now refillable"
+        )));
+
+        let mug = changes(2);
+        assert_eq!(mug.len(), 2, "{mug:?}");
+        assert!(mug.contains(&FieldChange::AccessoryRepriced {
+            id: 9,
+            name: "Lid".to_string(),
+            region: us,
+            old: Some(10),
+            new: Some(15),
+        }));
+        assert!(
+            mug.iter().any(
+                |c| matches!(c, FieldChange::AccessoryAdded { accessory } if accessory.id == 10)
+            )
+        );
+    }
+}
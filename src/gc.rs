@@ -0,0 +1,251 @@
+//! `storage gc`: thins out old snapshots and compresses the ones that are kept.
+//!
+//! Every snapshot is kept for `RETAIN_ALL_DAYS`, then only the last one of each hour until
+//! `RETAIN_HOURLY_DAYS`, then the last one of each day (until `RETAIN_DAILY_DAYS`, if set). The
+//! latest snapshot is always kept. Snapshots older than `COMPRESS_AFTER_DAYS` are gzipped, with
+//! their items moved into a store shared by every compressed snapshot, so an item that didn't
+//! change is stored once however many snapshots it's in.
+//!
+//! The history index already holds every snapshot that's pruned, so timelines stay complete.
+//!
+//! The daemon runs this itself every `DAEMON_GC_INTERVAL_SECS`. Only the `files` backend is
+//! pruned: the SQLite database keeps every snapshot.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::CONFIG;
+use crate::feed;
+use crate::storage;
+use crate::store::StorageBackend;
+use color_eyre::{Result, eyre::eyre};
+use log::info;
+use time_format::TimeStamp;
+
+const DAY: i64 = 24 * 60 * 60;
+const HOUR: i64 = 60 * 60;
+
+#[derive(PartialEq, Eq, Hash)]
+enum Bucket {
+    Hour(i64),
+    Day(i64),
+}
+
+/// The `RETAIN_*` settings, in seconds.
+struct Retention {
+    all: i64,
+    hourly: i64,
+    daily: Option<i64>,
+}
+
+impl Retention {
+    fn from_config() -> Self {
+        Self {
+            all: CONFIG.retain_all_days as i64 * DAY,
+            hourly: CONFIG.retain_hourly_days as i64 * DAY,
+            daily: CONFIG.retain_daily_days.map(|days| days as i64 * DAY),
+        }
+    }
+}
+
+/// Which of `snapshots` (oldest first) the retention policy keeps at `now`.
+fn retained(
+    snapshots: &[(TimeStamp, PathBuf)],
+    retention: &Retention,
+    now: TimeStamp,
+) -> BTreeSet<TimeStamp> {
+    let mut kept = BTreeSet::new();
+    // later snapshots replace earlier ones, so each bucket ends up with its last.
+    let mut buckets = HashMap::new();
+    for &(ts, _) in snapshots {
+        let age = now - ts;
+        if age < retention.all {
+            kept.insert(ts);
+        } else if age < retention.hourly {
+            buckets.insert(Bucket::Hour(ts.div_euclid(HOUR)), ts);
+        } else if retention.daily.is_none_or(|daily| age < daily) {
+            buckets.insert(Bucket::Day(ts.div_euclid(DAY)), ts);
+        }
+    }
+    kept.extend(buckets.into_values());
+    kept
+}
+
+fn size_of(path: &PathBuf) -> u64 {
+    fs::metadata(path).map_or(0, |m| m.len())
+}
+
+/// Applies the retention and compression policies to the stored snapshots. With `dry_run`, only
+/// reports what would change.
+pub fn collect_garbage(dry_run: bool) -> Result<()> {
+    if CONFIG.storage_backend != StorageBackend::Files {
+        return Err(eyre!(
+            "storage gc only prunes snapshot files - with STORAGE_BACKEND=sqlite every snapshot \
+             is kept in the database"
+        ));
+    }
+    // held for the whole run, so compressing each snapshot doesn't open the item records again.
+    let objects = storage::OBJECTS_DB.open()?;
+    let now = time_format::now().unwrap();
+    let snapshots = storage::list_snapshots()?;
+
+    let mut kept = retained(&snapshots, &Retention::from_config(), now);
+    if let Some((newest, _)) = snapshots.last() {
+        kept.insert(*newest);
+    }
+    if let Some(ts) = storage::latest_snapshot_name()
+        .as_deref()
+        .and_then(storage::parse_snapshot_timestamp)
+    {
+        kept.insert(ts);
+    }
+    let newest = snapshots.last().map(|(ts, _)| *ts);
+
+    let mut removed = 0;
+    let mut compressed = 0;
+    let mut bytes_before = 0;
+    let mut bytes_after = 0;
+    for (ts, path) in &snapshots {
+        bytes_before += size_of(path);
+        if !kept.contains(ts) {
+            info!(
+                "{} {}",
+                if dry_run { "Would remove" } else { "Removing" },
+                path.display()
+            );
+            if !dry_run {
+                storage::remove_snapshot_file(path)?;
+            }
+            removed += 1;
+            continue;
+        }
+
+        let packed = storage::packed_snapshot_path(*ts);
+        let compress = *path != packed
+            && now - ts >= CONFIG.compress_after_days as i64 * DAY
+            && Some(*ts) != newest;
+        if !compress {
+            bytes_after += size_of(path);
+            continue;
+        }
+        info!(
+            "{} {}",
+            if dry_run {
+                "Would compress"
+            } else {
+                "Compressing"
+            },
+            path.display()
+        );
+        if !dry_run {
+            storage::write_snapshot_file(&packed, &storage::load_snapshot(path)?)?;
+            storage::remove_snapshot_file(path)?;
+            bytes_after += size_of(&packed);
+        }
+        compressed += 1;
+    }
+
+    // the feeds and `/diff` are built from the newest diffs, the others are only worth keeping
+    // with their snapshot.
+    let diffs = storage::list_diffs()?;
+    let mut removed_diffs = 0;
+    for (ts, path) in diffs.iter().rev().skip(feed::MAX_ENTRIES) {
+        if !kept.contains(ts) {
+            if !dry_run {
+                fs::remove_file(path)?;
+            }
+            removed_diffs += 1;
+        }
+    }
+
+    let removed_objects = if dry_run {
+        0
+    } else {
+        storage::remove_unused_objects()?
+    };
+
+    if dry_run {
+        println!(
+            "Would remove {removed} snapshot(s) and {removed_diffs} diff(s), and compress \
+             {compressed} snapshot(s)"
+        );
+    } else {
+        println!(
+            "Removed {removed} snapshot(s), {removed_diffs} diff(s) and {removed_objects} unused \
+             item record(s), compressed {compressed} snapshot(s) - snapshots went from {} to {} \
+             KiB, plus {} KiB of item records",
+            bytes_before / 1024,
+            bytes_after / 1024,
+            objects.size_on_disk()? / 1024
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::utc_timestamp;
+
+    const MINUTE: i64 = 60;
+
+    fn kept(snapshots: &[TimeStamp], retention: &Retention, now: TimeStamp) -> Vec<TimeStamp> {
+        let snapshots: Vec<_> = snapshots.iter().map(|&ts| (ts, PathBuf::new())).collect();
+        retained(&snapshots, retention, now).into_iter().collect()
+    }
+
+    #[test]
+    fn keeps_the_last_snapshot_of_each_bucket() {
+        let now = utc_timestamp(2026, 1, 31);
+        let retention = Retention {
+            all: DAY,
+            hourly: 3 * DAY,
+            daily: Some(10 * DAY),
+        };
+        let two_days_ago = now - 2 * DAY;
+        let five_days_ago = now - 5 * DAY;
+        let snapshots = [
+            // too old for any bucket.
+            now - 20 * DAY,
+            // one per day.
+            five_days_ago + HOUR,
+            five_days_ago + 20 * HOUR,
+            // one per hour.
+            two_days_ago + 5 * MINUTE,
+            two_days_ago + 50 * MINUTE,
+            two_days_ago + 70 * MINUTE,
+            // every one.
+            now - 2 * HOUR,
+            now - 10 * MINUTE,
+            now - 9 * MINUTE,
+        ];
+        assert_eq!(
+            kept(&snapshots, &retention, now),
+            [
+                five_days_ago + 20 * HOUR,
+                two_days_ago + 50 * MINUTE,
+                two_days_ago + 70 * MINUTE,
+                now - 2 * HOUR,
+                now - 10 * MINUTE,
+                now - 9 * MINUTE,
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_daily_snapshots_forever_without_a_limit() {
+        let now = utc_timestamp(2026, 1, 31);
+        let retention = Retention {
+            all: 0,
+            hourly: 0,
+            daily: None,
+        };
+        let year_ago = utc_timestamp(2025, 1, 31);
+        let snapshots = [year_ago + HOUR, year_ago + 2 * HOUR, now - DAY + HOUR];
+        assert_eq!(
+            kept(&snapshots, &retention, now),
+            [year_ago + 2 * HOUR, now - DAY + HOUR]
+        );
+    }
+}
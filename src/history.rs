@@ -0,0 +1,164 @@
+//! An index over every stored snapshot, so questions about the past don't have to re-read years
+//! of `snap_*.json` files.
+//!
+//! Snapshots are only written when something changed, so each observation holds from its
+//! timestamp until the next one.
+
+use std::collections::BTreeMap;
+
+use crate::scraper::{Region, ShopItemId, ShopItems};
+use crate::storage::{self, SharedDb};
+use color_eyre::Result;
+use log::{info, warn};
+use serde::Serialize;
+use sled::{Db, Tree};
+use time_format::TimeStamp;
+
+const HISTORY_DB_PATH: &str = "history.sled";
+
+static HISTORY_DB: SharedDb = SharedDb::new(HISTORY_DB_PATH);
+
+/// `item id ++ region code ++ 0 ++ timestamp` -> price
+fn prices(db: &Db) -> Result<Tree> {
+    Ok(db.open_tree("prices")?)
+}
+
+/// `item id` -> timestamp of the first snapshot containing the item
+fn first_seen_tree(db: &Db) -> Result<Tree> {
+    Ok(db.open_tree("first_seen")?)
+}
+
+/// `item id` -> timestamp of the latest snapshot where the item wasn't out of stock
+fn last_in_stock_tree(db: &Db) -> Result<Tree> {
+    Ok(db.open_tree("last_in_stock")?)
+}
+
+/// `timestamp` -> () for every snapshot that has been indexed
+fn indexed(db: &Db) -> Result<Tree> {
+    Ok(db.open_tree("indexed")?)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PricePoint {
+    pub at: TimeStamp,
+    pub price: u32,
+}
+
+fn item_key(item_id: ShopItemId) -> [u8; 8] {
+    (item_id as u64).to_be_bytes()
+}
+
+fn price_prefix(item_id: ShopItemId, region: &Region) -> Vec<u8> {
+    let mut key = item_key(item_id).to_vec();
+    key.extend_from_slice(region.code().as_bytes());
+    key.push(0);
+    key
+}
+
+fn decode_ts(bytes: &[u8]) -> TimeStamp {
+    i64::from_be_bytes(bytes.try_into().expect("corrupt history timestamp"))
+}
+
+/// Keeps whichever of the stored and new timestamps `keep` prefers.
+fn merge_ts(tree: &Tree, key: &[u8], ts: TimeStamp, keep: fn(i64, i64) -> i64) -> Result<()> {
+    tree.fetch_and_update(key, |old| {
+        let merged = old.map_or(ts, |old| keep(decode_ts(old), ts));
+        Some(merged.to_be_bytes().to_vec())
+    })?;
+    Ok(())
+}
+
+/// Adds a snapshot taken at `at` to the index. Indexing the same snapshot twice is a no-op.
+pub fn record_snapshot(at: TimeStamp, items: &ShopItems) -> Result<()> {
+    let db = HISTORY_DB.open()?;
+    let indexed = indexed(&db)?;
+    if indexed.contains_key(at.to_be_bytes())? {
+        return Ok(());
+    }
+
+    let prices = prices(&db)?;
+    let first_seen = first_seen_tree(&db)?;
+    let last_in_stock = last_in_stock_tree(&db)?;
+
+    for item in items {
+        for (region, price) in &item.prices {
+            let mut key = price_prefix(item.id, region);
+            key.extend_from_slice(&at.to_be_bytes());
+            prices.insert(key, &price.to_be_bytes())?;
+        }
+
+        merge_ts(&first_seen, &item_key(item.id), at, i64::min)?;
+        if item.remaining_stock != Some(0) {
+            merge_ts(&last_in_stock, &item_key(item.id), at, i64::max)?;
+        }
+    }
+
+    indexed.insert(at.to_be_bytes(), &[])?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Indexes any snapshots on disk that aren't in the index yet, e.g. ones written before the
+/// index existed.
+pub fn backfill() -> Result<()> {
+    let db = HISTORY_DB.open()?;
+    let indexed = indexed(&db)?;
+    let mut count = 0;
+    for (at, path) in storage::list_snapshots()? {
+        if !indexed.contains_key(at.to_be_bytes())? {
+            match storage::load_snapshot(&path) {
+                Ok(snapshot) => {
+                    record_snapshot(at, &snapshot.items)?;
+                    count += 1;
+                }
+                Err(e) => warn!("Not indexing a damaged snapshot: {e:#}"),
+            }
+        }
+    }
+    if count > 0 {
+        info!("Indexed {count} snapshot(s) into the history index");
+    }
+    Ok(())
+}
+
+/// The item's price timeline in every region it has ever been listed in.
+pub fn price_timelines(item_id: ShopItemId) -> Result<BTreeMap<Region, Vec<PricePoint>>> {
+    let db = HISTORY_DB.open()?;
+    let prefix = item_key(item_id);
+    let mut timelines: BTreeMap<Region, Vec<PricePoint>> = BTreeMap::new();
+    for entry in prices(&db)?.scan_prefix(prefix) {
+        let (key, value) = entry?;
+        let rest = &key[prefix.len()..];
+        let Some(end) = rest.iter().position(|&b| b == 0) else {
+            continue;
+        };
+        let Some(region) = std::str::from_utf8(&rest[..end])
+            .ok()
+            .and_then(Region::from_code)
+        else {
+            continue;
+        };
+        timelines.entry(region).or_default().push(PricePoint {
+            at: decode_ts(&rest[end + 1..]),
+            price: u32::from_be_bytes(value.as_ref().try_into()?),
+        });
+    }
+    Ok(timelines)
+}
+
+/// When the item first showed up in a snapshot.
+pub fn first_seen(item_id: ShopItemId) -> Result<Option<TimeStamp>> {
+    let db = HISTORY_DB.open()?;
+    Ok(first_seen_tree(&db)?
+        .get(item_key(item_id))?
+        .map(|v| decode_ts(&v)))
+}
+
+/// The latest snapshot in which the item wasn't out of stock. If that's the newest snapshot, the
+/// item is still in stock now.
+pub fn last_in_stock(item_id: ShopItemId) -> Result<Option<TimeStamp>> {
+    let db = HISTORY_DB.open()?;
+    Ok(last_in_stock_tree(&db)?
+        .get(item_key(item_id))?
+        .map(|v| decode_ts(&v)))
+}
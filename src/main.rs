@@ -1,13 +1,145 @@
-use color_eyre::Result;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use color_eyre::{Result, eyre::eyre};
 use log::{info, warn};
 
+use crate::export::{ExportFilter, ExportFormat};
+use crate::fetch::SessionExpired;
+use crate::notify::Alert;
+use crate::outbox::PendingSnapshot;
+use crate::scraper::{Region, ShopItemId};
+use crate::storage::Snapshot;
+use crate::store::{STORE, StorageBackend};
+use crate::watchlist::{Condition, Subscription};
+
 mod config;
+mod daemon;
 mod diff;
+mod export;
+mod feed;
+mod fetch;
+mod gc;
+mod history;
+mod migrate;
+mod notify;
+mod outbox;
 mod rails;
+mod retry;
 mod scraper;
+mod server;
+mod session;
+mod slack_api;
+mod sqlite;
 mod storage;
+mod store;
+mod summary;
+#[cfg(test)]
+mod testing;
+mod watchlist;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Scrape the shop once, send notifications and exit. This is the default.
+    Run,
+    /// Keep running and scrape the shop on an interval.
+    Daemon,
+    /// Serve the read-only JSON API without scraping. `daemon` serves it too.
+    Serve,
+    /// Show an item's price timeline and stock history from past snapshots.
+    History {
+        item_id: ShopItemId,
+        /// Region code for the price timeline, e.g. US, EU, UK, IN, CA, AU or XX.
+        #[arg(long, default_value = "US")]
+        region: String,
+    },
+    /// Flatten the snapshot history into one row per item, region and accessory per snapshot.
+    Export {
+        #[arg(long, value_enum, default_value = "csv")]
+        format: ExportFormat,
+        /// File to write to instead of stdout.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Only snapshots taken at or after this time: a unix timestamp, YYYY-MM-DD or
+        /// YYYY-MM-DDTHH:MM:SSZ, in UTC.
+        #[arg(long, value_parser = export::parse_time)]
+        since: Option<i64>,
+        /// Only snapshots taken before this time.
+        #[arg(long, value_parser = export::parse_time)]
+        until: Option<i64>,
+        /// Only this item. Can be given more than once.
+        #[arg(long = "item")]
+        item_ids: Vec<ShopItemId>,
+    },
+    /// Rewrite every stored snapshot in the current schema.
+    Migrate {
+        /// Only list the snapshots that would be rewritten.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Maintain the stored snapshots.
+    Storage {
+        #[command(subcommand)]
+        command: StorageCommand,
+    },
+    /// Choose who gets pinged about which items. Works while the daemon is running.
+    Watch {
+        #[command(subcommand)]
+        command: WatchCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum StorageCommand {
+    /// Prune and compress old snapshots, following the RETAIN_* and COMPRESS_AFTER_DAYS settings.
+    Gc {
+        /// Only list what would be removed or compressed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy the snapshot files into the SQLite database used by `STORAGE_BACKEND=sqlite`.
+    Import,
+}
+
+#[derive(Subcommand)]
+enum WatchCommand {
+    /// Subscribe a user to changes and print the subscription's ID.
+    Add {
+        /// Slack member ID to mention, like U012AB3CD.
+        #[arg(long, required_unless_present = "discord_user")]
+        slack_user: Option<String>,
+        /// Discord user ID to mention.
+        #[arg(long)]
+        discord_user: Option<String>,
+        /// Item to watch. Can be given more than once. Without --item or --title, every item is
+        /// watched.
+        #[arg(long = "item")]
+        item_ids: Vec<ShopItemId>,
+        /// Watch items whose title contains this, ignoring case. Can be given more than once.
+        #[arg(long = "title")]
+        title_contains: Vec<String>,
+        #[arg(long, default_value = "US")]
+        region: String,
+        /// below:N (the price in the region drops below N cookies), back-in-stock or
+        /// lock-removed (the item no longer needs an achievement).
+        #[arg(long = "when", value_parser = watchlist::parse_condition)]
+        condition: Condition,
+    },
+    /// List every subscription.
+    List,
+    /// Remove a subscription by ID.
+    Remove { id: u64 },
+}
 
 fn main() -> Result<()> {
+    let cli = Cli::parse();
     dotenvy::dotenv().ok();
     color_eyre::install()?;
     env_logger::init();
@@ -22,13 +154,199 @@ fn main() -> Result<()> {
         ))
     });
 
+    let command = cli.command.unwrap_or(Command::Run);
+    // the watchlist is a file of its own, so the daemon's lock doesn't get in the way.
+    if let Command::Watch { command } = command {
+        return watch(command);
+    }
+
+    // serving, exporting and showing history only read, so they can run next to the daemon. They
+    // don't backfill the history index either, which the daemon does when it starts.
+    let read_only = matches!(
+        command,
+        Command::Serve | Command::Export { .. } | Command::History { .. }
+    );
+    let _lock = if read_only {
+        None
+    } else {
+        Some(storage::lock_storage()?)
+    };
+    if !read_only && config::CONFIG.storage_backend == StorageBackend::Files {
+        history::backfill()?;
+    }
+
+    // sentry has to be set up before the runtime starts, so this isn't `#[tokio::main]`.
+    let runtime = tokio::runtime::Runtime::new()?;
+
+    match command {
+        Command::Run => {
+            let mut latest = STORE.load_latest()?;
+            runtime.block_on(run_once(&mut latest))
+        }
+        Command::Daemon => {
+            server::spawn();
+            runtime.block_on(daemon::run())
+        }
+        Command::Serve => server::serve(),
+        Command::History { item_id, region } => {
+            let region =
+                Region::from_code(&region).ok_or_else(|| eyre!("unknown region {region}"))?;
+            print_history(item_id, &region)
+        }
+        Command::Export {
+            format,
+            output,
+            since,
+            until,
+            item_ids,
+        } => export::export(
+            format,
+            output.as_deref(),
+            &ExportFilter {
+                since,
+                until,
+                item_ids,
+            },
+        ),
+        Command::Migrate { dry_run } => migrate::migrate_storage(dry_run),
+        Command::Storage {
+            command: StorageCommand::Gc { dry_run },
+        } => gc::collect_garbage(dry_run),
+        Command::Storage {
+            command: StorageCommand::Import,
+        } => sqlite::import_snapshots(),
+        Command::Watch { .. } => unreachable!("handled before taking the lock"),
+    }
+}
+
+fn watch(command: WatchCommand) -> Result<()> {
+    match command {
+        WatchCommand::Add {
+            slack_user,
+            discord_user,
+            item_ids,
+            title_contains,
+            region,
+            condition,
+        } => {
+            let region =
+                Region::from_code(&region).ok_or_else(|| eyre!("unknown region {region}"))?;
+            let id = watchlist::add(Subscription {
+                id: 0,
+                slack_user,
+                discord_user,
+                item_ids,
+                title_contains,
+                region,
+                condition,
+            })?;
+            println!("Added subscription {id}");
+        }
+        WatchCommand::List => {
+            for s in watchlist::load()? {
+                let users: Vec<_> = [("slack", &s.slack_user), ("discord", &s.discord_user)]
+                    .into_iter()
+                    .filter_map(|(kind, user)| Some(format!("{kind}:{}", user.as_ref()?)))
+                    .collect();
+                let items: Vec<_> = s
+                    .item_ids
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(s.title_contains.iter().map(|t| format!("{t:?}")))
+                    .collect();
+                let items = if items.is_empty() {
+                    "every item".to_string()
+                } else {
+                    items.join(", ")
+                };
+                println!(
+                    "{:>4}  {}  {items}  in {}  when {}",
+                    s.id,
+                    users.join(" "),
+                    s.region.code(),
+                    s.condition
+                );
+            }
+        }
+        WatchCommand::Remove { id } => {
+            if !watchlist::remove(id)? {
+                return Err(eyre!("there is no subscription {id}"));
+            }
+            println!("Removed subscription {id}");
+        }
+    }
+    Ok(())
+}
+
+fn print_history(item_id: ShopItemId, region: &Region) -> Result<()> {
+    let fmt = |ts| time_format::format_iso8601_utc(ts).unwrap();
+
+    let Some(history) = STORE.item_history(item_id)? else {
+        println!("Item {item_id} has never been seen");
+        return Ok(());
+    };
+    println!("First seen: {}", fmt(history.first_seen));
+    match history.last_in_stock {
+        Some(ts) => println!("Last in stock: {}", fmt(ts)),
+        None => println!("Last in stock: never"),
+    }
+
+    println!("Price in {region}:");
+    for point in history.prices.get(region).into_iter().flatten() {
+        println!("  {}  {}", fmt(point.at), point.price);
+    }
+    Ok(())
+}
+
+/// Scrapes the shop, queues notifications about anything that changed since `latest` and
+/// stores the new snapshot, then sends whatever is waiting in the outbox. `latest` is only
+/// replaced once the new snapshot has been written.
+pub async fn run_once(latest: &mut Option<Snapshot>) -> Result<()> {
+    // a run that died between queueing its messages and writing its snapshot left it behind.
+    if let Some(snapshot) = outbox::commit_snapshot()? {
+        *latest = Some(snapshot);
+    }
+
+    let checked = check_for_changes(latest).await;
+    let delivered = outbox::deliver().await;
+    checked?;
+    delivered
+}
+
+/// Alerts about each logged out session once, until it's logged in again.
+fn alert_expired_sessions(sessions: &[String]) -> Result<()> {
+    for session in sessions {
+        let alert = Alert::SessionExpired {
+            session: session.clone(),
+        };
+        outbox::enqueue_session_expired(session, notify::render_alert(&alert)?)?;
+    }
+    Ok(())
+}
+
+async fn check_for_changes(latest: &mut Option<Snapshot>) -> Result<()> {
     info!("Starting scrape job...");
-    let items = scraper::scrape()?;
-    let old_snap = storage::load_latest_snapshot()?;
+    let snapshot = match scraper::scrape(latest.as_ref()).await {
+        Ok(scrape) => {
+            for error in &scrape.errors {
+                warn!("Scraped around a failure - {error}");
+            }
+            alert_expired_sessions(&scrape.expired_sessions)?;
+            outbox::clear_expired_sessions(&scrape.expired_sessions)?;
+            scrape.snapshot
+        }
+        Err(e) => {
+            if let Some(expired) = e.downcast_ref::<SessionExpired>() {
+                let session = expired.session.clone().unwrap_or_else(|| "COOKIE".into());
+                alert_expired_sessions(&[session])?;
+            }
+            return Err(e);
+        }
+    };
 
-    match old_snap {
+    match latest {
         Some(old_snap) => {
-            let item_diff = diff::compute_diff(&old_snap, &items);
+            let mut item_diff = diff::compute_diff(old_snap, &snapshot);
 
             if item_diff.is_empty() {
                 info!("Items haven't changed - exiting!");
@@ -36,18 +354,30 @@ fn main() -> Result<()> {
             }
 
             info!(
-                "*flavortown updates:* {} new, {} updated, {} deleted items",
+                "*flavortown updates:* {} new, {} updated, {} deleted items, {} new and {} removed regions",
                 item_diff.new_items.len(),
                 item_diff.updated_items.len(),
-                item_diff.deleted_items.len()
+                item_diff.deleted_items.len(),
+                item_diff.new_regions.len(),
+                item_diff.removed_regions.len()
             );
 
-            diff::send_webhook_notifications(&item_diff)?;
-            storage::write_new_snapshot(&items)?;
+            summary::summarize_long_descriptions(&mut item_diff).await;
+            let messages = notify::render_all(&item_diff)?;
+            outbox::enqueue(
+                messages,
+                Some(PendingSnapshot {
+                    at: time_format::now().unwrap(),
+                    snapshot,
+                    diff: item_diff,
+                }),
+            )?;
+            *latest = outbox::commit_snapshot()?;
         }
         None => {
             warn!("No old snapshot found, writing first snapshot and exiting");
-            storage::write_new_snapshot(&items)?;
+            STORE.write(time_format::now().unwrap(), &snapshot)?;
+            *latest = Some(snapshot);
         }
     }
 
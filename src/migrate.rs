@@ -0,0 +1,225 @@
+//! Snapshot schema versions, and the migrations between them.
+//!
+//! Snapshots are always written in the current schema. Older ones are upgraded in memory when
+//! they're loaded, one version at a time, and the `migrate` command rewrites them on disk.
+//!
+//! - 0: a bare list of items, from before regions were read from the shop page.
+//! - 1: `{"regions": [...], "items": [...]}`.
+//! - 2: the versioned envelope: `schema_version`, `tracker_version` and `scrape` next to the
+//!   regions and items.
+
+use crate::config::CONFIG;
+use crate::scraper::known_regions;
+use crate::storage;
+use crate::store::StorageBackend;
+use color_eyre::{Result, eyre::eyre};
+use log::{info, warn};
+use serde_json::{Value, json};
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// `MIGRATIONS[n]` turns a version `n` snapshot into a version `n + 1` one.
+const MIGRATIONS: [fn(Value) -> Result<Value>; SCHEMA_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// Version 0 snapshots were taken across the regions that were hard-coded back then.
+fn v0_to_v1(items: Value) -> Result<Value> {
+    Ok(json!({
+        "regions": known_regions(),
+        "items": items,
+    }))
+}
+
+/// Nothing is known about how version 1 snapshots were taken.
+fn v1_to_v2(mut snapshot: Value) -> Result<Value> {
+    let object = snapshot
+        .as_object_mut()
+        .ok_or_else(|| eyre!("version 1 snapshot isn't an object"))?;
+    object.insert("schema_version".into(), json!(2));
+    object.insert("tracker_version".into(), Value::Null);
+    object.insert("scrape".into(), Value::Null);
+    Ok(snapshot)
+}
+
+fn version_of(snapshot: &Value) -> Result<u32> {
+    match snapshot {
+        Value::Array(_) => Ok(0),
+        Value::Object(object) => match object.get("schema_version") {
+            None => Ok(1),
+            Some(version) => version
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| eyre!("invalid schema_version {version}")),
+        },
+        _ => Err(eyre!("not a snapshot")),
+    }
+}
+
+/// Brings a snapshot of any version up to [`SCHEMA_VERSION`].
+pub fn upgrade(mut snapshot: Value) -> Result<Value> {
+    let version = version_of(&snapshot)?;
+    if version > SCHEMA_VERSION {
+        return Err(eyre!(
+            "snapshot has schema version {version}, but this tracker only understands up to \
+             {SCHEMA_VERSION} - upgrade the tracker"
+        ));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        snapshot = migration(snapshot)?;
+    }
+    Ok(snapshot)
+}
+
+/// Rewrites every stored snapshot that isn't in the current schema. With `dry_run`, only reports
+/// what would change.
+pub fn migrate_storage(dry_run: bool) -> Result<()> {
+    if CONFIG.storage_backend != StorageBackend::Files {
+        return Err(eyre!(
+            "migrate only rewrites snapshot files - with STORAGE_BACKEND=sqlite snapshots are \
+             upgraded as they're imported, and the database keeps no schema versions"
+        ));
+    }
+    let mut migrated = 0;
+    let mut current = 0;
+    let mut damaged = 0;
+    for (_, path) in storage::list_snapshots()? {
+        let version = match storage::read_snapshot_json(&path).and_then(|raw| version_of(&raw)) {
+            Ok(version) => version,
+            Err(e) => {
+                warn!("Leaving a damaged snapshot alone: {e:#}");
+                damaged += 1;
+                continue;
+            }
+        };
+        if version == SCHEMA_VERSION {
+            current += 1;
+            continue;
+        }
+
+        info!(
+            "{} {} from schema {version} to {SCHEMA_VERSION}",
+            if dry_run {
+                "Would migrate"
+            } else {
+                "Migrating"
+            },
+            path.display()
+        );
+        if !dry_run {
+            storage::write_snapshot_file(&path, &storage::load_snapshot(&path)?)?;
+        }
+        migrated += 1;
+    }
+
+    println!(
+        "{migrated} snapshot(s) {} to schema {SCHEMA_VERSION}, {current} already current, \
+         {damaged} damaged",
+        if dry_run {
+            "need migrating"
+        } else {
+            "migrated"
+        }
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::scraper::{Region, ShopItem};
+    use crate::storage::Snapshot;
+
+    /// One snapshot per schema version, as the tracker wrote them at the time.
+    fn fixture(version: u32) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(format!("tests/fixtures/snapshots/v{version}.json"))
+    }
+
+    fn price(item: &ShopItem, code: &str) -> Option<u32> {
+        item.prices.get(&Region::from_code(code).unwrap()).copied()
+    }
+
+    #[test]
+    fn detects_versions() {
+        for version in 0..=SCHEMA_VERSION {
+            let raw = storage::read_snapshot_json(&fixture(version)).unwrap();
+            assert_eq!(version_of(&raw).unwrap(), version);
+        }
+    }
+
+    #[test]
+    fn upgrades_bare_item_lists() {
+        let raw = storage::read_snapshot_json(&fixture(0)).unwrap();
+        let upgraded = upgrade(raw).unwrap();
+        assert_eq!(upgraded["schema_version"], 2);
+        assert_eq!(upgraded["tracker_version"], Value::Null);
+        assert_eq!(upgraded["scrape"], Value::Null);
+
+        let snapshot = storage::load_snapshot(&fixture(0)).unwrap();
+        assert_eq!(snapshot.regions, known_regions());
+        let [pen, mug] = snapshot.items.as_slice() else {
+            panic!("expected two items");
+        };
+        assert_eq!(price(pen, "US"), Some(100));
+        assert_eq!(price(pen, "UK"), Some(90));
+        assert_eq!(price(pen, "XX"), Some(120));
+        assert_eq!(price(mug, "EU"), Some(310));
+        assert_eq!(
+            mug.accessories[0].prices[&Region::from_code("EU").unwrap()],
+            11
+        );
+        assert_eq!(mug.remaining_stock, None);
+        assert!(mug.incomplete_regions.is_empty());
+    }
+
+    #[test]
+    fn upgrades_region_lists() {
+        let snapshot = storage::load_snapshot(&fixture(1)).unwrap();
+        let codes: Vec<_> = snapshot.regions.iter().map(|r| r.code.as_str()).collect();
+        assert_eq!(codes, ["US", "UK", "BR"]);
+        assert_eq!(snapshot.tracker_version, None);
+        assert!(snapshot.scrape.is_none());
+        assert_eq!(price(&snapshot.items[0], "UK"), Some(90));
+        assert_eq!(price(&snapshot.items[0], "BR"), Some(80));
+    }
+
+    #[test]
+    fn loads_current_snapshots_as_they_are() {
+        let raw = storage::read_snapshot_json(&fixture(2)).unwrap();
+        assert_eq!(upgrade(raw.clone()).unwrap(), raw);
+
+        let snapshot = storage::load_snapshot(&fixture(2)).unwrap();
+        assert_eq!(snapshot.tracker_version.as_deref(), Some("0.1.0"));
+        assert_eq!(snapshot.scrape.unwrap().errors.len(), 1);
+        assert_eq!(
+            snapshot.items[0].incomplete_regions,
+            [Region::from_code("UK").unwrap()]
+        );
+    }
+
+    #[test]
+    fn rewrites_old_snapshots_in_the_current_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        for version in 0..SCHEMA_VERSION {
+            let snapshot = storage::load_snapshot(&fixture(version)).unwrap();
+            let path = dir.path().join(format!("snap_v{version}.json"));
+            storage::write_snapshot_file(&path, &snapshot).unwrap();
+
+            let raw = storage::read_snapshot_json(&path).unwrap();
+            assert_eq!(version_of(&raw).unwrap(), SCHEMA_VERSION);
+            // known regions keep the keys older trackers read.
+            assert_eq!(raw["items"][0]["prices"]["UnitedStates"], 100);
+            let rewritten: Snapshot = serde_json::from_value(raw).unwrap();
+            assert_eq!(rewritten.items, snapshot.items);
+            assert_eq!(rewritten.regions, snapshot.regions);
+        }
+    }
+
+    #[test]
+    fn refuses_newer_schemas() {
+        let from_the_future = json!({"schema_version": SCHEMA_VERSION + 1, "items": []});
+        assert!(upgrade(from_the_future).is_err());
+        assert!(upgrade(json!("not a snapshot")).is_err());
+    }
+}
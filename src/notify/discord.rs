@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+
+use super::{Alert, Notifier, Payload, Ping, Post};
+use crate::diff::{FieldChange, ItemDiff, UpdatedItem};
+use crate::scraper::{Accessory, Region, RegionInfo, ShopItem};
+use color_eyre::Result;
+use reqwest::Url;
+use serde::Serialize;
+
+const EMOJI_COOKIE: &str = "🍪";
+const EMOJI_MEDAL: &str = "🏅";
+
+const COLOR_NEW: u32 = 0x57f287;
+const COLOR_UPDATED: u32 = 0xfee75c;
+const COLOR_DELETED: u32 = 0xed4245;
+
+// https://discord.com/developers/docs/resources/message#embed-object-embed-limits
+const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+const MAX_CHARS_PER_MESSAGE: usize = 6000;
+const MAX_TITLE_CHARS: usize = 256;
+const MAX_DESCRIPTION_CHARS: usize = 4096;
+const MAX_FIELD_VALUE_CHARS: usize = 1024;
+const MAX_CONTENT_CHARS: usize = 2000;
+
+#[derive(Serialize)]
+struct EmbedImage {
+    url: Url,
+}
+
+#[derive(Serialize)]
+struct EmbedField {
+    name: String,
+    value: String,
+    inline: bool,
+}
+
+#[derive(Serialize)]
+struct Embed {
+    title: String,
+    description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<Url>,
+    color: u32,
+    fields: Vec<EmbedField>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail: Option<EmbedImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<EmbedImage>,
+}
+
+impl Embed {
+    /// Characters that count towards the per-message limit.
+    fn len(&self) -> usize {
+        self.title.chars().count()
+            + self.description.chars().count()
+            + self
+                .fields
+                .iter()
+                .map(|f| f.name.chars().count() + f.value.chars().count())
+                .sum::<usize>()
+    }
+
+    /// Cuts the description down until the whole embed fits in `max_chars`. If that isn't
+    /// enough, the longest fields are cut too, and dropped once there's nothing left to cut.
+    fn shrink_to(&mut self, max_chars: usize) {
+        let overflow = self.len().saturating_sub(max_chars);
+        if overflow > 0 {
+            let keep = self
+                .description
+                .chars()
+                .count()
+                .saturating_sub(overflow + 1);
+            self.description = truncate(&self.description, keep.max(1));
+        }
+
+        while self.len() > max_chars {
+            let overflow = self.len() - max_chars;
+            let Some(longest) = self
+                .fields
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, f)| f.value.chars().count())
+                .map(|(i, _)| i)
+            else {
+                // titles are capped well below any message's limit, so this can't happen.
+                break;
+            };
+            let value = &mut self.fields[longest].value;
+            let chars = value.chars().count();
+            if chars > overflow + 1 {
+                *value = truncate(value, chars - overflow);
+            } else {
+                self.fields.remove(longest);
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookMessage<'a> {
+    content: &'a str,
+    embeds: &'a [Embed],
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        let mut truncated: String = text.chars().take(max_chars - 1).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+fn escape_markdown(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| match c {
+            '_' | '*' | '~' | '`' | '|' | '>' | '#' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+fn field(name: &str, value: String) -> EmbedField {
+    EmbedField {
+        name: name.to_string(),
+        value: truncate(&value, MAX_FIELD_VALUE_CHARS),
+        inline: false,
+    }
+}
+
+fn format_prices(prices: &HashMap<Region, u32>) -> String {
+    let mut entries: Vec<_> = prices.iter().collect();
+    entries.sort_by_key(|(region, _)| *region);
+
+    match entries.as_slice() {
+        [(region, price)] => format!("{} {price}", region.emoji()),
+        entries
+            if entries.len() == Region::all().len()
+                && entries.iter().all(|(_, p)| *p == entries[0].1) =>
+        {
+            format!("🌎 {}", entries[0].1)
+        }
+        entries => entries
+            .iter()
+            .map(|(r, p)| format!("{} {p}", r.emoji()))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn format_price(price: Option<u32>) -> String {
+    price.map_or("–".to_string(), |p| p.to_string())
+}
+
+fn format_stock(stock: Option<u32>) -> String {
+    match stock {
+        Some(0) => "Out of stock".to_string(),
+        Some(n) => format!("{n} left"),
+        None => "Unlimited".to_string(),
+    }
+}
+
+fn format_accessories(accessories: &[Accessory]) -> String {
+    if accessories.is_empty() {
+        "*none*".to_string()
+    } else {
+        accessories
+            .iter()
+            .map(|a| {
+                format!(
+                    "{} ({EMOJI_COOKIE} {})",
+                    escape_markdown(&a.name),
+                    format_prices(&a.prices)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn format_achievement_lock(lock: Option<&String>) -> String {
+    match lock {
+        Some(s) if s == "Cooking" => "*Cooking (Black Market)*".to_string(),
+        Some(s) if !s.is_empty() => escape_markdown(s),
+        _ => "*none*".to_string(),
+    }
+}
+
+fn format_long_description(desc: Option<&String>) -> String {
+    match desc {
+        Some(s) if !s.is_empty() => format!("*{}*", escape_markdown(s)),
+        _ => "*none*".to_string(),
+    }
+}
+
+fn description(desc: &str) -> String {
+    truncate(
+        &if desc.is_empty() {
+            String::new()
+        } else {
+            format!("*{}*", escape_markdown(desc))
+        },
+        MAX_DESCRIPTION_CHARS,
+    )
+}
+
+fn render_new_item(item: &ShopItem) -> Embed {
+    Embed {
+        title: truncate(&format!("🆕 {}", item.title), MAX_TITLE_CHARS),
+        description: description(&item.description),
+        url: Some(item.buy_link()),
+        color: COLOR_NEW,
+        fields: vec![
+            field(
+                "Price",
+                format!("{EMOJI_COOKIE} {}", format_prices(&item.prices)),
+            ),
+            field("Stock", format_stock(item.remaining_stock)),
+        ],
+        thumbnail: None,
+        image: Some(EmbedImage {
+            url: item.image_url.clone(),
+        }),
+    }
+}
+
+fn render_deleted_item(item: &ShopItem) -> Embed {
+    Embed {
+        title: truncate(&format!("🗑️ {}", item.title), MAX_TITLE_CHARS),
+        description: description(&item.description),
+        url: Some(item.buy_link()),
+        color: COLOR_DELETED,
+        fields: vec![field(
+            "Price",
+            format!("{EMOJI_COOKIE} {}", format_prices(&item.prices)),
+        )],
+        thumbnail: None,
+        image: Some(EmbedImage {
+            url: item.image_url.clone(),
+        }),
+    }
+}
+
+fn render_updated_item(item: &UpdatedItem) -> Embed {
+    let (old, new) = (&item.old, &item.new);
+
+    let mut title = new.title.clone();
+    let mut description = self::description(&new.description);
+    let mut price_lines = Vec::new();
+    let mut long_description = None;
+    let mut stock = format_stock(new.remaining_stock);
+    let mut achievement = format_achievement_lock(new.achievement_lock.as_ref());
+    let mut thumbnail = None;
+
+    for change in &item.changes {
+        match change {
+            FieldChange::Title { old, new } => title = format!("{old} → {new}"),
+            FieldChange::Description { old, new } => {
+                description = format!("{} → {}", self::description(old), self::description(new));
+            }
+            FieldChange::LongDescription {
+                summary: Some(s), ..
+            } => {
+                long_description = Some(escape_markdown(s));
+            }
+            FieldChange::LongDescription { old, new, .. } => {
+                long_description = Some(format!(
+                    "{} → {}",
+                    format_long_description(old.as_ref()),
+                    format_long_description(new.as_ref())
+                ));
+            }
+            FieldChange::Price { region, old, new } => price_lines.push(format!(
+                "{} {} → {}{}",
+                region.emoji(),
+                format_price(*old),
+                format_price(*new),
+                change
+                    .price_delta()
+                    .map_or(String::new(), |delta| format!(" ({delta:+})"))
+            )),
+            FieldChange::Stock { old, new } => {
+                stock = format!("{} → {}", format_stock(*old), format_stock(*new));
+            }
+            FieldChange::AchievementLock { old, new } => {
+                achievement = format!(
+                    "{} → {}",
+                    format_achievement_lock(old.as_ref()),
+                    format_achievement_lock(new.as_ref())
+                );
+            }
+            // embeds only have room for one big image, so the old one becomes the thumbnail.
+            FieldChange::Image { old, .. } => thumbnail = Some(EmbedImage { url: old.clone() }),
+            FieldChange::AccessoryAdded { .. }
+            | FieldChange::AccessoryRemoved { .. }
+            | FieldChange::AccessoryRepriced { .. } => {}
+        }
+    }
+
+    let mut fields = vec![field(
+        "Price",
+        if price_lines.is_empty() {
+            format!("{EMOJI_COOKIE} {}", format_prices(&new.prices))
+        } else {
+            // one line per region that changed, so a single-region change doesn't get lost.
+            format!("{EMOJI_COOKIE}\n{}", price_lines.join("\n"))
+        },
+    )];
+
+    if let Some(long_description) = long_description {
+        fields.push(field("Long Description", long_description));
+    }
+
+    if item.has(FieldChange::is_accessory) {
+        fields.push(field(
+            "Accessories",
+            format!(
+                "{} → {}",
+                format_accessories(&old.accessories),
+                format_accessories(&new.accessories)
+            ),
+        ));
+    }
+
+    fields.push(field("Stock", stock));
+    fields.push(field(
+        &format!("{EMOJI_MEDAL} Requires achievement"),
+        achievement,
+    ));
+
+    Embed {
+        title: truncate(&title, MAX_TITLE_CHARS),
+        description: truncate(&description, MAX_DESCRIPTION_CHARS),
+        url: Some(new.buy_link()),
+        color: COLOR_UPDATED,
+        fields,
+        thumbnail,
+        image: Some(EmbedImage {
+            url: new.image_url.clone(),
+        }),
+    }
+}
+
+fn render_region(region: &RegionInfo, added: bool) -> Embed {
+    let (emoji, change, color) = if added {
+        ("🆕", "New region", COLOR_NEW)
+    } else {
+        ("🗑️", "Region removed", COLOR_DELETED)
+    };
+    Embed {
+        title: truncate(
+            &format!(
+                "{emoji} {change}: {} {}",
+                region.region().emoji(),
+                region.name
+            ),
+            MAX_TITLE_CHARS,
+        ),
+        description: format!("Region code `{}`", region.code),
+        url: None,
+        color,
+        fields: Vec::new(),
+        thumbnail: None,
+        image: None,
+    }
+}
+
+fn post(webhook_url: &Url, content: &str, embeds: &[Embed]) -> Result<Post> {
+    Post::json(webhook_url, &WebhookMessage { content, embeds })
+}
+
+pub struct DiscordNotifier {
+    pub webhook_url: Url,
+}
+
+impl Notifier for DiscordNotifier {
+    fn render(&self, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Payload>> {
+        Ok(render_notifications(&self.webhook_url, diff, ping)?
+            .into_iter()
+            .map(Payload::Post)
+            .collect())
+    }
+
+    fn render_alert(&self, alert: &Alert) -> Result<Vec<Payload>> {
+        Ok(vec![Payload::Post(post(
+            &self.webhook_url,
+            &format!("⚠️ {}", escape_markdown(&alert.to_string())),
+            &[],
+        )?)])
+    }
+}
+
+fn render_notifications(webhook_url: &Url, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Post>> {
+    let embeds: Vec<Embed> = diff
+        .new_regions
+        .iter()
+        .map(|region| render_region(region, true))
+        .chain(
+            diff.removed_regions
+                .iter()
+                .map(|region| render_region(region, false)),
+        )
+        .chain(diff.new_items.iter().map(render_new_item))
+        .chain(diff.updated_items.iter().map(render_updated_item))
+        .chain(diff.deleted_items.iter().map(render_deleted_item))
+        .collect();
+
+    let content = format!(
+        "**flavortown updates:** {} new, {} updated, {} removed",
+        diff.new_items.len(),
+        diff.updated_items.len(),
+        diff.deleted_items.len()
+    );
+
+    let mut posts = Vec::new();
+    let mut message: Vec<Embed> = Vec::new();
+    let mut message_chars = content.chars().count();
+
+    for mut embed in embeds {
+        embed.shrink_to(MAX_CHARS_PER_MESSAGE - content.chars().count());
+        if !message.is_empty()
+            && (message.len() == MAX_EMBEDS_PER_MESSAGE
+                || message_chars + embed.len() > MAX_CHARS_PER_MESSAGE)
+        {
+            posts.push(post(webhook_url, &content, &message)?);
+            message.clear();
+            message_chars = content.chars().count();
+        }
+
+        message_chars += embed.len();
+        message.push(embed);
+    }
+
+    if !message.is_empty() {
+        posts.push(post(webhook_url, &content, &message)?);
+    }
+
+    // a message of its own, so subscribers are pinged once however many posts the embeds take.
+    if let Ping::Subscribers(matches) = ping {
+        let mentions: Vec<String> = matches
+            .iter()
+            .filter_map(|m| {
+                let user = m.discord_user.as_ref()?;
+                Some(format!("<@{user}> {}", escape_markdown(&m.reason)))
+            })
+            .collect();
+        if !mentions.is_empty() {
+            let content = truncate(&mentions.join("\n"), MAX_CONTENT_CHARS);
+            posts.push(post(webhook_url, &content, &[])?);
+        }
+    }
+
+    Ok(posts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::compute_changes;
+    use crate::testing;
+    use serde_json::Value;
+
+    const ITEMS: usize = 25;
+
+    /// An item with every field as long as it can get.
+    fn huge(id: usize, price: u32) -> ShopItem {
+        let mut item = testing::item(id, &"T".repeat(300), &[("US", price), ("UK", price)]);
+        item.description = "d".repeat(5000);
+        item.long_description = Some(format!("{price} {}", "l".repeat(2000)));
+        item.accessories = (0..40)
+            .map(|i| Accessory {
+                id: i,
+                name: format!("{price} {}", "a".repeat(60)),
+                prices: [(Region::from_code("US").unwrap(), price)].into(),
+            })
+            .collect();
+        item.remaining_stock = Some(price);
+        item
+    }
+
+    fn huge_diff() -> ItemDiff {
+        let updated = (0..ITEMS)
+            .map(|id| {
+                let (old, new) = (huge(id, 100), huge(id, 90));
+                UpdatedItem {
+                    changes: compute_changes(&old, &new),
+                    old,
+                    new,
+                }
+            })
+            .collect();
+        ItemDiff {
+            new_items: (100..100 + ITEMS).map(|id| huge(id, 10)).collect(),
+            updated_items: updated,
+            deleted_items: (200..200 + ITEMS).map(|id| huge(id, 10)).collect(),
+            new_regions: Vec::new(),
+            removed_regions: Vec::new(),
+        }
+    }
+
+    fn chars(value: &Value) -> usize {
+        value.as_str().map_or(0, |s| s.chars().count())
+    }
+
+    #[test]
+    fn every_message_fits_discords_limits() {
+        let url: Url = "https://discord.com/api/webhooks/1/x".parse().unwrap();
+        let posts = render_notifications(&url, &huge_diff(), &Ping::Channel).unwrap();
+
+        let mut embeds = 0;
+        for post in &posts {
+            let body: Value = serde_json::from_str(&post.body).unwrap();
+            let message = body["embeds"].as_array().unwrap();
+            assert!(message.len() <= MAX_EMBEDS_PER_MESSAGE);
+            let total: usize = chars(&body["content"])
+                + message
+                    .iter()
+                    .map(|embed| {
+                        chars(&embed["title"])
+                            + chars(&embed["description"])
+                            + embed["fields"]
+                                .as_array()
+                                .unwrap()
+                                .iter()
+                                .map(|f| chars(&f["name"]) + chars(&f["value"]))
+                                .sum::<usize>()
+                    })
+                    .sum::<usize>();
+            assert!(total <= MAX_CHARS_PER_MESSAGE, "{total} characters");
+            embeds += message.len();
+        }
+        // nothing was left out to make it fit.
+        assert_eq!(embeds, 3 * ITEMS);
+    }
+
+    #[test]
+    fn cuts_fields_when_the_description_is_not_enough() {
+        let mut embed = Embed {
+            title: "Sticker".to_string(),
+            description: "d".repeat(100),
+            url: None,
+            color: COLOR_UPDATED,
+            fields: (0..8)
+                .map(|i| field(&format!("Field {i}"), "v".repeat(MAX_FIELD_VALUE_CHARS)))
+                .collect(),
+            thumbnail: None,
+            image: None,
+        };
+        embed.shrink_to(3000);
+        assert!(embed.len() <= 3000, "{}", embed.len());
+        assert!(embed.fields.iter().all(|f| !f.value.is_empty()));
+
+        // fields that can't be cut short enough go.
+        embed.shrink_to(100);
+        assert!(embed.len() <= 100, "{}", embed.len());
+        assert!(embed.fields.len() < 8);
+    }
+}
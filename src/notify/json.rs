@@ -0,0 +1,335 @@
+//! POSTs the raw changes as JSON, for tools that want data rather than a chat message.
+//!
+//! The body is a [`DiffDocument`]. Its shape only changes together with [`SCHEMA_VERSION`]
+//! (also sent as the `X-Flavortown-Schema-Version` header), and within a version fields are only
+//! ever added. Prices are keyed by region code (`US`, `EU`, `UK`, `IN`, `CA`, `AU`, `XX`, or
+//! whatever code a region added later has), and timestamps are RFC 3339 in UTC.
+//!
+//! Operational alerts are sent as an [`AlertDocument`] to the same URL.
+//!
+//! If the sink has a `secret`, the body is signed with HMAC-SHA256 and the hex digest is sent as
+//! `X-Flavortown-Signature: sha256=<digest>`. Receivers should compute the same over the raw
+//! body and compare in constant time.
+//!
+//! Every document carries an `X-Flavortown-Delivery` ID that stays the same if it has to be sent
+//! again, so receivers can drop duplicates.
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::{Alert, Notifier, Payload, Ping, Post};
+use crate::diff::{FieldChange, ItemDiff, UpdatedItem};
+use crate::scraper::{Accessory, Region, RegionInfo, ShopItem, ShopItemId};
+use color_eyre::Result;
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::Url;
+use serde::Serialize;
+use sha2::Sha256;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Sent instead of a [`DiffDocument`] when something needs a human, e.g. the session expired.
+/// Receivers can tell the two apart by the `alert` key.
+#[derive(Serialize)]
+pub struct AlertDocument {
+    pub schema_version: u32,
+    pub generated_at: String,
+    pub alert: AlertBody,
+}
+
+#[derive(Serialize)]
+pub struct AlertBody {
+    /// Machine-readable, e.g. `session_expired`.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct DiffDocument {
+    pub schema_version: u32,
+    pub generated_at: String,
+    pub new_items: Vec<ItemDocument>,
+    pub updated_items: Vec<UpdatedItemDocument>,
+    pub deleted_items: Vec<ItemDocument>,
+    /// Regions added to the shop. Prices in them aren't repeated under `updated_items`.
+    pub new_regions: Vec<RegionInfo>,
+    /// Regions removed from the shop, likewise.
+    pub removed_regions: Vec<RegionInfo>,
+}
+
+#[derive(Serialize)]
+pub struct ItemDocument {
+    pub id: ShopItemId,
+    pub title: String,
+    pub description: String,
+    pub long_description: Option<String>,
+    pub prices: BTreeMap<String, u32>,
+    /// `null` means unlimited stock.
+    pub remaining_stock: Option<u32>,
+    pub achievement_lock: Option<String>,
+    pub accessories: Vec<AccessoryDocument>,
+    pub image_url: Url,
+    pub buy_url: Url,
+}
+
+#[derive(Serialize)]
+pub struct AccessoryDocument {
+    pub id: usize,
+    pub name: String,
+    pub prices: BTreeMap<String, u32>,
+}
+
+/// The item as it is now, plus only the fields that changed.
+#[derive(Serialize)]
+pub struct UpdatedItemDocument {
+    pub id: ShopItemId,
+    pub item: ItemDocument,
+    pub changes: Changes,
+}
+
+#[derive(Serialize)]
+pub struct Change<T> {
+    pub old: T,
+    pub new: T,
+}
+
+#[derive(Serialize, Default)]
+pub struct Changes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<Change<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<Change<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long_description: Option<Change<Option<String>>>,
+    /// A short summary of the long description change, when AI summaries are enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub long_description_summary: Option<String>,
+    /// Region code -> old/new price. A `null` side means the item wasn't sold there.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub prices: BTreeMap<String, Change<Option<u32>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_stock: Option<Change<Option<u32>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accessories: Option<AccessoryChanges>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub achievement_lock: Option<Change<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<Change<Url>>,
+}
+
+#[derive(Serialize, Default)]
+pub struct AccessoryChanges {
+    pub added: Vec<AccessoryDocument>,
+    pub removed: Vec<AccessoryDocument>,
+    pub repriced: Vec<AccessoryRepricing>,
+}
+
+#[derive(Serialize)]
+pub struct AccessoryRepricing {
+    pub id: usize,
+    pub name: String,
+    pub prices: BTreeMap<String, Change<Option<u32>>>,
+}
+
+fn price_map(prices: &HashMap<Region, u32>) -> BTreeMap<String, u32> {
+    prices
+        .iter()
+        .map(|(r, p)| (r.code().to_string(), *p))
+        .collect()
+}
+
+impl From<&Accessory> for AccessoryDocument {
+    fn from(accessory: &Accessory) -> Self {
+        Self {
+            id: accessory.id,
+            name: accessory.name.clone(),
+            prices: price_map(&accessory.prices),
+        }
+    }
+}
+
+impl From<&ShopItem> for ItemDocument {
+    fn from(item: &ShopItem) -> Self {
+        Self {
+            id: item.id,
+            title: item.title.clone(),
+            description: item.description.clone(),
+            long_description: item.long_description.clone(),
+            prices: price_map(&item.prices),
+            remaining_stock: item.remaining_stock,
+            achievement_lock: item.achievement_lock.clone(),
+            accessories: item.accessories.iter().map(Into::into).collect(),
+            image_url: item.image_url.clone(),
+            buy_url: item.buy_link(),
+        }
+    }
+}
+
+fn updated_item(item: &UpdatedItem) -> UpdatedItemDocument {
+    let mut changes = Changes::default();
+    let mut accessories = AccessoryChanges::default();
+
+    for change in &item.changes {
+        match change.clone() {
+            FieldChange::Title { old, new } => changes.title = Some(Change { old, new }),
+            FieldChange::Description { old, new } => {
+                changes.description = Some(Change { old, new });
+            }
+            FieldChange::LongDescription { old, new, summary } => {
+                changes.long_description = Some(Change { old, new });
+                changes.long_description_summary = summary;
+            }
+            FieldChange::Price { region, old, new } => {
+                changes
+                    .prices
+                    .insert(region.code().to_string(), Change { old, new });
+            }
+            FieldChange::Stock { old, new } => changes.remaining_stock = Some(Change { old, new }),
+            FieldChange::AccessoryAdded { accessory } => {
+                accessories.added.push((&accessory).into());
+            }
+            FieldChange::AccessoryRemoved { accessory } => {
+                accessories.removed.push((&accessory).into());
+            }
+            FieldChange::AccessoryRepriced {
+                id,
+                name,
+                region,
+                old,
+                new,
+            } => {
+                let prices = match accessories.repriced.iter_mut().find(|r| r.id == id) {
+                    Some(repricing) => &mut repricing.prices,
+                    None => {
+                        accessories.repriced.push(AccessoryRepricing {
+                            id,
+                            name,
+                            prices: BTreeMap::new(),
+                        });
+                        &mut accessories.repriced.last_mut().unwrap().prices
+                    }
+                };
+                prices.insert(region.code().to_string(), Change { old, new });
+            }
+            FieldChange::AchievementLock { old, new } => {
+                changes.achievement_lock = Some(Change { old, new });
+            }
+            FieldChange::Image { old, new } => changes.image_url = Some(Change { old, new }),
+        }
+    }
+
+    if item.has(FieldChange::is_accessory) {
+        changes.accessories = Some(accessories);
+    }
+
+    UpdatedItemDocument {
+        id: item.new.id,
+        item: (&item.new).into(),
+        changes,
+    }
+}
+
+impl From<&ItemDiff> for DiffDocument {
+    fn from(diff: &ItemDiff) -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            generated_at: time_format::format_iso8601_utc(time_format::now().unwrap()).unwrap(),
+            new_items: diff.new_items.iter().map(Into::into).collect(),
+            updated_items: diff.updated_items.iter().map(updated_item).collect(),
+            deleted_items: diff.deleted_items.iter().map(Into::into).collect(),
+            new_regions: diff.new_regions.clone(),
+            removed_regions: diff.removed_regions.clone(),
+        }
+    }
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub struct JsonWebhookNotifier {
+    pub url: Url,
+    pub secret: Option<String>,
+}
+
+impl JsonWebhookNotifier {
+    fn post(&self, document: &impl Serialize) -> Result<Post> {
+        let mut post = Post::json(&self.url, document)?
+            .header("X-Flavortown-Schema-Version", &SCHEMA_VERSION.to_string());
+        if let Some(secret) = &self.secret {
+            let signature = format!("sha256={}", sign(secret, post.body.as_bytes()));
+            post = post.header("X-Flavortown-Signature", &signature);
+        }
+        Ok(post)
+    }
+}
+
+impl Notifier for JsonWebhookNotifier {
+    fn render(&self, diff: &ItemDiff, _ping: &Ping) -> Result<Vec<Payload>> {
+        Ok(vec![Payload::Post(self.post(&DiffDocument::from(diff))?)])
+    }
+
+    fn render_alert(&self, alert: &Alert) -> Result<Vec<Payload>> {
+        Ok(vec![Payload::Post(self.post(&AlertDocument {
+            schema_version: SCHEMA_VERSION,
+            generated_at: time_format::format_iso8601_utc(time_format::now().unwrap()).unwrap(),
+            alert: AlertBody {
+                kind: alert.kind(),
+                message: alert.to_string(),
+            },
+        })?)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(post: &'a Post, name: &str) -> Option<&'a str> {
+        post.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn signs_like_hmac_sha256() {
+        // RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signs_the_raw_body() {
+        let document = AlertDocument {
+            schema_version: SCHEMA_VERSION,
+            generated_at: "2026-01-01T00:00:00Z".to_string(),
+            alert: AlertBody {
+                kind: "session_expired",
+                message: "The Flavortown session expired".to_string(),
+            },
+        };
+        let mut notifier = JsonWebhookNotifier {
+            url: "https://example.com/hook".parse().unwrap(),
+            secret: Some("whsec_flavortown".to_string()),
+        };
+
+        let post = notifier.post(&document).unwrap();
+        assert_eq!(
+            post.body,
+            r#"{"schema_version":1,"generated_at":"2026-01-01T00:00:00Z","alert":{"kind":"session_expired","message":"The Flavortown session expired"}}"#
+        );
+        assert_eq!(
+            header(&post, "X-Flavortown-Signature"),
+            Some("sha256=361dcdbee7d1ec005ee4e5adfc3b17ece593a48ef44ff112bf0be6fe20441538")
+        );
+
+        notifier.secret = None;
+        let post = notifier.post(&document).unwrap();
+        assert_eq!(header(&post, "X-Flavortown-Signature"), None);
+    }
+}
@@ -0,0 +1,620 @@
+//! Delivery of shop changes to wherever people want to hear about them.
+//!
+//! Sinks are configured with `NOTIFIERS`, a JSON array like
+//!
+//! ```json
+//! [
+//!   {"type": "slack", "url": "https://hooks.slack.com/services/..."},
+//!   {"type": "discord", "url": "https://discord.com/api/webhooks/...",
+//!    "filters": {"changes": ["new", "regions"], "regions": ["UK"]}},
+//!   {"type": "json", "url": "https://example.com/hook", "secret": "hunter2"},
+//!   {"type": "slack", "token": "xoxb-...", "channel": "C0123456789"}
+//! ]
+//! ```
+//!
+//! A Slack sink with a bot `token` and a `channel` instead of a `url` posts through the Web API:
+//! a summary message with the details in its thread, and edits instead of new messages when a
+//! change is corrected soon after, see [`crate::slack_api`].
+//!
+//! `WEBHOOK_URL` and `DISCORD_WEBHOOK_URL` are still honoured and become an unfiltered Slack and
+//! Discord sink respectively.
+//!
+//! Once anyone has subscribed to items with `watch add`, messages mention only the subscribers
+//! a change is for instead of pinging the whole channel, see [`crate::watchlist`].
+//!
+//! Notifiers only render messages. Sending them is up to [`crate::outbox`], which keeps them on
+//! disk until they've been delivered.
+
+use crate::config::CONFIG;
+use crate::diff::ItemDiff;
+use crate::outbox::Message;
+use crate::scraper::{Region, RegionInfo, ShopItem, ShopItemId};
+use crate::slack_api::Call;
+use crate::watchlist::{self, Match, Subscription};
+use color_eyre::{Result, eyre::Context, eyre::eyre};
+use log::{error, info};
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+mod discord;
+mod json;
+mod slack;
+
+pub trait Notifier: Send + Sync {
+    /// The messages announcing `diff`, in the order they should be posted.
+    fn render(&self, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Payload>>;
+
+    /// Tells whoever runs the tracker that something needs fixing. Filters don't apply.
+    fn render_alert(&self, alert: &Alert) -> Result<Vec<Payload>>;
+}
+
+/// One message, ready to be sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Payload {
+    Post(Post),
+    SlackApi(Call),
+}
+
+impl Payload {
+    /// What's being sent, for logs. Leaves out URLs and tokens.
+    pub fn body(&self) -> String {
+        match self {
+            Self::Post(post) => post.body.clone(),
+            Self::SlackApi(call) => serde_json::to_string(&call.content).unwrap_or_default(),
+        }
+    }
+}
+
+/// An HTTP POST, rendered ahead of time so it can wait in the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Post {
+    pub url: Url,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Post {
+    pub fn json(url: &Url, payload: &impl Serialize) -> Result<Self> {
+        Ok(Self {
+            url: url.clone(),
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: serde_json::to_string(payload)?,
+        })
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Who a message about a diff should ping.
+#[derive(Debug, Clone)]
+pub enum Ping {
+    /// Nobody this sink can mention has subscribed to anything, so everyone hears about every
+    /// change.
+    Channel,
+    /// Only these subscribers. Nobody, if it's empty.
+    Subscribers(Vec<Match>),
+}
+
+impl Ping {
+    /// Whether a subscriber is waiting to hear about the item.
+    pub fn is_watched(&self, item_id: ShopItemId) -> bool {
+        match self {
+            Self::Channel => false,
+            Self::Subscribers(matches) => matches.iter().any(|m| m.item_id == item_id),
+        }
+    }
+}
+
+/// Problems with the tracker itself, rather than changes in the shop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// Flavortown logged out the named session, e.g. `COOKIES #1 (UK)`.
+    SessionExpired { session: String },
+}
+
+impl Alert {
+    pub const fn kind(&self) -> &'static str {
+        match self {
+            Self::SessionExpired { .. } => "session_expired",
+        }
+    }
+}
+
+impl std::fmt::Display for Alert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SessionExpired { session } => write!(
+                f,
+                "The Flavortown session in {session} has expired, so the tracker can't see the \
+                 shop through it. Its regions won't be updated unless another session can take \
+                 them over, until it is replaced."
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkType {
+    Slack,
+    Discord,
+    /// A versioned JSON document of the changes, see [`json`].
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    New,
+    Updated,
+    Deleted,
+    /// Regions being added to or removed from the shop.
+    Regions,
+}
+
+/// Narrows down which changes a sink hears about. Every list that isn't empty must match.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Filters {
+    pub changes: Vec<ChangeKind>,
+    /// Region codes (`US`, `UK`, ...). An item matches if it has a price in any of them.
+    pub regions: Vec<String>,
+    pub item_ids: Vec<ShopItemId>,
+    /// Case-insensitive substrings of the item title.
+    pub title_contains: Vec<String>,
+}
+
+impl Filters {
+    fn matches_item(&self, item: &ShopItem) -> bool {
+        (self.regions.is_empty()
+            || self
+                .regions
+                .iter()
+                .any(|code| Region::from_code(code).is_some_and(|r| item.prices.contains_key(&r))))
+            && (self.item_ids.is_empty() || self.item_ids.contains(&item.id))
+            && (self.title_contains.is_empty()
+                || self
+                    .title_contains
+                    .iter()
+                    .any(|needle| item.title.to_lowercase().contains(&needle.to_lowercase())))
+    }
+
+    fn wants(&self, kind: ChangeKind) -> bool {
+        self.changes.is_empty() || self.changes.contains(&kind)
+    }
+
+    /// Region changes aren't about any one item, so item filters leave them out.
+    fn matches_region(&self, region: &RegionInfo) -> bool {
+        self.wants(ChangeKind::Regions)
+            && self.item_ids.is_empty()
+            && self.title_contains.is_empty()
+            && (self.regions.is_empty()
+                || self
+                    .regions
+                    .iter()
+                    .any(|code| code.eq_ignore_ascii_case(&region.code)))
+    }
+
+    pub fn apply(&self, diff: &ItemDiff) -> ItemDiff {
+        let keep = |kind, items: &[ShopItem]| -> Vec<ShopItem> {
+            if !self.wants(kind) {
+                return Vec::new();
+            }
+            items
+                .iter()
+                .filter(|item| self.matches_item(item))
+                .cloned()
+                .collect()
+        };
+        let keep_regions = |regions: &[RegionInfo]| -> Vec<RegionInfo> {
+            regions
+                .iter()
+                .filter(|region| self.matches_region(region))
+                .cloned()
+                .collect()
+        };
+
+        ItemDiff {
+            new_items: keep(ChangeKind::New, &diff.new_items),
+            deleted_items: keep(ChangeKind::Deleted, &diff.deleted_items),
+            updated_items: if self.wants(ChangeKind::Updated) {
+                diff.updated_items
+                    .iter()
+                    .filter(|item| self.matches_item(&item.old) || self.matches_item(&item.new))
+                    .cloned()
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            new_regions: keep_regions(&diff.new_regions),
+            removed_regions: keep_regions(&diff.removed_regions),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SinkConfig {
+    /// Shows up in logs, and queued messages find their sink by it. Defaults to the sink's type
+    /// and its channel or a hash of its URL, so it doesn't change when sinks are reordered.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: SinkType,
+    /// Only Slack sinks with a `token` and `channel` go without.
+    #[serde(default)]
+    pub url: Option<Url>,
+    #[serde(default)]
+    pub filters: Filters,
+    /// Signs `json` sink bodies with HMAC-SHA256.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// A Slack bot token, to post to `channel` through the Web API instead of a webhook.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// A Slack channel ID.
+    #[serde(default)]
+    pub channel: Option<String>,
+}
+
+struct Sink {
+    name: String,
+    kind: SinkType,
+    filters: Filters,
+    /// Slack bot token, kept out of the rendered calls so it never ends up in the outbox.
+    token: Option<String>,
+    notifier: Box<dyn Notifier>,
+}
+
+impl Sink {
+    fn messages(&self, payloads: Vec<Payload>) -> impl Iterator<Item = Message> + '_ {
+        payloads.into_iter().map(|payload| Message {
+            sink: self.name.clone(),
+            kind: self.kind,
+            payload,
+        })
+    }
+}
+
+/// The sink's type plus something that identifies where it posts. For webhooks that's a hash of
+/// the URL, since the URL itself is a secret.
+fn default_name(config: &SinkConfig) -> Result<String> {
+    let kind = format!("{:?}", config.kind).to_lowercase();
+    match (&config.token, &config.channel, &config.url) {
+        (Some(_), Some(channel), _) => Ok(format!("{kind}-{channel}")),
+        (_, _, Some(url)) => {
+            let hash = hex::encode(Sha256::digest(url.as_str()));
+            Ok(format!("{kind}-{}", &hash[..8]))
+        }
+        _ => Err(eyre!("a {kind} sink needs a url")),
+    }
+}
+
+fn build_sink(config: &SinkConfig) -> Result<Sink> {
+    let name = match &config.name {
+        Some(name) => name.clone(),
+        None => default_name(config)?,
+    };
+    let url = || {
+        config
+            .url
+            .clone()
+            .ok_or_else(|| eyre!("{name} needs a url"))
+    };
+    let notifier: Box<dyn Notifier> = match (config.kind, &config.token, &config.channel) {
+        (SinkType::Slack, Some(_), Some(channel)) => Box::new(slack::SlackApiNotifier {
+            channel: channel.clone(),
+        }),
+        (SinkType::Slack, Some(_), None) => {
+            return Err(eyre!("{name} has a token, so it needs a channel too"));
+        }
+        (SinkType::Slack, None, _) => Box::new(slack::SlackNotifier {
+            webhook_url: url()?,
+        }),
+        (SinkType::Discord, ..) => Box::new(discord::DiscordNotifier {
+            webhook_url: url()?,
+        }),
+        (SinkType::Json, ..) => Box::new(json::JsonWebhookNotifier {
+            url: url()?,
+            secret: config.secret.clone(),
+        }),
+    };
+    Ok(Sink {
+        name,
+        kind: config.kind,
+        filters: config.filters.clone(),
+        token: config.token.clone(),
+        notifier,
+    })
+}
+
+static SINKS: Lazy<Vec<Sink>> = Lazy::new(|| {
+    let legacy = [
+        (SinkType::Slack, &CONFIG.webhook_url),
+        (SinkType::Discord, &CONFIG.discord_webhook_url),
+    ]
+    .into_iter()
+    .filter_map(|(kind, url)| {
+        url.as_ref().map(|url| SinkConfig {
+            name: None,
+            kind,
+            url: Some(url.clone()),
+            filters: Filters::default(),
+            secret: None,
+            token: None,
+            channel: None,
+        })
+    });
+
+    build_sinks(CONFIG.notifiers.iter().cloned().chain(legacy))
+        .wrap_err("invalid NOTIFIERS")
+        .unwrap()
+});
+
+fn build_sinks(configs: impl Iterator<Item = SinkConfig>) -> Result<Vec<Sink>> {
+    let sinks = configs
+        .map(|config| build_sink(&config))
+        .collect::<Result<Vec<_>>>()?;
+    for (i, sink) in sinks.iter().enumerate() {
+        if sinks[..i].iter().any(|other| other.name == sink.name) {
+            return Err(eyre!(
+                "more than one sink is called {} - give them different names",
+                sink.name
+            ));
+        }
+    }
+    Ok(sinks)
+}
+
+/// The bot token of the Slack sink named `sink`, as configured now.
+pub fn slack_token(sink: &str) -> Option<&'static str> {
+    SINKS
+        .iter()
+        .find(|s| s.name == sink)
+        .and_then(|s| s.token.as_deref())
+}
+
+/// Renders the diff for every configured sink, as filtered for that sink. A sink that fails to
+/// render is logged and left out, so the others still hear about the diff.
+pub fn render_all(diff: &ItemDiff) -> Result<Vec<Message>> {
+    if SINKS.is_empty() {
+        return Err(eyre!(
+            "no notifiers configured - set NOTIFIERS or WEBHOOK_URL"
+        ));
+    }
+    render_for(&SINKS, &watchlist::load()?, diff)
+}
+
+/// Fails only if there was something to render and no sink managed to.
+fn render_for(
+    sinks: &[Sink],
+    subscriptions: &[Subscription],
+    diff: &ItemDiff,
+) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    let mut rendered = 0;
+    let mut failed = Vec::new();
+    for sink in sinks {
+        let diff = sink.filters.apply(diff);
+        if diff.is_empty() {
+            info!("Nothing for {} after filtering", sink.name);
+            continue;
+        }
+        let subscribers: Vec<_> = subscriptions
+            .iter()
+            .filter(|s| s.notifies(sink.kind))
+            .cloned()
+            .collect();
+        let ping = if subscribers.is_empty() {
+            Ping::Channel
+        } else {
+            Ping::Subscribers(watchlist::matches(&subscribers, &diff))
+        };
+        match sink.notifier.render(&diff, &ping) {
+            Ok(payloads) => {
+                messages.extend(sink.messages(payloads));
+                rendered += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to render the changes for {}, skipping it: {e:?}",
+                    sink.name
+                );
+                failed.push(sink.name.as_str());
+            }
+        }
+    }
+    if rendered == 0 && !failed.is_empty() {
+        return Err(eyre!(
+            "no sink could render the changes ({})",
+            failed.join(", ")
+        ));
+    }
+    Ok(messages)
+}
+
+/// Renders an alert for every configured sink that can render it.
+pub fn render_alert(alert: &Alert) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    for sink in SINKS.iter() {
+        match sink.notifier.render_alert(alert) {
+            Ok(payloads) => messages.extend(sink.messages(payloads)),
+            Err(e) => error!(
+                "Failed to render an alert for {}, skipping it: {e:?}",
+                sink.name
+            ),
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use serde_json::json;
+
+    struct Broken;
+
+    impl Notifier for Broken {
+        fn render(&self, _diff: &ItemDiff, _ping: &Ping) -> Result<Vec<Payload>> {
+            Err(eyre!("broken"))
+        }
+
+        fn render_alert(&self, _alert: &Alert) -> Result<Vec<Payload>> {
+            Err(eyre!("broken"))
+        }
+    }
+
+    /// Posts who it was asked to ping.
+    struct ShowsPing;
+
+    impl Notifier for ShowsPing {
+        fn render(&self, _diff: &ItemDiff, ping: &Ping) -> Result<Vec<Payload>> {
+            let body = match ping {
+                Ping::Channel => "channel".to_string(),
+                Ping::Subscribers(matches) => format!("{} subscribers", matches.len()),
+            };
+            Ok(vec![Payload::Post(Post {
+                url: "https://example.com/hook".parse()?,
+                headers: Vec::new(),
+                body,
+            })])
+        }
+
+        fn render_alert(&self, _alert: &Alert) -> Result<Vec<Payload>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn sink(name: &str, notifier: Box<dyn Notifier>) -> Sink {
+        sink_of(SinkType::Json, name, notifier)
+    }
+
+    fn sink_of(kind: SinkType, name: &str, notifier: Box<dyn Notifier>) -> Sink {
+        Sink {
+            name: name.to_string(),
+            kind,
+            filters: Filters::default(),
+            token: None,
+            notifier,
+        }
+    }
+
+    fn json_sink(name: &str) -> Sink {
+        sink(
+            name,
+            Box::new(json::JsonWebhookNotifier {
+                url: "https://example.com/hook".parse().unwrap(),
+                secret: None,
+            }),
+        )
+    }
+
+    fn diff(new_items: Vec<ShopItem>) -> ItemDiff {
+        ItemDiff {
+            new_items,
+            updated_items: Vec::new(),
+            deleted_items: Vec::new(),
+            new_regions: Vec::new(),
+            removed_regions: Vec::new(),
+        }
+    }
+
+    fn sticker() -> ItemDiff {
+        diff(vec![testing::item(1, "Sticker", &[("US", 100)])])
+    }
+
+    fn names(notifiers: serde_json::Value) -> Result<Vec<String>> {
+        let configs: Vec<SinkConfig> = serde_json::from_value(notifiers).unwrap();
+        Ok(build_sinks(configs.into_iter())?
+            .into_iter()
+            .map(|sink| sink.name)
+            .collect())
+    }
+
+    #[test]
+    fn default_names_do_not_depend_on_the_order() {
+        let slack = json!({"type": "slack", "url": "https://hooks.slack.com/services/T0/B0/x"});
+        let bot = json!({"type": "slack", "token": "xoxb-1", "channel": "C0123"});
+        let named = json!({"type": "json", "name": "archive", "url": "https://example.com/hook"});
+        let [webhook, bot_name, archive] = &names(json!([slack, bot, named])).unwrap()[..] else {
+            panic!("expected three sinks");
+        };
+        assert_eq!(bot_name, "slack-C0123");
+        assert_eq!(archive, "archive");
+        assert!(webhook.starts_with("slack-") && webhook.len() == "slack-".len() + 8);
+
+        let reordered = names(json!([named, bot, slack])).unwrap();
+        assert_eq!(
+            reordered,
+            [archive.clone(), bot_name.clone(), webhook.clone()]
+        );
+    }
+
+    #[test]
+    fn sinks_need_different_names() {
+        let sink = json!({"type": "discord", "url": "https://discord.com/api/webhooks/1/x"});
+        assert!(names(json!([sink, sink])).is_err());
+        let mut renamed = sink.clone();
+        renamed["name"] = json!("discord-uk");
+        assert!(names(json!([sink, renamed])).is_ok());
+    }
+
+    #[test]
+    fn a_broken_sink_does_not_stop_the_others() {
+        let sinks = [
+            json_sink("first"),
+            sink("broken", Box::new(Broken)),
+            json_sink("last"),
+        ];
+        let messages = render_for(&sinks, &[], &sticker()).unwrap();
+        let names: Vec<_> = messages.iter().map(|m| m.sink.as_str()).collect();
+        assert_eq!(names, ["first", "last"]);
+    }
+
+    #[test]
+    fn fails_when_no_sink_renders() {
+        let sinks = [sink("broken", Box::new(Broken))];
+        assert!(render_for(&sinks, &[], &sticker()).is_err());
+        // nothing to render isn't a failure.
+        assert!(
+            render_for(&sinks, &[], &diff(Vec::new()))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn only_sinks_with_subscribers_stop_pinging_the_channel() {
+        let sinks = [
+            sink_of(SinkType::Slack, "slack", Box::new(ShowsPing)),
+            sink_of(SinkType::Discord, "discord", Box::new(ShowsPing)),
+        ];
+        let subscription: Subscription = serde_json::from_value(json!({
+            "id": 1,
+            "slack_user": "U012AB3CD",
+            "region": "US",
+            "condition": {"type": "price_below", "price": 200},
+        }))
+        .unwrap();
+
+        let messages = render_for(&sinks, &[subscription], &sticker()).unwrap();
+        let pings: Vec<_> = messages
+            .iter()
+            .map(|m| (m.sink.as_str(), m.payload.body()))
+            .collect();
+        assert_eq!(
+            pings,
+            [
+                ("slack", "1 subscribers".to_string()),
+                ("discord", "channel".to_string())
+            ]
+        );
+    }
+}
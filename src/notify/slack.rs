@@ -0,0 +1,724 @@
+use std::collections::HashMap;
+
+use super::{Alert, Notifier, Payload, Ping, Post};
+use crate::config::CONFIG;
+use crate::diff::{FieldChange, ItemDiff, UpdatedItem, compute_changes};
+use crate::scraper::{Accessory, Region, RegionInfo, ShopItem};
+use crate::slack_api::{self, Action, Call, ItemRecord};
+use color_eyre::Result;
+use log::info;
+use reqwest::Url;
+use slack_morphism::prelude::*;
+use time_format::TimeStamp;
+
+const EMOJI_COOKIES: &str = ":cookie:";
+const EMOJI_TROLLEY: &str = ":tw_shopping_trolley:";
+const EMOJI_NEW: &str = ":new:";
+const EMOJI_TRASH: &str = ":win10-trash:";
+const EMOJI_STAR: &str = ":star:";
+const EMOJI_ROBOT: &str = ":robot_face:";
+const EMOJI_MEDAL: &str = ":tw_medal:";
+const EMOJI_WARNING: &str = ":warning:";
+
+fn escape_markdown(text: &str) -> String {
+    text.chars()
+        .flat_map(|c| match c {
+            '_' | '*' | '~' | '`' => vec!['\\', c],
+            _ => vec![c],
+        })
+        .collect()
+}
+
+fn format_prices_with_flags(prices: &HashMap<Region, u32>) -> String {
+    let price_entries: Vec<_> = prices.iter().collect();
+
+    match price_entries.as_slice() {
+        [(region, price)] => format!("{} {price}", region.flag()),
+        entries
+            if entries.len() == Region::all().len()
+                && entries.iter().all(|(_, p)| **p == *entries[0].1) =>
+        {
+            format!(":earth_americas: {}", entries[0].1)
+        }
+        entries => entries
+            .iter()
+            .map(|(r, p)| format!("{} {p}", r.flag()))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn item_header(emoji: &str, title: &str) -> String {
+    format!("{emoji} {title}")
+}
+
+fn format_price_line(prices: &HashMap<Region, u32>) -> String {
+    format!(
+        "*Price:* {EMOJI_COOKIES} {}",
+        format_prices_with_flags(prices)
+    )
+}
+
+fn item_description(desc: &str) -> String {
+    if desc.is_empty() {
+        String::new()
+    } else {
+        format!("_{}_\n", escape_markdown(desc))
+    }
+}
+
+fn buy_button(url: &impl ToString) -> String {
+    format!("<{}|*{EMOJI_TROLLEY} Buy*>", url.to_string())
+}
+
+fn format_accessories(accessories: &[Accessory]) -> String {
+    if accessories.is_empty() {
+        "_none_".to_string()
+    } else {
+        accessories
+            .iter()
+            .map(|a| {
+                format!(
+                    "{} ({EMOJI_COOKIES} {})",
+                    escape_markdown(&a.name),
+                    format_prices_with_flags(&a.prices)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn format_stock(stock: Option<u32>) -> String {
+    match stock {
+        Some(0) => "Out of stock".to_string(),
+        Some(n) => format!("{n} left"),
+        None => "Unlimited".to_string(),
+    }
+}
+
+fn format_achievement_lock(achievement_lock: Option<String>) -> String {
+    match achievement_lock {
+        Some(s) if s == "Cooking" => "_Cooking (Black Market)_".to_string(),
+        Some(s) if !s.is_empty() => escape_markdown(s.as_str()),
+        _ => "_none_".to_string(),
+    }
+}
+
+fn format_long_description(desc: Option<&String>) -> String {
+    match desc {
+        Some(s) if !s.is_empty() => format!("_{}_", escape_markdown(s)),
+        _ => "_none_".to_string(),
+    }
+}
+
+fn render_new_item(item: &ShopItem) -> Vec<SlackBlock> {
+    let section_text = format!(
+        "{}{}\n*Stock:* {}\n\n{}",
+        item_description(&item.description),
+        format_price_line(&item.prices),
+        format_stock(item.remaining_stock),
+        buy_button(&item.buy_link())
+    );
+
+    vec![
+        SlackHeaderBlock::new(pt!(item_header(EMOJI_NEW, &item.title))).into(),
+        SlackSectionBlock::new().with_text(md!(section_text)).into(),
+        SlackImageBlock::new(
+            item.image_url.clone().into(),
+            format!("Image for {}", item.title),
+        )
+        .into(),
+    ]
+}
+
+fn render_deleted_item(item: &ShopItem) -> Vec<SlackBlock> {
+    let section_text = format!(
+        "{}{}\n",
+        item_description(&item.description),
+        format_price_line(&item.prices)
+    );
+
+    vec![
+        SlackHeaderBlock::new(pt!(item_header(EMOJI_TRASH, &item.title))).into(),
+        SlackSectionBlock::new().with_text(md!(section_text)).into(),
+        SlackImageBlock::new(
+            item.image_url.clone().into(),
+            format!("Image for {}", item.title),
+        )
+        .into(),
+    ]
+}
+
+fn render_updated_item(item: &UpdatedItem) -> Vec<SlackBlock> {
+    let (old, new) = (&item.old, &item.new);
+
+    let title = match item.find(|c| matches!(c, FieldChange::Title { .. })) {
+        Some(FieldChange::Title { old, new }) => format!("{old} → {new}"),
+        _ => new.title.clone(),
+    };
+
+    let price_line = if item.has(FieldChange::is_price) {
+        format!(
+            "*Price:* {EMOJI_COOKIES} {} → {}",
+            format_prices_with_flags(&old.prices),
+            format_prices_with_flags(&new.prices)
+        )
+    } else {
+        format_price_line(&new.prices)
+    };
+
+    let description = match item.find(|c| matches!(c, FieldChange::Description { .. })) {
+        Some(FieldChange::Description { old, new }) => {
+            let old_desc = if old.is_empty() {
+                "_no description_"
+            } else {
+                &escape_markdown(old)
+            };
+            let new_desc = if new.is_empty() {
+                "_no description_"
+            } else {
+                &escape_markdown(new)
+            };
+            format!("{old_desc} → {new_desc}\n")
+        }
+        _ => item_description(&new.description),
+    };
+
+    let long_desc_line = match item.find(|c| matches!(c, FieldChange::LongDescription { .. })) {
+        Some(FieldChange::LongDescription {
+            summary: Some(s), ..
+        }) => format!("*Long Description:* {}\n", escape_markdown(s)),
+        Some(FieldChange::LongDescription { old, new, .. }) => format!(
+            "*Long Description:* {} → {}\n",
+            format_long_description(old.as_ref()),
+            format_long_description(new.as_ref())
+        ),
+        _ => String::new(),
+    };
+
+    let accessories_line = if item.has(FieldChange::is_accessory) {
+        format!(
+            "*Accessories:* {} → {}\n",
+            format_accessories(&old.accessories),
+            format_accessories(&new.accessories)
+        )
+    } else {
+        String::new()
+    };
+
+    let stock_line = match item.find(|c| matches!(c, FieldChange::Stock { .. })) {
+        Some(FieldChange::Stock { old, new }) => {
+            format!("*Stock:* {} → {}\n", format_stock(*old), format_stock(*new))
+        }
+        _ => format!("*Stock:* {}\n", format_stock(new.remaining_stock)),
+    };
+
+    let achievement_line = match item.find(|c| matches!(c, FieldChange::AchievementLock { .. })) {
+        Some(FieldChange::AchievementLock { old, new }) => format!(
+            "{EMOJI_MEDAL} *Requires achievement:* {} → {}\n",
+            format_achievement_lock(old.clone()),
+            format_achievement_lock(new.clone())
+        ),
+        _ => format!(
+            "{EMOJI_MEDAL} *Requires achievement:* {}\n",
+            format_achievement_lock(new.achievement_lock.clone())
+        ),
+    };
+
+    let section_text = format!(
+        "{description}{price_line}\n{long_desc_line}{accessories_line}{stock_line}{achievement_line}\n{}",
+        buy_button(&new.buy_link())
+    );
+
+    let mut blocks = vec![
+        SlackHeaderBlock::new(pt!(title)).into(),
+        SlackSectionBlock::new().with_text(md!(section_text)).into(),
+    ];
+
+    if let Some(FieldChange::Image { old: old_image, .. }) =
+        item.find(|c| matches!(c, FieldChange::Image { .. }))
+    {
+        blocks.push(
+            SlackImageBlock::new(
+                old_image.clone().into(),
+                format!("Old image for {}", new.title),
+            )
+            .into(),
+        );
+    }
+
+    blocks.push(
+        SlackImageBlock::new(
+            new.image_url.clone().into(),
+            format!("New image for {}", new.title),
+        )
+        .into(),
+    );
+    blocks
+}
+
+/// Replaces an item's message when a later run finds it changed back.
+fn render_reverted_item(item: &ShopItem) -> Vec<SlackBlock> {
+    vec![
+        SlackSectionBlock::new()
+            .with_text(md!(format!(
+                "*{}* changed back, so nothing is different any more.",
+                escape_markdown(&item.title)
+            )))
+            .into(),
+    ]
+}
+
+fn format_region(region: &RegionInfo) -> String {
+    format!(
+        "{} {} ({})",
+        region.region().flag(),
+        escape_markdown(&region.name),
+        region.code
+    )
+}
+
+fn render_region_changes(diff: &ItemDiff) -> Vec<SlackBlock> {
+    let lines: Vec<_> = diff
+        .new_regions
+        .iter()
+        .map(|r| format!("{EMOJI_NEW} *New region:* {}", format_region(r)))
+        .chain(
+            diff.removed_regions
+                .iter()
+                .map(|r| format!("{EMOJI_TRASH} *Region removed:* {}", format_region(r))),
+        )
+        .collect();
+
+    vec![
+        SlackHeaderBlock::new(pt!("Regions".to_string())).into(),
+        SlackSectionBlock::new()
+            .with_text(md!(lines.join("\n")))
+            .into(),
+    ]
+}
+
+/// Pings the channel, or the subscribers the changes are for, then links to the project.
+fn render_ping(ping: &Ping) -> Vec<SlackBlock> {
+    let links = format!(
+        "<https://github.com/skyfallwastaken/flavortown-tracker|{EMOJI_STAR} star the repo!> · <https://hackclub.slack.com/archives/C091UF79VDM|{EMOJI_ROBOT} discord/slackbot ysws>"
+    );
+    let text = match ping {
+        Ping::Channel => format!("pinging <!channel> · {links}"),
+        Ping::Subscribers(matches) => matches
+            .iter()
+            .filter_map(|m| {
+                let user = m.slack_user.as_ref()?;
+                Some(format!("<@{user}> {}\n", escape_markdown(&m.reason)))
+            })
+            .chain([links])
+            .collect(),
+    };
+    vec![SlackContextBlock::new(vec![SlackContextBlockElement::MarkDown(md!(text))]).into()]
+}
+
+const MAX_BLOCKS_PER_MESSAGE: usize = 50;
+
+pub struct SlackNotifier {
+    pub webhook_url: Url,
+}
+
+impl Notifier for SlackNotifier {
+    fn render(&self, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Payload>> {
+        Ok(render_notifications(&self.webhook_url, diff, ping)?
+            .into_iter()
+            .map(Payload::Post)
+            .collect())
+    }
+
+    fn render_alert(&self, alert: &Alert) -> Result<Vec<Payload>> {
+        let text = format!("{EMOJI_WARNING} {}", escape_markdown(&alert.to_string()));
+        Ok(vec![Payload::Post(post_blocks(
+            &self.webhook_url,
+            vec![SlackSectionBlock::new().with_text(md!(text)).into()],
+            &alert.to_string(),
+        )?)])
+    }
+}
+
+fn post_blocks(webhook_url: &Url, blocks: Vec<SlackBlock>, fallback_text: &str) -> Result<Post> {
+    let payload = SlackMessageContent::new()
+        .with_text(fallback_text.to_string())
+        .with_blocks(blocks);
+    Post::json(webhook_url, &payload)
+}
+
+fn diff_summary(diff: &ItemDiff) -> String {
+    format!(
+        "Shop update: {} new, {} updated, {} removed",
+        diff.new_items.len(),
+        diff.updated_items.len(),
+        diff.deleted_items.len()
+    )
+}
+
+fn render_notifications(webhook_url: &Url, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Post>> {
+    let mut item_block_groups: Vec<Vec<SlackBlock>> = Vec::new();
+
+    if !diff.new_regions.is_empty() || !diff.removed_regions.is_empty() {
+        info!(
+            "Rendering notification for {} new and {} removed regions",
+            diff.new_regions.len(),
+            diff.removed_regions.len()
+        );
+        item_block_groups.push(render_region_changes(diff));
+    }
+
+    for item in &diff.new_items {
+        info!("Rendering notification for new item: {}", item.title);
+        item_block_groups.push(render_new_item(item));
+    }
+
+    for item in &diff.updated_items {
+        info!(
+            "Rendering notification for updated item: {}",
+            item.new.title
+        );
+        item_block_groups.push(render_updated_item(item));
+    }
+
+    for item in &diff.deleted_items {
+        info!("Rendering notification for deleted item: {}", item.title);
+        item_block_groups.push(render_deleted_item(item));
+    }
+
+    let fallback_text = diff_summary(diff);
+
+    let mut posts = Vec::new();
+    let mut current_blocks: Vec<SlackBlock> = Vec::new();
+    let group_count = item_block_groups.len();
+
+    for (i, group) in item_block_groups.into_iter().enumerate() {
+        let group_size = group.len() + 1; // +1 for divider
+
+        if !current_blocks.is_empty()
+            && current_blocks.len() + group_size > MAX_BLOCKS_PER_MESSAGE - 1
+        {
+            posts.push(post_blocks(webhook_url, current_blocks, &fallback_text)?);
+            current_blocks = Vec::new();
+        }
+
+        current_blocks.extend(group);
+        if i < group_count - 1 {
+            current_blocks.push(SlackDividerBlock::new().into());
+        }
+    }
+
+    current_blocks.extend(render_ping(ping));
+    posts.push(post_blocks(webhook_url, current_blocks, &fallback_text)?);
+    Ok(posts)
+}
+
+/// Posts with a bot token through the Web API: a summary of each diff that does the pinging, with
+/// every change as a reply in its thread. When an item changes back, or the same field changes
+/// again, within `SLACK_EDIT_WINDOW_SECS` of being announced, the earlier reply is edited
+/// instead. Changes someone on the watchlist is waiting for always get a new reply.
+pub struct SlackApiNotifier {
+    pub channel: String,
+}
+
+impl SlackApiNotifier {
+    fn call(&self, action: Action, blocks: Vec<SlackBlock>, fallback_text: &str) -> Payload {
+        Payload::SlackApi(Call {
+            channel: self.channel.clone(),
+            action,
+            content: SlackMessageContent::new()
+                .with_text(fallback_text.to_string())
+                .with_blocks(blocks),
+        })
+    }
+
+    fn post(
+        &self,
+        thread: Option<&str>,
+        record: Option<ItemRecord>,
+        blocks: Vec<SlackBlock>,
+        text: &str,
+    ) -> Result<Payload> {
+        let action = Action::Post {
+            key: slack_api::new_key()?,
+            thread: thread.map(str::to_string),
+            record,
+        };
+        Ok(self.call(action, blocks, text))
+    }
+
+    /// An edit of the item's last reply, if this change corrects it: it was posted recently, and
+    /// the item either changed back or changed again in the fields that reply was about.
+    fn correction(&self, item: &UpdatedItem, now: TimeStamp) -> Result<Option<Payload>> {
+        let Some(announcement) = slack_api::announcement(&self.channel, item.new.id)? else {
+            return Ok(None);
+        };
+        if now - announcement.at >= CONFIG.slack_edit_window_secs {
+            return Ok(None);
+        }
+
+        let changes = compute_changes(&announcement.before, &item.new);
+        let same_fields = item.changes.iter().all(|change| {
+            announcement
+                .changes
+                .iter()
+                .any(|announced| announced.same_field(change))
+        });
+        let blocks = if changes.is_empty() {
+            render_reverted_item(&item.new)
+        } else if same_fields {
+            render_updated_item(&UpdatedItem {
+                old: announcement.before,
+                new: item.new.clone(),
+                changes,
+            })
+        } else {
+            return Ok(None);
+        };
+        info!("Editing the earlier message about {}", item.new.title);
+        let action = Action::Update {
+            key: announcement.key,
+        };
+        Ok(Some(self.call(action, blocks, &item.new.title)))
+    }
+}
+
+impl Notifier for SlackApiNotifier {
+    fn render(&self, diff: &ItemDiff, ping: &Ping) -> Result<Vec<Payload>> {
+        let now = time_format::now().unwrap();
+        let mut payloads = Vec::new();
+
+        let mut updated_items = Vec::new();
+        for item in &diff.updated_items {
+            let edit = if ping.is_watched(item.new.id) {
+                None
+            } else {
+                self.correction(item, now)?
+            };
+            match edit {
+                Some(edit) => payloads.push(edit),
+                None => updated_items.push(item.clone()),
+            }
+        }
+        let diff = ItemDiff {
+            updated_items,
+            ..diff.clone()
+        };
+        // only corrections, which don't need a new thread or ping.
+        if diff.is_empty() {
+            return Ok(payloads);
+        }
+
+        let summary = diff_summary(&diff);
+        let mut blocks: Vec<SlackBlock> = vec![
+            SlackSectionBlock::new()
+                .with_text(md!(format!("*{summary}*, details in the thread")))
+                .into(),
+        ];
+        blocks.extend(render_ping(ping));
+        let thread = slack_api::new_key()?;
+        payloads.push(self.call(
+            Action::Post {
+                key: thread.clone(),
+                thread: None,
+                record: None,
+            },
+            blocks,
+            &summary,
+        ));
+
+        if !diff.new_regions.is_empty() || !diff.removed_regions.is_empty() {
+            payloads.push(self.post(
+                Some(&thread),
+                None,
+                render_region_changes(&diff),
+                &summary,
+            )?);
+        }
+        for item in &diff.new_items {
+            payloads.push(self.post(Some(&thread), None, render_new_item(item), &item.title)?);
+        }
+        for item in &diff.updated_items {
+            let record = ItemRecord::Announce {
+                item_id: item.new.id,
+                at: now,
+                before: Box::new(item.old.clone()),
+                changes: item.changes.clone(),
+            };
+            payloads.push(self.post(
+                Some(&thread),
+                Some(record),
+                render_updated_item(item),
+                &item.new.title,
+            )?);
+        }
+        for item in &diff.deleted_items {
+            let record = ItemRecord::Forget { item_id: item.id };
+            payloads.push(self.post(
+                Some(&thread),
+                Some(record),
+                render_deleted_item(item),
+                &item.title,
+            )?);
+        }
+        Ok(payloads)
+    }
+
+    fn render_alert(&self, alert: &Alert) -> Result<Vec<Payload>> {
+        let text = format!("{EMOJI_WARNING} {}", escape_markdown(&alert.to_string()));
+        Ok(vec![self.post(
+            None,
+            None,
+            vec![SlackSectionBlock::new().with_text(md!(text)).into()],
+            &alert.to_string(),
+        )?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::SinkType;
+    use crate::outbox::{self, Message};
+    use crate::testing::{self, SLACK_SINK, SLACK_STUB};
+    use crate::watchlist::Match;
+
+    fn updated(old: &ShopItem, new: &ShopItem) -> ItemDiff {
+        ItemDiff {
+            new_items: Vec::new(),
+            deleted_items: Vec::new(),
+            updated_items: vec![UpdatedItem {
+                old: old.clone(),
+                new: new.clone(),
+                changes: compute_changes(old, new),
+            }],
+            new_regions: Vec::new(),
+            removed_regions: Vec::new(),
+        }
+    }
+
+    async fn send_all(payloads: Vec<Payload>) {
+        for payload in payloads {
+            let Payload::SlackApi(call) = payload else {
+                panic!("expected a Web API call");
+            };
+            let sent = slack_api::send(SLACK_SINK, &call).await.unwrap();
+            assert!(matches!(sent, crate::outbox::Sent::Delivered));
+        }
+    }
+
+    #[tokio::test]
+    async fn threads_replies_and_edits_corrections() {
+        testing::init();
+        let notifier = SlackApiNotifier {
+            channel: "C0THREAD".to_string(),
+        };
+        let v1 = testing::item(1, "Sticker", &[("US", 100)]);
+        let v2 = testing::item(1, "Sticker", &[("US", 80)]);
+        let v3 = testing::item(1, "Sticker", &[("US", 90)]);
+
+        send_all(notifier.render(&updated(&v1, &v2), &Ping::Channel).unwrap()).await;
+        let calls = SLACK_STUB.calls("C0THREAD");
+        let [parent, reply] = calls.as_slice() else {
+            panic!("expected a parent and a reply, got {calls:?}");
+        };
+        assert_eq!(parent.method, "chat.postMessage");
+        assert!(parent.body.get("thread_ts").is_none());
+        assert!(parent.body.to_string().contains("<!channel>"));
+        assert_eq!(reply.method, "chat.postMessage");
+        assert_eq!(reply.body["thread_ts"].as_str(), parent.ts.as_deref());
+
+        // the same price changing again within the window corrects the reply.
+        let payloads = notifier.render(&updated(&v2, &v3), &Ping::Channel).unwrap();
+        assert_eq!(payloads.len(), 1);
+        send_all(payloads).await;
+        let calls = SLACK_STUB.calls("C0THREAD");
+        let edit = calls.last().unwrap();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(edit.method, "chat.update");
+        assert_eq!(edit.body["ts"].as_str(), reply.ts.as_deref());
+        assert!(edit.body.to_string().contains("100"));
+    }
+
+    #[tokio::test]
+    async fn new_events_get_new_replies() {
+        testing::init();
+        let notifier = SlackApiNotifier {
+            channel: "C0EVENTS".to_string(),
+        };
+        let v1 = testing::item(2, "Mug", &[("US", 100)]);
+        let v2 = testing::item(2, "Mug", &[("US", 80)]);
+        send_all(notifier.render(&updated(&v1, &v2), &Ping::Channel).unwrap()).await;
+
+        // a restock isn't a correction of a price change.
+        let v3 = ShopItem {
+            remaining_stock: Some(5),
+            ..v2.clone()
+        };
+        let payloads = notifier.render(&updated(&v2, &v3), &Ping::Channel).unwrap();
+        assert_eq!(payloads.len(), 2);
+        send_all(payloads).await;
+
+        // changes someone is waiting for get a new reply, even if they're to the same field.
+        let v4 = ShopItem {
+            prices: v1.prices.clone(),
+            ..v3.clone()
+        };
+        let ping = Ping::Subscribers(vec![Match {
+            item_id: 2,
+            slack_user: Some("U0WATCHER".to_string()),
+            discord_user: None,
+            reason: "Mug costs 100 cookies in US".to_string(),
+        }]);
+        let payloads = notifier.render(&updated(&v3, &v4), &ping).unwrap();
+        assert_eq!(payloads.len(), 2);
+        send_all(payloads).await;
+
+        let calls = SLACK_STUB.calls("C0EVENTS");
+        assert!(calls.iter().all(|call| call.method == "chat.postMessage"));
+        assert!(calls[4].body.to_string().contains("<@U0WATCHER>"));
+    }
+
+    #[tokio::test]
+    async fn corrects_announcements_still_in_the_outbox() {
+        testing::init();
+        let _outbox = testing::OUTBOX.lock().await;
+        let notifier = SlackApiNotifier {
+            channel: "C0QUEUED".to_string(),
+        };
+        let v1 = testing::item(3, "Pin", &[("US", 100)]);
+        let v2 = testing::item(3, "Pin", &[("US", 80)]);
+        let v3 = testing::item(3, "Pin", &[("US", 90)]);
+
+        // Slack was down, so the first announcement is still waiting.
+        let payloads = notifier.render(&updated(&v1, &v2), &Ping::Channel).unwrap();
+        let Some(Payload::SlackApi(Call {
+            action: Action::Post { key: reply, .. },
+            ..
+        })) = payloads.last().cloned()
+        else {
+            panic!("expected a reply");
+        };
+        let messages = payloads.into_iter().map(|payload| Message {
+            sink: SLACK_SINK.to_string(),
+            kind: SinkType::Slack,
+            payload,
+        });
+        outbox::enqueue(messages.collect(), None).unwrap();
+
+        let payloads = notifier.render(&updated(&v2, &v3), &Ping::Channel).unwrap();
+        let [
+            Payload::SlackApi(Call {
+                action: Action::Update { key },
+                ..
+            }),
+        ] = payloads.as_slice()
+        else {
+            panic!("expected an edit, got {payloads:?}");
+        };
+        assert_eq!(key, &reply);
+        outbox::deliver().await.unwrap();
+    }
+}
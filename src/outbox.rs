@@ -0,0 +1,468 @@
+//! Messages waiting to be sent, kept on disk so a failed or interrupted run picks up where it
+//! left off instead of losing or repeating messages.
+//!
+//! A run with changes [`enqueue`]s every rendered message together with the snapshot they're
+//! about, in one transaction. The snapshot is then written by [`commit_snapshot`] and the
+//! messages sent by [`deliver`], each one removed as soon as it has been accepted. Anything left
+//! over is sent on the next run, before anything new.
+//!
+//! A message is marked as sending before its request goes out. Finding one still marked like that
+//! means the tracker died mid-request, so there's no telling whether it arrived - it's sent again.
+//! Plain webhooks (Slack, Discord and JSON) are therefore delivered at least once, and a crash at
+//! the wrong moment posts a message twice. JSON sinks get an `X-Flavortown-Delivery` ID that
+//! doesn't change between tries, so receivers can drop the duplicate; Slack and Discord webhooks
+//! have no way to.
+//!
+//! Messages for Slack sinks with a bot token are Web API calls instead of plain POSTs, and are
+//! sent by [`slack_api`]. It remembers what it has posted, so those are only sent again if the
+//! crash came while Slack was answering.
+
+use std::convert::Infallible;
+
+use crate::config::CONFIG;
+use crate::diff::ItemDiff;
+use crate::feed;
+use crate::fetch::FETCHER;
+use crate::notify::{Payload, SinkType};
+use crate::retry::{self, RetryPolicy};
+use crate::scraper::CLIENT;
+use crate::slack_api;
+use crate::storage::Snapshot;
+use crate::store::STORE;
+use color_eyre::{Result, eyre::eyre};
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Config, Db, IVec, Tree};
+use time_format::TimeStamp;
+
+const OUTBOX_DB_PATH: &str = "outbox.sled";
+const PENDING_SNAPSHOT_KEY: &[u8] = b"pending";
+
+static OUTBOX_DB: Lazy<Db> = Lazy::new(|| {
+    Config::new()
+        .path(CONFIG.storage_path.join(OUTBOX_DB_PATH))
+        .open()
+        .unwrap()
+});
+
+/// `id` (big endian, so iteration is oldest first) -> [`Entry`]
+fn messages() -> Result<Tree> {
+    Ok(OUTBOX_DB.open_tree("messages")?)
+}
+
+/// `"pending"` -> the [`PendingSnapshot`] whose messages were queued last, until it's written
+fn pending_snapshot() -> Result<Tree> {
+    Ok(OUTBOX_DB.open_tree("pending_snapshot")?)
+}
+
+/// session name -> `""`, for sessions whose "session expired" alert has been queued
+fn expired_sessions() -> Result<Tree> {
+    Ok(OUTBOX_DB.open_tree("expired_sessions")?)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    /// The sink's name, for logs and keeping its messages in order.
+    pub sink: String,
+    pub kind: SinkType,
+    /// Flattened, so posts queued by older versions still read as [`Payload::Post`].
+    #[serde(flatten)]
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Pending,
+    Sending,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    status: Status,
+    message: Message,
+}
+
+/// A snapshot whose messages have been queued, but which might not have been written yet.
+#[derive(Serialize, Deserialize)]
+pub struct PendingSnapshot {
+    pub at: TimeStamp,
+    #[serde(flatten)]
+    pub snapshot: Snapshot,
+    pub diff: ItemDiff,
+}
+
+/// Queues `messages`, and the snapshot they announce if there is one, in one go. Until the
+/// snapshot is written the queued one takes precedence, so the same changes can't be queued
+/// twice.
+pub fn enqueue(messages_to_send: Vec<Message>, snapshot: Option<PendingSnapshot>) -> Result<()> {
+    let snapshot = snapshot.map(|s| serde_json::to_vec(&s)).transpose()?;
+    queue(messages_to_send, snapshot.as_deref(), None)
+}
+
+/// Queues the "session expired" alert for `session`, unless it already has been since the
+/// session was last seen logged in. The alert and the note that it was sent are written together,
+/// so it can't be queued twice.
+pub fn enqueue_session_expired(session: &str, messages_to_send: Vec<Message>) -> Result<()> {
+    if expired_sessions()?.contains_key(session)? {
+        return Ok(());
+    }
+    queue(messages_to_send, None, Some(session))
+}
+
+/// Forgets that sessions other than `expired` were alerted about, so they're alerted about again
+/// if they're logged out again.
+pub fn clear_expired_sessions(expired: &[String]) -> Result<()> {
+    let tree = expired_sessions()?;
+    for key in tree.iter().keys() {
+        let key = key?;
+        if !expired.iter().any(|session| session.as_bytes() == &*key) {
+            tree.remove(key)?;
+        }
+    }
+    tree.flush()?;
+    Ok(())
+}
+
+fn queue(
+    messages_to_send: Vec<Message>,
+    snapshot: Option<&[u8]>,
+    expired_session: Option<&str>,
+) -> Result<()> {
+    let entries = messages_to_send
+        .into_iter()
+        .map(|message| {
+            let id = OUTBOX_DB.generate_id()?;
+            let entry = Entry {
+                status: Status::Pending,
+                message,
+            };
+            Ok((id.to_be_bytes(), serde_json::to_vec(&entry)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    (&messages()?, &pending_snapshot()?, &expired_sessions()?)
+        .transaction(|(messages, pending, expired)| {
+            for (id, entry) in &entries {
+                messages.insert(id, entry.as_slice())?;
+            }
+            if let Some(snapshot) = snapshot {
+                pending.insert(PENDING_SNAPSHOT_KEY, snapshot)?;
+            }
+            if let Some(session) = expired_session {
+                expired.insert(session, "")?;
+            }
+            Ok::<_, ConflictableTransactionError<Infallible>>(())
+        })
+        .map_err(|e: TransactionError<Infallible>| eyre!("failed to queue messages: {e}"))?;
+    OUTBOX_DB.flush()?;
+    Ok(())
+}
+
+/// Writes the queued snapshot and its diff, if there is one. Returns the snapshot, since it's the
+/// latest one now.
+pub fn commit_snapshot() -> Result<Option<Snapshot>> {
+    let pending = pending_snapshot()?;
+    let Some(bytes) = pending.get(PENDING_SNAPSHOT_KEY)? else {
+        return Ok(None);
+    };
+    let snapshot: PendingSnapshot = serde_json::from_slice(&bytes)?;
+
+    STORE.write_diff(snapshot.at, &snapshot.diff)?;
+    STORE.write(snapshot.at, &snapshot.snapshot)?;
+    pending.remove(PENDING_SNAPSHOT_KEY)?;
+    OUTBOX_DB.flush()?;
+    // the feeds can be written again from the diffs at any time, so they don't fail the run.
+    if let Err(e) = feed::write() {
+        warn!("Failed to write the feeds: {e:?}");
+    }
+    Ok(Some(snapshot.snapshot))
+}
+
+/// Every message still waiting to be sent, oldest first.
+pub fn queued() -> Result<Vec<Message>> {
+    messages()?
+        .iter()
+        .map(|row| Ok(serde_json::from_slice::<Entry>(&row?.1)?.message))
+        .collect()
+}
+
+const fn retry_policy(kind: SinkType) -> &'static RetryPolicy {
+    match kind {
+        SinkType::Slack => &RetryPolicy::SLACK,
+        SinkType::Discord => &RetryPolicy::DISCORD,
+        SinkType::Json => &RetryPolicy::WEBHOOK,
+    }
+}
+
+pub enum Sent {
+    Delivered,
+    /// The sink refused the message outright, so sending it again won't help.
+    Rejected(String),
+}
+
+async fn send(id: IVec, message: Message) -> Result<Sent> {
+    let Message {
+        sink,
+        kind,
+        payload,
+    } = &message;
+    debug!("Sending to {sink}: {}", payload.body());
+
+    if FETCHER.is_offline() {
+        info!("Replaying - not sending to {sink}: {}", payload.body());
+        return Ok(Sent::Delivered);
+    }
+
+    let post = match payload {
+        Payload::Post(post) => post,
+        Payload::SlackApi(call) => return slack_api::send(sink, call).await,
+    };
+    let delivery_id = hex::encode(id);
+    let response = retry::send(retry_policy(*kind), || {
+        let mut request = CLIENT.post(post.url.clone());
+        for (name, value) in &post.headers {
+            request = request.header(name, value);
+        }
+        if *kind == SinkType::Json {
+            request = request.header("X-Flavortown-Delivery", &delivery_id);
+        }
+        request.body(post.body.clone())
+    })
+    .await?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(Sent::Delivered);
+    }
+    let body = response.text().await.unwrap_or_default();
+    if status.is_client_error() && status.as_u16() != 408 && status.as_u16() != 429 {
+        Ok(Sent::Rejected(format!("{status}: {body}")))
+    } else {
+        Err(eyre!("{sink} answered {status}: {body}"))
+    }
+}
+
+/// Sends everything in the outbox, oldest first. When a message can't be sent the rest of that
+/// sink's messages wait for the next run, so they still arrive in order; other sinks carry on.
+pub async fn deliver() -> Result<()> {
+    deliver_with(send).await
+}
+
+async fn deliver_with<F: Future<Output = Result<Sent>>>(
+    send: impl Fn(IVec, Message) -> F,
+) -> Result<()> {
+    let messages = messages()?;
+    let mut failed: Vec<String> = Vec::new();
+    let mut rejected: Vec<String> = Vec::new();
+
+    for row in messages.iter() {
+        let (id, bytes) = row?;
+        let mut entry: Entry = serde_json::from_slice(&bytes)?;
+        let sink = entry.message.sink.clone();
+        if failed.contains(&sink) {
+            continue;
+        }
+        if entry.status == Status::Sending {
+            warn!("The tracker stopped while sending a message to {sink} - sending it again");
+        }
+
+        entry.status = Status::Sending;
+        messages.insert(&id, serde_json::to_vec(&entry)?)?;
+        messages.flush()?;
+
+        match send(id.clone(), entry.message.clone()).await {
+            Ok(Sent::Delivered) => {
+                info!("Notified {sink}");
+                messages.remove(&id)?;
+            }
+            Ok(Sent::Rejected(reason)) => {
+                error!(
+                    "{sink} rejected a message ({reason}), dropping it: {}",
+                    entry.message.payload.body()
+                );
+                messages.remove(&id)?;
+                rejected.push(sink);
+            }
+            Err(e) => {
+                error!("Failed to notify {sink}, will try again next run: {e:?}");
+                entry.status = Status::Pending;
+                messages.insert(&id, serde_json::to_vec(&entry)?)?;
+                failed.push(sink);
+            }
+        }
+        messages.flush()?;
+    }
+
+    match (failed.is_empty(), rejected.is_empty()) {
+        (true, true) => Ok(()),
+        (false, _) => Err(eyre!("failed to notify {}", failed.join(", "))),
+        (true, false) => Err(eyre!("{} rejected messages", rejected.join(", "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::Post;
+    use crate::testing;
+    use std::sync::{Arc, Mutex};
+
+    fn message(sink: &str, body: &str) -> Message {
+        Message {
+            sink: sink.to_string(),
+            kind: SinkType::Json,
+            payload: Payload::Post(Post {
+                url: "https://example.com/hook".parse().unwrap(),
+                headers: Vec::new(),
+                body: body.to_string(),
+            }),
+        }
+    }
+
+    /// Everything sent as `sink: body`, oldest first.
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    /// Sends by recording, and fails for the sink called `down`.
+    fn recorder(
+        sent: &Log,
+        down: &'static str,
+    ) -> impl Fn(IVec, Message) -> std::future::Ready<Result<Sent>> {
+        let sent = Arc::clone(sent);
+        move |_, message| {
+            let Payload::Post(post) = &message.payload else {
+                unreachable!()
+            };
+            sent.lock()
+                .unwrap()
+                .push(format!("{}: {}", message.sink, post.body));
+            std::future::ready(if message.sink == down {
+                Err(eyre!("{down} is down"))
+            } else {
+                Ok(Sent::Delivered)
+            })
+        }
+    }
+
+    fn queued_for(sink: &str) -> Vec<(Status, String)> {
+        messages()
+            .unwrap()
+            .iter()
+            .map(|row| serde_json::from_slice::<Entry>(&row.unwrap().1).unwrap())
+            .filter(|entry| entry.message.sink == sink)
+            .map(|entry| (entry.status, entry.message.payload.body()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sends_again_what_was_being_sent_when_the_tracker_stopped() {
+        testing::init();
+        let _outbox = testing::OUTBOX.lock().await;
+        enqueue(vec![message("crashed", "1"), message("crashed", "2")], None).unwrap();
+        // the tracker stopped while the first message was on its way.
+        let messages = messages().unwrap();
+        let (id, bytes) = messages.iter().next().unwrap().unwrap();
+        let mut entry: Entry = serde_json::from_slice(&bytes).unwrap();
+        entry.status = Status::Sending;
+        messages
+            .insert(id, serde_json::to_vec(&entry).unwrap())
+            .unwrap();
+
+        let sent = Log::default();
+        deliver_with(recorder(&sent, "")).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["crashed: 1", "crashed: 2"]);
+        assert!(queued_for("crashed").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_failing_sink_does_not_hold_up_the_others() {
+        testing::init();
+        let _outbox = testing::OUTBOX.lock().await;
+        enqueue(
+            vec![
+                message("down", "1"),
+                message("up", "1"),
+                message("down", "2"),
+                message("up", "2"),
+            ],
+            None,
+        )
+        .unwrap();
+
+        let sent = Log::default();
+        assert!(deliver_with(recorder(&sent, "down")).await.is_err());
+        // the second message to `down` waits, so the two still arrive in order.
+        assert_eq!(*sent.lock().unwrap(), ["down: 1", "up: 1", "up: 2"]);
+        assert_eq!(
+            queued_for("down"),
+            [
+                (Status::Pending, "1".to_string()),
+                (Status::Pending, "2".to_string())
+            ]
+        );
+        assert!(queued_for("up").is_empty());
+
+        let sent = Log::default();
+        deliver_with(recorder(&sent, "")).await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["down: 1", "down: 2"]);
+    }
+
+    #[tokio::test]
+    async fn commits_a_snapshot_left_queued() {
+        testing::init();
+        let _outbox = testing::OUTBOX.lock().await;
+        let at = 1767225600;
+        let item = testing::item(1, "Sticker", &[("US", 100)]);
+        let snapshot = Snapshot {
+            tracker_version: None,
+            scrape: None,
+            regions: crate::scraper::known_regions(),
+            items: vec![item.clone()],
+        };
+        let diff = ItemDiff {
+            new_items: vec![item],
+            updated_items: Vec::new(),
+            deleted_items: Vec::new(),
+            new_regions: Vec::new(),
+            removed_regions: Vec::new(),
+        };
+        // a run queued its messages and snapshot, then stopped before writing the snapshot.
+        enqueue(
+            vec![message("queued", "1")],
+            Some(PendingSnapshot { at, snapshot, diff }),
+        )
+        .unwrap();
+
+        let committed = commit_snapshot().unwrap().unwrap();
+        assert_eq!(committed.items[0].id, 1);
+        assert_eq!(STORE.get(at).unwrap().unwrap().items.len(), 1);
+        assert_eq!(STORE.get_diff(at).unwrap().unwrap().new_items.len(), 1);
+        assert!(commit_snapshot().unwrap().is_none());
+        // the messages are still there to be sent.
+        assert_eq!(queued_for("queued").len(), 1);
+        deliver_with(recorder(&Log::default(), "")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn alerts_once_per_expired_session() {
+        testing::init();
+        let _outbox = testing::OUTBOX.lock().await;
+        let alert = || vec![message("alerts", "COOKIES #1 expired")];
+        enqueue_session_expired("COOKIES #1", alert()).unwrap();
+        enqueue_session_expired("COOKIES #1", alert()).unwrap();
+        assert_eq!(queued_for("alerts").len(), 1);
+
+        // still logged out, so it stays alerted about.
+        clear_expired_sessions(&["COOKIES #1".to_string()]).unwrap();
+        enqueue_session_expired("COOKIES #1", alert()).unwrap();
+        assert_eq!(queued_for("alerts").len(), 1);
+
+        // logged in again, then out again.
+        clear_expired_sessions(&[]).unwrap();
+        enqueue_session_expired("COOKIES #1", alert()).unwrap();
+        assert_eq!(queued_for("alerts").len(), 2);
+        deliver_with(recorder(&Log::default(), "")).await.unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Retries and rate limiting for outbound HTTP. Each destination gets its own [`RetryPolicy`],
+//! since Slack is happy to be asked again a few seconds later while Flavortown shouldn't be
+//! hammered. Tries under the Flavortown policy also count against `MAX_CONCURRENT_REQUESTS`
+//! and wait their turn under `MAX_REQUESTS_PER_SECOND`, since that's the site we're asked to be
+//! polite to. Only the try itself holds a place, not the wait before the next one.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::CONFIG;
+use color_eyre::Result;
+use log::warn;
+use once_cell::sync::Lazy;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use tokio::sync::Semaphore;
+use tokio::time::sleep;
+
+pub struct RetryPolicy {
+    /// Shown in logs.
+    pub name: &'static str,
+    /// Including the first try.
+    pub max_attempts: u32,
+    /// Backoff before the second try, doubling after that.
+    pub base_delay: Duration,
+    /// Longest we'll wait between tries. A `Retry-After` longer than this is given up on.
+    pub max_delay: Duration,
+    /// Whether tries count against `MAX_CONCURRENT_REQUESTS` and `MAX_REQUESTS_PER_SECOND`.
+    pub rate_limited: bool,
+}
+
+impl RetryPolicy {
+    pub const FLAVORTOWN: Self = Self {
+        name: "Flavortown",
+        max_attempts: 4,
+        base_delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(60),
+        rate_limited: true,
+    };
+    pub const CDN: Self = Self {
+        name: "CDN",
+        max_attempts: 3,
+        base_delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(30),
+        rate_limited: false,
+    };
+    pub const OPENAI: Self = Self {
+        name: "OpenAI",
+        max_attempts: 3,
+        base_delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(30),
+        rate_limited: false,
+    };
+    pub const SLACK: Self = Self {
+        name: "Slack",
+        max_attempts: 6,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(120),
+        rate_limited: false,
+    };
+    pub const DISCORD: Self = Self {
+        name: "Discord",
+        max_attempts: 6,
+        base_delay: Duration::from_secs(1),
+        max_delay: Duration::from_secs(120),
+        rate_limited: false,
+    };
+    pub const WEBHOOK: Self = Self {
+        name: "JSON webhook",
+        max_attempts: 4,
+        base_delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(60),
+        rate_limited: false,
+    };
+
+    /// Exponential backoff with "equal jitter": half the delay is fixed, half is random, so
+    /// parallel requests that failed together don't all come back at once.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+    }
+
+    /// How long to wait before try number `retry + 2`, or `None` if the server asked for a
+    /// longer wait than we're willing to give it.
+    fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(wait) if wait > self.max_delay => None,
+            Some(wait) => Some(wait),
+            None => Some(self.backoff(retry)),
+        }
+    }
+}
+
+/// Caps rate limited requests in flight at `MAX_CONCURRENT_REQUESTS`.
+static IN_FLIGHT: Lazy<Semaphore> =
+    Lazy::new(|| Semaphore::new(CONFIG.max_concurrent_requests.max(1)));
+
+/// host -> when its next request may go out
+static NEXT_SLOT: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Blocks until `url`'s host is allowed another request. Slots are handed out in order, so
+/// requests that arrive together are spaced out rather than all woken at once.
+async fn wait_for_slot(url: &Url) {
+    if CONFIG.max_requests_per_second <= 0.0 {
+        return;
+    }
+    let interval = Duration::from_secs_f64(1.0 / CONFIG.max_requests_per_second);
+    let host = url.host_str().unwrap_or_default().to_string();
+
+    let wait = {
+        let mut slots = NEXT_SLOT.lock().unwrap();
+        let now = Instant::now();
+        let slot = slots.get(&host).copied().unwrap_or(now).max(now);
+        slots.insert(host, slot + interval);
+        slot - now
+    };
+    sleep(wait).await;
+}
+
+const fn is_transient(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    parse_retry_after(response.headers().get(RETRY_AFTER)?.to_str().ok()?)
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<f64>() {
+        Ok(secs) => Duration::try_from_secs_f64(secs).ok(),
+        Err(_) => httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .ok()
+            .or(Some(Duration::ZERO)),
+    }
+}
+
+/// Sends the request built by `build` (called again for every try), retrying timeouts,
+/// connection errors, 429s and 5xx responses. Once out of tries the last response is returned
+/// as is, so callers still see the status.
+pub async fn send(policy: &RetryPolicy, build: impl Fn() -> RequestBuilder) -> Result<Response> {
+    let mut attempt = 1;
+    loop {
+        let (client, request) = build().build_split();
+        let request = request?;
+        let result = if policy.rate_limited {
+            let _permit = IN_FLIGHT.acquire().await?;
+            wait_for_slot(request.url()).await;
+            client.execute(request).await
+        } else {
+            client.execute(request).await
+        };
+        let delay = match result {
+            Ok(response) if attempt < policy.max_attempts && is_transient(response.status()) => {
+                let wait = retry_after(&response);
+                let Some(delay) = policy.delay(attempt - 1, wait) else {
+                    warn!(
+                        "{} asked us to wait {wait:?} before retrying {} - giving up",
+                        policy.name,
+                        response.url()
+                    );
+                    return Ok(response);
+                };
+                warn!(
+                    "{} answered {} for {} - retrying in {delay:?} ({attempt}/{})",
+                    policy.name,
+                    response.status(),
+                    response.url(),
+                    policy.max_attempts
+                );
+                delay
+            }
+            Ok(response) => return Ok(response),
+            Err(e)
+                if attempt < policy.max_attempts
+                    && (e.is_timeout() || e.is_connect() || e.is_request()) =>
+            {
+                let delay = policy.backoff(attempt - 1);
+                warn!(
+                    "Request to {} failed ({e}) - retrying in {delay:?} ({attempt}/{})",
+                    policy.name, policy.max_attempts
+                );
+                delay
+            }
+            Err(e) => return Err(e.into()),
+        };
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_delay() {
+        let policy = RetryPolicy::FLAVORTOWN;
+        for retry in 0..40 {
+            let full = policy
+                .base_delay
+                .saturating_mul(2u32.saturating_pow(retry))
+                .min(policy.max_delay);
+            for _ in 0..20 {
+                let delay = policy.backoff(retry);
+                assert!(
+                    delay >= full / 2 && delay <= full,
+                    "{delay:?} for retry {retry}"
+                );
+            }
+        }
+        assert!(policy.backoff(0) <= Duration::from_secs(2));
+        assert!(policy.backoff(39) >= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn follows_retry_after_unless_it_is_too_long() {
+        let policy = RetryPolicy::SLACK;
+        let wait = Duration::from_secs(30);
+        assert_eq!(policy.delay(0, Some(wait)), Some(wait));
+        assert_eq!(
+            policy.delay(5, Some(policy.max_delay)),
+            Some(policy.max_delay)
+        );
+        assert_eq!(policy.delay(0, Some(policy.max_delay * 2)), None);
+        assert!(policy.delay(0, None).unwrap() <= policy.base_delay);
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after(" 1.5 "),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(parse_retry_after("-3"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(90));
+        let wait = parse_retry_after(&later).unwrap();
+        assert!(wait > Duration::from_secs(80) && wait <= Duration::from_secs(90));
+    }
+}
@@ -1,80 +1,178 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use crate::config::CONFIG;
-use crate::storage::{CDN_CACHE_DB, upload_to_cdn};
-use color_eyre::{Result, eyre::eyre};
-use log::debug;
+use crate::fetch::{FETCHER, SessionExpired};
+use crate::session::{self, Session};
+use crate::storage::{CDN_CACHE_DB, ScrapeInfo, Snapshot, upload_to_cdn};
+use color_eyre::{Report, Result, eyre::eyre};
+use futures::{StreamExt, future, stream};
+use log::{debug, warn};
 use once_cell::sync::Lazy;
-use rayon::prelude::*;
-use reqwest::blocking::Client;
-use reqwest::{StatusCode, Url, header, redirect};
+use reqwest::{Client, Url, redirect};
 use scraper::{ElementRef, Html, Selector};
 use serde::{Deserialize, Serialize};
-use strum::VariantArray;
-use strum_macros::{Display, VariantArray};
 
+/// Shared by everything that talks HTTP. Flavortown requests bring their own session cookie.
 pub static CLIENT: Lazy<Client> = Lazy::new(|| {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::COOKIE,
-        CONFIG
-            .cookie
-            .parse()
-            .expect("cookie parsing failed - check your COOKIE env var"),
-    );
     Client::builder()
         .user_agent(&CONFIG.user_agent)
-        .default_headers(headers)
         .redirect(redirect::Policy::none())
+        .timeout(Duration::from_secs(30))
         .build()
         .expect("failed to build scraping client")
 });
 
-#[derive(Display, Debug, VariantArray, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
-pub enum Region {
-    #[strum(to_string = "United States")]
-    UnitedStates,
-    #[strum(to_string = "EU")]
-    Europe,
-    #[strum(to_string = "United Kingdom")]
-    UnitedKingdom,
-    #[strum(to_string = "India")]
-    India,
-    #[strum(to_string = "Canada")]
-    Canada,
-    #[strum(to_string = "Australia")]
-    Australia,
-    #[strum(to_string = "Rest of World")]
-    Global,
-}
+/// The regions the shop had before regions were read from the page, in dropdown order, with
+/// their names and the keys old snapshots store their prices under. Those keys are still what
+/// these regions are written as, so existing snapshots and API consumers keep working.
+const KNOWN_REGIONS: [(&str, &str, &str); 7] = [
+    ("US", "United States", "UnitedStates"),
+    ("EU", "EU", "Europe"),
+    ("UK", "United Kingdom", "UnitedKingdom"),
+    ("IN", "India", "India"),
+    ("CA", "Canada", "Canada"),
+    ("AU", "Australia", "Australia"),
+    ("XX", "Rest of World", "Global"),
+];
+
+/// A shop region, identified by the code Flavortown uses for it (`US`, `UK`, ...). Which regions
+/// exist is read from the shop's region dropdown on every run, see [`RegionInfo`].
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Region(String);
 
 impl Region {
-    pub const fn code(&self) -> &'static str {
-        match self {
-            Self::UnitedStates => "US",
-            Self::Europe => "EU",
-            Self::UnitedKingdom => "UK",
-            Self::India => "IN",
-            Self::Canada => "CA",
-            Self::Australia => "AU",
-            Self::Global => "XX",
+    /// Any alphanumeric code is a region, whether or not the shop has it.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.trim();
+        (!code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric()))
+            .then(|| Self(code.to_ascii_uppercase()))
+    }
+
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+
+    fn known_index(&self) -> Option<usize> {
+        KNOWN_REGIONS.iter().position(|(code, ..)| *code == self.0)
+    }
+
+    /// Unicode flag, for places that don't understand Slack's emoji shortcodes.
+    pub fn emoji(&self) -> String {
+        match self.code() {
+            "XX" => "🌎".to_string(),
+            "UK" => "🇬🇧".to_string(),
+            code if code.len() == 2 && code.chars().all(|c| c.is_ascii_uppercase()) => code
+                .chars()
+                .filter_map(|c| char::from_u32(0x1F1E6 + (c as u32 - 'A' as u32)))
+                .collect(),
+            code => format!("[{code}]"),
+        }
+    }
+
+    pub fn flag(&self) -> String {
+        match self.code() {
+            "XX" => ":earth_americas:".to_string(),
+            "UK" => ":flag-gb:".to_string(),
+            code if code.len() == 2 => format!(":flag-{}:", code.to_ascii_lowercase()),
+            code => format!("[{code}]"),
         }
     }
 
-    pub const fn flag(&self) -> &'static str {
-        match self {
-            Self::UnitedStates => ":flag-us:",
-            Self::Europe => ":flag-eu:",
-            Self::UnitedKingdom => ":flag-gb:",
-            Self::India => ":flag-in:",
-            Self::Canada => ":flag-ca:",
-            Self::Australia => ":flag-au:",
-            Self::Global => ":earth_americas:",
+    /// Every region the shop currently has, in dropdown order.
+    pub fn all() -> Vec<Self> {
+        CURRENT_REGIONS
+            .read()
+            .unwrap()
+            .iter()
+            .map(RegionInfo::region)
+            .collect()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = CURRENT_REGIONS
+            .read()
+            .unwrap()
+            .iter()
+            .find(|info| info.code == self.0)
+            .map(|info| info.name.clone());
+        match name {
+            Some(name) => write!(f, "{name}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// The original regions first, in their old order, then new ones by code.
+impl Ord for Region {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        let key = |r: &Self| (r.known_index().unwrap_or(KNOWN_REGIONS.len()), r.0.clone());
+        key(self).cmp(&key(other))
+    }
+}
+
+impl PartialOrd for Region {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Serialize for Region {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.known_index() {
+            Some(i) => serializer.serialize_str(KNOWN_REGIONS[i].2),
+            None => serializer.serialize_str(&self.0),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for Region {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        match KNOWN_REGIONS.iter().find(|(.., legacy)| *legacy == key) {
+            Some((code, ..)) => Ok(Self(code.to_string())),
+            None => Self::from_code(&key)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid region {key:?}"))),
+        }
+    }
+}
+
+/// A region as the shop's region dropdown lists it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegionInfo {
+    pub code: String,
+    pub name: String,
+}
+
+impl RegionInfo {
+    pub fn region(&self) -> Region {
+        Region(self.code.clone())
+    }
+}
+
+/// The regions every snapshot had before they were read from the page.
+pub fn known_regions() -> Vec<RegionInfo> {
+    KNOWN_REGIONS
+        .iter()
+        .map(|(code, name, _)| RegionInfo {
+            code: code.to_string(),
+            name: name.to_string(),
+        })
+        .collect()
+}
+
+/// The regions of the latest snapshot or scrape, for naming and listing them.
+static CURRENT_REGIONS: Lazy<RwLock<Vec<RegionInfo>>> = Lazy::new(|| RwLock::new(known_regions()));
+
+pub fn set_current_regions(regions: &[RegionInfo]) {
+    *CURRENT_REGIONS.write().unwrap() = regions.to_vec();
+}
+
 pub type ShopItems = Vec<ShopItem>;
 pub type ShopItemId = usize;
 
@@ -102,6 +200,12 @@ pub struct ShopItem {
     #[serde(default)]
     pub remaining_stock: Option<u32>,
     pub achievement_lock: Option<String>,
+
+    /// Regions whose data for this item couldn't be scraped this time and was carried over from
+    /// the previous snapshot instead. Not part of the item's identity, so it never shows up as a
+    /// change.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub incomplete_regions: Vec<Region>,
 }
 
 impl ShopItem {
@@ -153,27 +257,10 @@ fn parse_shop_item(element: ElementRef, region: &Region) -> Result<ShopItem> {
         accessories: Vec::new(),
         remaining_stock: None,
         achievement_lock: None,
+        incomplete_regions: Vec::new(),
     })
 }
 
-fn fetch_shop_page() -> Result<String> {
-    let res = CLIENT
-        .get(CONFIG.base_url.join("shop")?)
-        .send()?
-        .error_for_status()?;
-    assert_eq!(res.status(), StatusCode::OK);
-    res.text().map_err(Into::into)
-}
-
-fn fetch_item_detail_page(item_id: ShopItemId) -> Result<String> {
-    let url = CONFIG
-        .base_url
-        .join(&format!("shop/order?shop_item_id={item_id}"))?;
-    let res = CLIENT.get(url).send()?.error_for_status()?;
-    assert_eq!(res.status(), StatusCode::OK);
-    res.text().map_err(Into::into)
-}
-
 struct ItemDetails {
     long_description: Option<String>,
     accessories: Vec<Accessory>,
@@ -181,8 +268,26 @@ struct ItemDetails {
     achievement_lock: Option<String>,
 }
 
-fn scrape_item_details_for_region(item_id: ShopItemId, region: &Region) -> Result<ItemDetails> {
-    let html = fetch_item_detail_page(item_id)?;
+impl From<ShopItem> for ItemDetails {
+    fn from(item: ShopItem) -> Self {
+        Self {
+            long_description: item.long_description,
+            accessories: item.accessories,
+            remaining_stock: item.remaining_stock,
+            achievement_lock: item.achievement_lock,
+        }
+    }
+}
+
+async fn scrape_item_details_for_region(
+    session: &Session,
+    item_id: ShopItemId,
+    region: &Region,
+) -> Result<ItemDetails> {
+    let url = CONFIG
+        .base_url
+        .join(&format!("shop/order?shop_item_id={item_id}"))?;
+    let html = session.get(url).await?;
     let document = Html::parse_document(&html);
     let root = document.root_element();
 
@@ -272,59 +377,472 @@ fn merge_item_details(item: &mut ShopItem, details: ItemDetails) {
     }
 }
 
-fn get_csrf_token() -> Result<String> {
-    let document = Html::parse_document(&fetch_shop_page()?);
-    document
-        .select(&Selector::parse("meta[name=\"csrf-token\"]").unwrap())
-        .next()
-        .and_then(|e| e.attr("content"))
-        .map(String::from)
-        .ok_or_else(|| eyre!("Failed to find csrf-token"))
-}
-
-fn set_region(region: &Region, csrf_token: &str) -> Result<()> {
-    let res = CLIENT
-        .patch(CONFIG.base_url.join("shop/update_region")?)
-        .header("X-CSRF-Token", csrf_token)
-        .form(&[("region", region.code())])
-        .send()?
-        .error_for_status()?;
-    assert_eq!(res.status(), StatusCode::OK);
-    Ok(())
+#[derive(Default)]
+struct RegionCards {
+    items: ShopItems,
+    /// Cards that didn't parse, with their item ID if even that couldn't be read.
+    failed: Vec<(Option<ShopItemId>, Report)>,
 }
 
-fn scrape_region(region: &Region, csrf_token: &str) -> Result<ShopItems> {
-    set_region(region, csrf_token)?;
-
-    let document = Html::parse_document(&fetch_shop_page()?);
-    let root = document.root_element();
-
-    // step 1: region selection
-    let selected_region = select_one(
-        &root,
+fn selected_region(document: &Html) -> Result<String> {
+    Ok(select_one(
+        &document.root_element(),
         "button.dropdown__button > span.dropdown__selected > span.dropdown__char-span",
     )?
     .text()
     .next()
-    .unwrap();
-    assert_eq!(selected_region, region.to_string());
+    .unwrap_or_default()
+    .to_string())
+}
+
+/// The region dropdown's options, like
+/// `<button class="dropdown__option" data-value="UK">United Kingdom</button>`.
+const REGION_OPTION_SELECTOR: &str = ".dropdown__option[data-value]";
+
+fn parse_regions(document: &Html) -> Vec<RegionInfo> {
+    let mut regions: Vec<RegionInfo> = Vec::new();
+    for option in document.select(&Selector::parse(REGION_OPTION_SELECTOR).unwrap()) {
+        let Some(region) = option.attr("data-value").and_then(Region::from_code) else {
+            continue;
+        };
+        if !regions.iter().any(|r| r.code == region.code()) {
+            regions.push(RegionInfo {
+                code: region.0,
+                name: option.text().collect::<String>().trim().to_string(),
+            });
+        }
+    }
+    regions
+}
+
+/// Reads which regions the shop has from its region dropdown, trying each session in turn until
+/// one is still logged in. Sessions that aren't are added to `expired`. If the dropdown can't be
+/// read, the previous snapshot's regions are scraped instead, so a change to the page doesn't
+/// look like every region closing down.
+async fn discover_regions(
+    previous: Option<&Snapshot>,
+    errors: &mut Vec<ScrapeError>,
+    expired: &mut Vec<String>,
+) -> Result<Vec<RegionInfo>> {
+    let mut found = Err(eyre!("there's no session to read the shop with"));
+    for session in session::SESSIONS.iter() {
+        found = session
+            .shop_page()
+            .await
+            .map(|page| parse_regions(&Html::parse_document(&page)));
+        match &found {
+            Err(e) if e.downcast_ref::<SessionExpired>().is_some() => {
+                warn!("{} is logged out", session.name);
+                expired.push(session.name.clone());
+            }
+            _ => break,
+        }
+    }
+    let error = match found {
+        Ok(regions) if !regions.is_empty() => return Ok(regions),
+        Ok(_) => eyre!("the region dropdown has no options"),
+        Err(e) if e.downcast_ref::<SessionExpired>().is_some() => return Err(e),
+        Err(e) => e,
+    };
+    errors.push(ScrapeError {
+        region: None,
+        item_id: None,
+        error: error.wrap_err("couldn't read the shop's regions, so last run's were scraped"),
+    });
+    Ok(previous.map_or_else(known_regions, |snapshot| snapshot.regions.clone()))
+}
 
-    // step 2: parse all shop items
-    document
-        .select(&Selector::parse(".shop-item-card").unwrap())
-        .map(|element_ref| parse_shop_item(element_ref, region))
+/// Loads the shop page in `region`, switching `session` to it first unless it's already there.
+async fn load_shop_page(session: &Session, region: &Region) -> Result<Html> {
+    let mut switched = false;
+    loop {
+        if !session.is_on(region) {
+            session.switch_to(region).await?;
+            switched = true;
+        }
+
+        let document = Html::parse_document(&session.shop_page().await?);
+        let selected = selected_region(&document)?;
+        if selected == region.to_string() {
+            session.saw_region(Some(region));
+            return Ok(document);
+        }
+
+        session.saw_region(None);
+        // a session we didn't just switch may have been switched elsewhere, e.g. by someone
+        // using the same cookie in a browser, so it gets one switch before giving up.
+        if switched {
+            return Err(eyre!(
+                "asked for {region} but the shop is showing {selected:?}"
+            ));
+        }
+    }
+}
+
+async fn scrape_region(session: &Session, region: &Region) -> Result<RegionCards> {
+    let document = load_shop_page(session, region).await?;
+
+    // one broken card shouldn't lose the whole region.
+    let mut cards = RegionCards::default();
+    for element_ref in document.select(&Selector::parse(".shop-item-card").unwrap()) {
+        match parse_shop_item(element_ref, region) {
+            Ok(item) => cards.items.push(item),
+            Err(e) => {
+                let id = element_ref
+                    .attr("data-shop-id")
+                    .and_then(|id| id.parse().ok());
+                cards.failed.push((id, e));
+            }
+        }
+    }
+    Ok(cards)
+}
+
+/// Something that went wrong while scraping, which the snapshot works around.
+pub struct ScrapeError {
+    pub region: Option<Region>,
+    pub item_id: Option<ShopItemId>,
+    pub error: Report,
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.region, self.item_id) {
+            (Some(region), Some(id)) => write!(f, "item {id} in {region}: ")?,
+            (Some(region), None) => write!(f, "{region}: ")?,
+            (None, Some(id)) => write!(f, "item {id}: ")?,
+            (None, None) => {}
+        }
+        write!(f, "{:#}", self.error)
+    }
+}
+
+pub struct Scrape {
+    pub snapshot: Snapshot,
+    /// Everything that failed. Affected items are already patched up from the previous
+    /// snapshot and marked in `incomplete_regions`.
+    pub errors: Vec<ScrapeError>,
+    /// Sessions Flavortown has logged out. Their regions were scraped by another session if it
+    /// was found in time, and carried over otherwise.
+    pub expired_sessions: Vec<String>,
+}
+
+/// What we knew about `item` in `region` last time, as if it had just been scraped.
+fn carried_over(item: &ShopItem, region: &Region) -> Option<ShopItem> {
+    let price = *item.prices.get(region)?;
+    let mut carried = item.clone();
+    carried.prices = HashMap::from([(region.clone(), price)]);
+    for accessory in &mut carried.accessories {
+        accessory.prices.retain(|r, _| r == region);
+    }
+    carried.accessories.retain(|a| !a.prices.is_empty());
+    Some(carried)
+}
+
+fn mark_incomplete(item: &mut ShopItem, region: &Region) {
+    if !item.incomplete_regions.contains(region) {
+        item.incomplete_regions.push(region.clone());
+    }
+}
+
+/// Fills in `item`'s details in `region` from `previous`, for an item whose card was scraped but
+/// whose detail page failed to load.
+fn carry_over_details(item: &mut ShopItem, previous: Option<&ShopItem>, region: &Region) {
+    if let Some(carried) = previous.and_then(|prev| carried_over(prev, region)) {
+        merge_item_details(item, ItemDetails::from(carried));
+    }
+    mark_incomplete(item, region);
+}
+
+/// Puts `previous`'s data for `region` back into `items`, for an item we couldn't scrape there.
+fn carry_over(items: &mut HashMap<ShopItemId, ShopItem>, previous: &ShopItem, region: &Region) {
+    let Some(carried) = carried_over(previous, region) else {
+        return;
+    };
+    match items.get_mut(&previous.id) {
+        Some(item) => {
+            item.prices.extend(carried.prices.clone());
+            merge_item_details(item, ItemDetails::from(carried));
+            mark_incomplete(item, region);
+        }
+        None => {
+            let mut item = carried;
+            item.incomplete_regions = vec![region.clone()];
+            items.insert(item.id, item);
+        }
+    }
+}
+
+/// How much of each item's detail page to fetch, set with `DETAIL_STRATEGY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DetailStrategy {
+    /// Every item's page in every region it's sold in.
+    Full,
+    /// Every item's page once, plus once per region for items with accessories, as only
+    /// accessory prices differ between regions.
+    #[default]
+    Smart,
+    /// Like `smart`, but items whose card hasn't changed since the previous snapshot keep their
+    /// previous details. Far fewer requests, but stock and long description changes are only
+    /// noticed once something on the card changes too.
+    Cached,
+}
+
+/// Which items have had their detail page fetched so far, shared by every session of a scrape.
+#[derive(Default)]
+struct DetailsSeen {
+    fetched: HashSet<ShopItemId>,
+    with_accessories: HashSet<ShopItemId>,
+}
+
+fn needs_detail_page(
+    strategy: DetailStrategy,
+    item_id: ShopItemId,
+    previous: Option<&ShopItem>,
+    seen: &mut DetailsSeen,
+) -> bool {
+    if strategy == DetailStrategy::Full {
+        return true;
+    }
+    // whichever region gets to an item first fetches its page.
+    let first = seen.fetched.insert(item_id);
+    first
+        || seen.with_accessories.contains(&item_id)
+        || previous.is_some_and(|prev| !prev.accessories.is_empty())
+}
+
+/// Whether everything the shop page shows about the item in `region` is as it was last time.
+fn card_unchanged(previous: &ShopItem, card: &ShopItem, region: &Region) -> bool {
+    previous.title == card.title
+        && previous.description == card.description
+        && previous.image_id == card.image_id
+        && previous.prices.get(region) == card.prices.get(region)
+        && !previous.incomplete_regions.contains(region)
+}
+
+/// What one region turned up, before it's merged with the others.
+#[derive(Default)]
+struct RegionScrape {
+    cards: RegionCards,
+    details: Vec<(ShopItemId, Result<ItemDetails>)>,
+    /// Items whose card hasn't changed, so they keep the previous snapshot's details.
+    reused: Vec<ShopItemId>,
+}
+
+/// Fetches detail pages in `region`, which `session` has to be showing. They can overlap with
+/// each other but not with the session's next region.
+async fn fetch_details(
+    session: &Session,
+    region: &Region,
+    item_ids: Vec<ShopItemId>,
+) -> Vec<(ShopItemId, Result<ItemDetails>)> {
+    stream::iter(item_ids)
+        .map(|id| async move {
+            let details = scrape_item_details_for_region(session, id, region).await;
+            (id, details)
+        })
+        .buffer_unordered(CONFIG.max_concurrent_requests.max(1))
         .collect()
+        .await
+}
+
+async fn scrape_region_with_details(
+    session: &Session,
+    region: &Region,
+    previous: &HashMap<ShopItemId, &ShopItem>,
+    seen: &Mutex<DetailsSeen>,
+) -> Result<RegionScrape> {
+    debug!("Now scraping {region:?} on {}", session.name);
+    let cards = scrape_region(session, region).await?;
+
+    let mut reused = Vec::new();
+    let mut item_ids = Vec::new();
+    {
+        let mut seen = seen.lock().unwrap();
+        for card in &cards.items {
+            let prev = previous.get(&card.id).copied();
+            if CONFIG.detail_strategy == DetailStrategy::Cached
+                && prev.is_some_and(|prev| card_unchanged(prev, card, region))
+            {
+                reused.push(card.id);
+            } else if needs_detail_page(CONFIG.detail_strategy, card.id, prev, &mut seen) {
+                item_ids.push(card.id);
+            }
+        }
+    }
+    debug!(
+        "Fetching {} of {} detail pages in {region}",
+        item_ids.len(),
+        cards.items.len()
+    );
+
+    let details = fetch_details(session, region, item_ids).await;
+    let mut seen = seen.lock().unwrap();
+    for (id, detail) in &details {
+        if let Ok(detail) = detail
+            && !detail.accessories.is_empty()
+        {
+            seen.with_accessories.insert(*id);
+        }
+    }
+    Ok(RegionScrape {
+        cards,
+        details,
+        reused,
+    })
+}
+
+/// Scrapes `regions` one after another, as they all share the session's region setting. Once the
+/// session is logged out, the regions it hasn't got to fail too.
+async fn scrape_session(
+    session: &Session,
+    regions: &[Region],
+    previous: &HashMap<ShopItemId, &ShopItem>,
+    seen: &Mutex<DetailsSeen>,
+) -> Vec<(Region, Result<RegionScrape>)> {
+    let mut results = Vec::new();
+    for region in regions {
+        let result = scrape_region_with_details(session, region, previous, seen).await;
+        let expired = result
+            .as_ref()
+            .is_err_and(|e| e.downcast_ref::<SessionExpired>().is_some());
+        results.push((region.clone(), result));
+        if expired {
+            warn!("{} is logged out", session.name);
+            break;
+        }
+    }
+    for region in &regions[results.len()..] {
+        let skipped = eyre!("{} was logged out before it got to {region}", session.name);
+        results.push((region.clone(), Err(skipped)));
+    }
+    results
 }
 
-pub fn scrape() -> Result<Vec<ShopItem>> {
+/// Scrapes every region, with each session working through its own regions in parallel.
+/// Failures are collected rather than returned: anything that can't be scraped is carried over
+/// from `previous`, so an item is never reported as deleted just because its card or region
+/// failed to load, or its session was logged out. Only every region failing is an error, which
+/// is the [`SessionExpired`] if that's why.
+pub async fn scrape(previous: Option<&Snapshot>) -> Result<Scrape> {
+    let started_at = time_format::now().unwrap();
+    let started = Instant::now();
+    let mut errors = Vec::new();
+    let mut expired = Vec::new();
+    let regions = discover_regions(previous, &mut errors, &mut expired).await?;
+    set_current_regions(&regions);
+    let region_list: Vec<_> = regions.iter().map(RegionInfo::region).collect();
+
+    let previous: HashMap<ShopItemId, &ShopItem> = previous
+        .into_iter()
+        .flat_map(|snapshot| &snapshot.items)
+        .map(|item| (item.id, item))
+        .collect();
+    let seen = Mutex::new(DetailsSeen::default());
+    let assignments = session::assign_regions(&region_list, &expired);
+
+    let sessions = assignments
+        .iter()
+        .map(|(session, regions)| scrape_session(session, regions, &previous, &seen));
+    // fixtures are matched up by request order, which parallel sessions would shuffle.
+    let results = if FETCHER.records_order() {
+        let mut results = Vec::new();
+        for session in sessions {
+            results.push(session.await);
+        }
+        results
+    } else {
+        future::join_all(sessions).await
+    };
+
+    let mut scraped = HashMap::new();
+    for ((session, _), results) in assignments.iter().zip(results) {
+        for (region, result) in results {
+            if result
+                .as_ref()
+                .is_err_and(|e| e.downcast_ref::<SessionExpired>().is_some())
+            {
+                expired.push(session.name.clone());
+            }
+            scraped.insert(region, result);
+        }
+    }
+
+    // a region can finish before another one finds out an item has accessories, and then still
+    // needs the item's page for their prices there.
+    if CONFIG.detail_strategy != DetailStrategy::Full {
+        let with_accessories = seen.into_inner().unwrap().with_accessories;
+        for (session, regions) in &assignments {
+            for region in regions {
+                let Some(Ok(region_scrape)) = scraped.get_mut(region) else {
+                    continue;
+                };
+                let missing: Vec<_> = region_scrape
+                    .cards
+                    .items
+                    .iter()
+                    .map(|card| card.id)
+                    .filter(|id| {
+                        with_accessories.contains(id)
+                            && !region_scrape.reused.contains(id)
+                            && !region_scrape.details.iter().any(|(d, _)| d == id)
+                    })
+                    .collect();
+                if missing.is_empty() {
+                    continue;
+                }
+
+                debug!("Going back to {region} for {} detail pages", missing.len());
+                match load_shop_page(session, region).await {
+                    Ok(_) => {
+                        let details = fetch_details(session, region, missing).await;
+                        region_scrape.details.extend(details);
+                    }
+                    Err(e) => region_scrape.details.extend(
+                        missing
+                            .into_iter()
+                            .map(|id| (id, Err(eyre!("couldn't go back to {region}: {e:#}")))),
+                    ),
+                }
+            }
+        }
+    }
+
     let mut items: HashMap<ShopItemId, ShopItem> = HashMap::new();
-    let csrf_token = get_csrf_token()?;
+    let mut failed_regions = 0;
 
-    for region in Region::VARIANTS {
-        debug!("Now scraping {region:?}");
-        let region_items = scrape_region(region, &csrf_token)?;
+    for region in &region_list {
+        let result = scraped
+            .remove(region)
+            .unwrap_or_else(|| Err(eyre!("no session in COOKIES is set up to scrape {region}")));
+        let (region_scrape, region_incomplete) = match result {
+            Ok(region_scrape) => {
+                // a card without a readable ID could be any item.
+                let incomplete = region_scrape
+                    .cards
+                    .failed
+                    .iter()
+                    .any(|(id, _)| id.is_none());
+                (region_scrape, incomplete)
+            }
+            Err(e) => {
+                failed_regions += 1;
+                errors.push(ScrapeError {
+                    region: Some(region.clone()),
+                    item_id: None,
+                    error: e,
+                });
+                (RegionScrape::default(), true)
+            }
+        };
+        let RegionScrape {
+            cards,
+            details,
+            reused,
+        } = region_scrape;
 
-        for item in &region_items {
+        for item in &cards.items {
             items
                 .entry(item.id)
                 .and_modify(|e| {
@@ -333,28 +851,220 @@ pub fn scrape() -> Result<Vec<ShopItem>> {
                 .or_insert_with(|| item.clone());
         }
 
-        let item_ids: Vec<_> = region_items.iter().map(|i| i.id).collect();
-        let details: Vec<_> = item_ids
-            .par_iter()
-            .map(|&id| scrape_item_details_for_region(id, region).map(|d| (id, d)))
-            .collect::<Result<Vec<_>>>()?;
+        for id in reused {
+            if let Some(carried) = carried_over(previous[&id], region) {
+                merge_item_details(items.get_mut(&id).unwrap(), carried.into());
+            }
+        }
 
         for (id, detail) in details {
-            merge_item_details(items.get_mut(&id).unwrap(), detail);
+            match detail {
+                Ok(detail) => merge_item_details(items.get_mut(&id).unwrap(), detail),
+                Err(e) => {
+                    let item = items.get_mut(&id).unwrap();
+                    carry_over_details(item, previous.get(&id).copied(), region);
+                    errors.push(ScrapeError {
+                        region: Some(region.clone()),
+                        item_id: Some(id),
+                        error: e,
+                    });
+                }
+            }
+        }
+
+        for (id, error) in cards.failed {
+            errors.push(ScrapeError {
+                region: Some(region.clone()),
+                item_id: id,
+                error,
+            });
+            if let Some(prev) = id.and_then(|id| previous.get(&id)) {
+                carry_over(&mut items, prev, region);
+            }
+        }
+
+        // we don't know what's missing, so keep everything from last time that wasn't seen here.
+        if region_incomplete {
+            for prev in previous.values() {
+                let seen = items
+                    .get(&prev.id)
+                    .is_some_and(|item| item.prices.contains_key(region));
+                if !seen {
+                    carry_over(&mut items, prev, region);
+                }
+            }
+        }
+    }
+
+    if failed_regions == region_list.len() {
+        if let Some(i) = errors
+            .iter()
+            .position(|e| e.error.downcast_ref::<SessionExpired>().is_some())
+        {
+            return Err(errors.swap_remove(i).error);
         }
+        let reasons: Vec<_> = errors.iter().map(ToString::to_string).collect();
+        return Err(eyre!("every region failed to load: {}", reasons.join("; ")));
+    }
+
+    for item in items.values_mut() {
+        item.accessories.sort_by_key(|a| a.id);
+        item.incomplete_regions
+            .sort_by(|a, b| a.code().cmp(b.code()));
     }
 
-    items
-        .par_iter_mut()
-        .try_for_each(|(_, item)| -> Result<()> {
-            item.image_url = upload_to_cdn(item.image_id, &item.image_url.clone())?;
-            item.accessories.sort_by_key(|a| a.id);
-            Ok(())
-        })?;
+    // replays stay offline, so recorded runs keep Flavortown's own image URLs.
+    let uploads: Vec<_> = if FETCHER.is_offline() {
+        Vec::new()
+    } else {
+        stream::iter(items.values())
+            .map(|item| async move {
+                let uploaded = upload_to_cdn(item.image_id, &item.image_url).await;
+                (item.id, uploaded)
+            })
+            .buffer_unordered(CONFIG.max_concurrent_requests.max(1))
+            .collect()
+            .await
+    };
+
+    let mut cdn_errors = Vec::new();
+    for (id, uploaded) in uploads {
+        let item = items.get_mut(&id).unwrap();
+        match uploaded {
+            Ok(url) => item.image_url = url,
+            Err(e) => {
+                // an unchanged image keeps last time's CDN URL, a new one Flavortown's.
+                if let Some(prev) = previous.get(&id)
+                    && prev.image_id == item.image_id
+                {
+                    item.image_url = prev.image_url.clone();
+                }
+                cdn_errors.push(ScrapeError {
+                    region: None,
+                    item_id: Some(id),
+                    error: e.wrap_err("CDN upload failed"),
+                });
+            }
+        }
+    }
+    errors.extend(cdn_errors);
 
     CDN_CACHE_DB.flush()?;
 
     let mut items = items.into_values().collect::<ShopItems>();
     items.sort_by_key(|item| item.id);
-    Ok(items)
+    Ok(Scrape {
+        snapshot: Snapshot {
+            tracker_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            scrape: Some(ScrapeInfo {
+                started_at,
+                duration_ms: started.elapsed().as_millis() as u64,
+                detail_strategy: CONFIG.detail_strategy,
+                errors: errors.iter().map(ToString::to_string).collect(),
+            }),
+            regions,
+            items,
+        },
+        errors,
+        expired_sessions: expired,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn region(code: &str) -> Region {
+        Region::from_code(code).unwrap()
+    }
+
+    fn with_accessory(item: ShopItem) -> ShopItem {
+        ShopItem {
+            accessories: vec![Accessory {
+                id: 7,
+                name: "Gift wrap".to_string(),
+                prices: HashMap::from([(region("US"), 5), (region("UK"), 6)]),
+            }],
+            ..item
+        }
+    }
+
+    /// Which of the item's regions would fetch its detail page, visited in order.
+    fn fetched_in(strategy: DetailStrategy, previous: Option<&ShopItem>) -> Vec<bool> {
+        let mut seen = DetailsSeen::default();
+        (0..3)
+            .map(|_| needs_detail_page(strategy, 1, previous, &mut seen))
+            .collect()
+    }
+
+    #[test]
+    fn full_fetches_every_region() {
+        let plain = testing::item(1, "Pen", &[("US", 100)]);
+        assert_eq!(fetched_in(DetailStrategy::Full, None), [true; 3]);
+        assert_eq!(fetched_in(DetailStrategy::Full, Some(&plain)), [true; 3]);
+    }
+
+    #[test]
+    fn smart_and_cached_fetch_once_unless_there_are_accessories() {
+        let plain = testing::item(1, "Pen", &[("US", 100)]);
+        let accessorized = with_accessory(plain.clone());
+        for strategy in [DetailStrategy::Smart, DetailStrategy::Cached] {
+            assert_eq!(fetched_in(strategy, None), [true, false, false]);
+            assert_eq!(fetched_in(strategy, Some(&plain)), [true, false, false]);
+            assert_eq!(fetched_in(strategy, Some(&accessorized)), [true; 3]);
+
+            // another region found accessories this time.
+            let mut seen = DetailsSeen::default();
+            assert!(needs_detail_page(strategy, 1, None, &mut seen));
+            seen.with_accessories.insert(1);
+            assert!(needs_detail_page(strategy, 1, None, &mut seen));
+        }
+    }
+
+    #[test]
+    fn cached_reuses_details_of_unchanged_cards() {
+        let us = region("US");
+        let previous = testing::item(1, "Pen", &[("US", 100), ("UK", 90)]);
+        let card = testing::item(1, "Pen", &[("US", 100)]);
+        // only the card's own region counts.
+        assert!(card_unchanged(&previous, &card, &us));
+        let cheaper = testing::item(1, "Pen", &[("US", 80)]);
+        assert!(!card_unchanged(&previous, &cheaper, &us));
+        let renamed = testing::item(1, "Fancy pen", &[("US", 100)]);
+        assert!(!card_unchanged(&previous, &renamed, &us));
+        // what was carried over last time has to be fetched again.
+        let incomplete = ShopItem {
+            incomplete_regions: vec![us.clone()],
+            ..previous.clone()
+        };
+        assert!(!card_unchanged(&incomplete, &card, &us));
+    }
+
+    #[test]
+    fn carries_details_over_when_the_detail_page_fails() {
+        let us = region("US");
+        let previous = ShopItem {
+            long_description: Some("A very good pen".to_string()),
+            remaining_stock: Some(3),
+            achievement_lock: Some("Chef".to_string()),
+            ..with_accessory(testing::item(1, "Pen", &[("US", 100), ("UK", 90)]))
+        };
+        let mut item = testing::item(1, "Pen", &[("US", 100)]);
+        carry_over_details(&mut item, Some(&previous), &us);
+
+        assert_eq!(item.long_description, previous.long_description);
+        assert_eq!(item.remaining_stock, Some(3));
+        assert_eq!(item.achievement_lock.as_deref(), Some("Chef"));
+        assert_eq!(item.accessories.len(), 1);
+        assert_eq!(item.accessories[0].prices, HashMap::from([(us.clone(), 5)]));
+        assert_eq!(item.incomplete_regions, vec![us.clone()]);
+        assert_eq!(item.prices, HashMap::from([(us.clone(), 100)]));
+
+        // a new item has nothing to carry over, but is still marked.
+        let mut new = testing::item(2, "Mug", &[("US", 50)]);
+        carry_over_details(&mut new, None, &us);
+        assert_eq!(new.remaining_stock, None);
+        assert_eq!(new.incomplete_regions, [us]);
+    }
 }
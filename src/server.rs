@@ -0,0 +1,202 @@
+//! A small read-only JSON API over the tracker's storage, so dashboards and bots can use the
+//! tracker's data instead of scraping Flavortown themselves.
+//!
+//! - `GET /items` - every item in the latest snapshot
+//! - `GET /items/{id}` - one item from the latest snapshot
+//! - `GET /items/{id}/history` - first seen, last in stock and price timelines per region
+//! - `GET /regions` - the regions the shop had in the latest snapshot
+//! - `GET /snapshots` - when every stored snapshot was taken
+//! - `GET /snapshots/{timestamp}` - the snapshot taken at that unix timestamp
+//! - `GET /diff` - the most recent set of changes that was sent out
+//! - `GET /feed.atom`, `GET /feed.rss` - the change feeds, see [`crate::feed`]
+
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+use crate::config::CONFIG;
+use crate::feed;
+use crate::history::PricePoint;
+use crate::scraper::ShopItemId;
+use crate::store::STORE;
+use color_eyre::{Result, eyre::eyre};
+use log::{error, info};
+use serde::Serialize;
+use time_format::TimeStamp;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+#[derive(Serialize)]
+struct ItemHistory {
+    item_id: ShopItemId,
+    first_seen: Option<TimeStamp>,
+    last_in_stock: Option<TimeStamp>,
+    /// Region code -> price timeline
+    prices: BTreeMap<String, Vec<PricePoint>>,
+}
+
+enum ApiResponse {
+    Json(String),
+    Feed(String, &'static str),
+    NotFound,
+}
+
+/// The feed as last written, or rendered now if it hasn't been written yet.
+fn feed(
+    file: &str,
+    render: fn() -> Result<String>,
+    content_type: &'static str,
+) -> Result<ApiResponse> {
+    let body = match std::fs::read_to_string(CONFIG.storage_path.join(file)) {
+        Ok(body) => body,
+        Err(_) => render()?,
+    };
+    Ok(ApiResponse::Feed(body, content_type))
+}
+
+fn json(value: &impl Serialize) -> Result<ApiResponse> {
+    Ok(ApiResponse::Json(serde_json::to_string(value)?))
+}
+
+fn route(path: &str) -> Result<ApiResponse> {
+    let segments: Vec<_> = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    match segments.as_slice() {
+        ["items"] => match STORE.load_latest()? {
+            Some(snapshot) => json(&snapshot.items),
+            None => Ok(ApiResponse::NotFound),
+        },
+        ["regions"] => match STORE.load_latest()? {
+            Some(snapshot) => json(&snapshot.regions),
+            None => Ok(ApiResponse::NotFound),
+        },
+        ["items", id] => {
+            let Ok(id) = id.parse::<ShopItemId>() else {
+                return Ok(ApiResponse::NotFound);
+            };
+            let item = STORE
+                .load_latest()?
+                .and_then(|snapshot| snapshot.items.into_iter().find(|item| item.id == id));
+            match item {
+                Some(item) => json(&item),
+                None => Ok(ApiResponse::NotFound),
+            }
+        }
+        ["items", id, "history"] => {
+            let Ok(item_id) = id.parse::<ShopItemId>() else {
+                return Ok(ApiResponse::NotFound);
+            };
+            let Some(history) = STORE.item_history(item_id)? else {
+                return Ok(ApiResponse::NotFound);
+            };
+            let prices = history
+                .prices
+                .into_iter()
+                .map(|(region, timeline)| (region.code().to_string(), timeline))
+                .collect();
+            json(&ItemHistory {
+                item_id,
+                first_seen: Some(history.first_seen),
+                last_in_stock: history.last_in_stock,
+                prices,
+            })
+        }
+        ["snapshots"] => json(&STORE.list()?),
+        ["snapshots", at] => {
+            let Ok(at) = at.parse::<TimeStamp>() else {
+                return Ok(ApiResponse::NotFound);
+            };
+            match STORE.get(at)? {
+                Some(snapshot) => json(&snapshot),
+                None => Ok(ApiResponse::NotFound),
+            }
+        }
+        ["diff"] => match STORE.load_latest_diff()? {
+            Some(diff) => json(&diff),
+            None => Ok(ApiResponse::NotFound),
+        },
+        ["feed.atom"] => feed(feed::ATOM_PATH, feed::render_atom, "application/atom+xml"),
+        ["feed.rss"] => feed(feed::RSS_PATH, feed::render_rss, "application/rss+xml"),
+        _ => Ok(ApiResponse::NotFound),
+    }
+}
+
+fn handle(request: Request) {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let result = if *request.method() == Method::Get {
+        route(&path)
+    } else {
+        Ok(ApiResponse::NotFound)
+    };
+
+    let response = match result {
+        Ok(ApiResponse::Json(body)) => Response::from_string(body)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+        Ok(ApiResponse::Feed(body, content_type)) => Response::from_string(body)
+            .with_header(Header::from_bytes("Content-Type", content_type).unwrap()),
+        Ok(ApiResponse::NotFound) => Response::from_string("{\"error\":\"not found\"}")
+            .with_status_code(404)
+            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+        Err(e) => {
+            error!("Failed to handle {path}: {e:?}");
+            Response::from_string("{\"error\":\"internal error\"}")
+                .with_status_code(500)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap())
+        }
+    }
+    .with_header(Header::from_bytes("Access-Control-Allow-Origin", "*").unwrap());
+
+    if let Err(e) = request.respond(response) {
+        error!("Failed to respond to {path}: {e}");
+    }
+}
+
+/// How many requests are handled at once. The rest wait their turn instead of each getting a
+/// thread.
+const WORKERS: usize = 8;
+
+/// Serves the API on `HTTP_ADDR` until the process exits.
+pub fn serve() -> Result<()> {
+    let server = Arc::new(
+        Server::http(&CONFIG.http_addr)
+            .map_err(|e| eyre!("failed to listen on {}: {e}", CONFIG.http_addr))?,
+    );
+    info!("Serving the API on http://{}", CONFIG.http_addr);
+
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            thread::spawn(move || {
+                // `recv` only fails once the server is shutting down.
+                while let Ok(request) = server.recv() {
+                    // a request that panics mustn't take its worker with it.
+                    if panic::catch_unwind(AssertUnwindSafe(|| handle(request))).is_err() {
+                        error!("Handling a request panicked");
+                    }
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Serves the API on a background thread, next to the daemon's scrape loop.
+pub fn spawn() {
+    thread::spawn(|| {
+        if let Err(e) = serve() {
+            error!("API server stopped: {e:?}");
+        }
+    });
+}
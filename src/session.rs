@@ -0,0 +1,232 @@
+//! The Flavortown sessions a scrape runs on. The shop shows whichever region a session last
+//! switched to, so one session has to scrape its regions one after another, but separate
+//! sessions can scrape at the same time without switching the region under each other.
+//!
+//! `COOKIE` is a session that switches between regions as needed. `COOKIES` adds more, as JSON:
+//!
+//! ```json
+//! [{"cookie": "..."}, {"cookie": "...", "region": "UK"}]
+//! ```
+//!
+//! A session with a `region` only ever scrapes that region, so a daemon only switches it once.
+//! Regions without a session of their own are shared out between the others.
+//!
+//! A session found logged out before a scrape starts leaves its regions to the others. One that
+//! gets logged out partway through has its unscraped regions carried over from the last snapshot.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CONFIG;
+use crate::fetch::{FETCHER, FetchRequest, FetchResponse, SessionExpired};
+use crate::scraper::Region;
+use color_eyre::{Result, eyre::eyre};
+use log::debug;
+use once_cell::sync::Lazy;
+use reqwest::Url;
+use scraper::{Html, Selector};
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionConfig {
+    pub cookie: String,
+    /// Region code this session is dedicated to.
+    #[serde(default)]
+    pub region: Option<String>,
+}
+
+pub struct Session {
+    /// Shows up in logs.
+    pub name: String,
+    cookie: Option<String>,
+    fixed_region: Option<Region>,
+    /// The CSRF token lives as long as the session, so a long-running process only needs to
+    /// fetch it again once Flavortown rejects it.
+    csrf_token: Mutex<Option<String>>,
+    last_switch: Mutex<Option<Instant>>,
+    /// The region the shop was last seen showing this session.
+    current_region: Mutex<Option<Region>>,
+}
+
+fn csrf_token(page: &str) -> Option<String> {
+    Html::parse_document(page)
+        .select(&Selector::parse("meta[name=\"csrf-token\"]").unwrap())
+        .next()
+        .and_then(|e| e.attr("content"))
+        .map(String::from)
+}
+
+impl Session {
+    fn new(name: String, cookie: Option<String>, fixed_region: Option<Region>) -> Self {
+        Self {
+            name,
+            cookie,
+            fixed_region,
+            csrf_token: Mutex::new(None),
+            last_switch: Mutex::new(None),
+            current_region: Mutex::new(None),
+        }
+    }
+
+    /// Sends `request` with the session's cookie. The cookie isn't recorded with the request, so
+    /// fixtures don't leak it. A [`SessionExpired`] says which session it was.
+    async fn fetch(&self, request: FetchRequest) -> Result<FetchResponse> {
+        let request = match &self.cookie {
+            Some(cookie) => request.header("Cookie", cookie),
+            None => request,
+        };
+        let url = request.url.clone();
+        FETCHER.fetch(&request).await?.ensure_ok(&url).map_err(|e| {
+            match e.downcast::<SessionExpired>() {
+                Ok(expired) => SessionExpired {
+                    session: Some(self.name.clone()),
+                    ..expired
+                }
+                .into(),
+                Err(e) => e,
+            }
+        })
+    }
+
+    pub async fn get(&self, url: Url) -> Result<String> {
+        Ok(self.fetch(FetchRequest::get(url)).await?.body)
+    }
+
+    pub fn is_on(&self, region: &Region) -> bool {
+        self.current_region.lock().unwrap().as_ref() == Some(region)
+    }
+
+    /// Remembers which region the shop page just showed, or that it showed something unexpected.
+    pub fn saw_region(&self, region: Option<&Region>) {
+        *self.current_region.lock().unwrap() = region.cloned();
+    }
+
+    /// Fetches the shop page, keeping its CSRF token for the next region switch.
+    pub async fn shop_page(&self) -> Result<String> {
+        let page = self.get(CONFIG.base_url.join("shop")?).await?;
+        if let Some(token) = csrf_token(&page) {
+            *self.csrf_token.lock().unwrap() = Some(token);
+        }
+        Ok(page)
+    }
+
+    async fn set_region(&self, region: &Region, csrf_token: &str) -> Result<()> {
+        let url = CONFIG.base_url.join("shop/update_region")?;
+        let req = FetchRequest::patch_form(url, &[("region", region.code())])
+            .header("X-CSRF-Token", csrf_token);
+        self.fetch(req).await?;
+        Ok(())
+    }
+
+    /// Switching regions back to back looks nothing like a person browsing, so a session's
+    /// switches are spaced at least `REGION_SWITCH_DELAY_MS` apart.
+    async fn wait_before_region_switch(&self) {
+        let last = *self.last_switch.lock().unwrap();
+        if let Some(last) = last {
+            let delay = Duration::from_millis(CONFIG.region_switch_delay_ms);
+            tokio::time::sleep(delay.saturating_sub(last.elapsed())).await;
+        }
+        *self.last_switch.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub async fn switch_to(&self, region: &Region) -> Result<()> {
+        debug!("Switching {} to {region}", self.name);
+        self.saw_region(None);
+        self.wait_before_region_switch().await;
+
+        let cached = self.csrf_token.lock().unwrap().clone();
+        if let Some(token) = cached {
+            match self.set_region(region, &token).await {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("Cached CSRF token was rejected ({e}) - fetching a new one"),
+            }
+        }
+
+        let page = self.get(CONFIG.base_url.join("shop")?).await?;
+        let token = csrf_token(&page).ok_or_else(|| eyre!("Failed to find csrf-token"))?;
+        self.set_region(region, &token).await?;
+        *self.csrf_token.lock().unwrap() = Some(token);
+        Ok(())
+    }
+}
+
+pub static SESSIONS: Lazy<Vec<Session>> = Lazy::new(|| {
+    let mut sessions: Vec<_> = CONFIG
+        .cookies
+        .iter()
+        .enumerate()
+        .map(|(i, config)| {
+            let region = config.region.as_ref().map(|code| {
+                Region::from_code(code)
+                    .unwrap_or_else(|| panic!("invalid region {code:?} in COOKIES"))
+            });
+            let name = match &region {
+                Some(region) => format!("COOKIES #{i} ({})", region.code()),
+                None => format!("COOKIES #{i}"),
+            };
+            Session::new(name, Some(config.cookie.clone()), region)
+        })
+        .collect();
+
+    // without any cookie there's still a session, for replays. Live requests on it are refused.
+    if CONFIG.cookie.is_some() || sessions.is_empty() {
+        sessions.insert(
+            0,
+            Session::new("COOKIE".into(), CONFIG.cookie.clone(), None),
+        );
+    }
+    sessions
+});
+
+/// Which of `regions` each session scrapes, in the same order. Regions go to a session
+/// dedicated to them if there is one, and are otherwise dealt out to the sessions that aren't
+/// dedicated to any region. Sessions named in `expired` get nothing, so their regions are dealt
+/// out too. If every live session is dedicated, the remaining regions are left out.
+pub fn assign_regions(
+    regions: &[Region],
+    expired: &[String],
+) -> Vec<(&'static Session, Vec<Region>)> {
+    let live = |i: &usize| !expired.contains(&SESSIONS[*i].name);
+    let mut assigned: Vec<_> = SESSIONS.iter().map(|s| (s, Vec::new())).collect();
+    let shared: Vec<_> = (0..SESSIONS.len())
+        .filter(live)
+        .filter(|&i| SESSIONS[i].fixed_region.is_none())
+        .collect();
+    let mut next_shared = 0;
+
+    for region in regions {
+        let dedicated = (0..SESSIONS.len())
+            .filter(live)
+            .find(|&i| SESSIONS[i].fixed_region.as_ref() == Some(region));
+        let session = dedicated.or_else(|| {
+            let i = *shared.get(next_shared % shared.len().max(1))?;
+            next_shared += 1;
+            Some(i)
+        });
+        if let Some(i) = session {
+            assigned[i].1.push(region.clone());
+        }
+    }
+
+    assigned.retain(|(_, regions)| !regions.is_empty());
+    assigned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn expired_sessions_get_no_regions() {
+        testing::init();
+        let regions: Vec<_> = ["US", "UK"].map(|c| Region::from_code(c).unwrap()).into();
+
+        let assigned = assign_regions(&regions, &[]);
+        assert_eq!(assigned.len(), 1);
+        assert_eq!(assigned[0].0.name, "COOKIE");
+        assert_eq!(assigned[0].1, regions);
+
+        assert!(assign_regions(&regions, &["COOKIE".to_string()]).is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+//! Slack's Web API, for Slack sinks with a bot token instead of a webhook URL. Unlike webhooks it
+//! can reply in threads and edit messages, which both need the `ts` Slack gives every message.
+//!
+//! Calls are rendered ahead of time like every other message, before the messages they refer to
+//! have been posted. So each posted message gets a key, its `ts` is stored under that key in
+//! `slack.sled` once Slack has accepted it, and calls refer to other messages by key. What a
+//! message announces is only recorded once it's been posted too, so a call that's never sent
+//! can't make later runs edit the wrong message. Until then, the queued post itself is what later
+//! runs correct.
+//!
+//! A post whose key already has a `ts` isn't made again, so a call that's retried because the
+//! tracker stopped before the outbox heard back only goes out once. Only a crash while the
+//! request itself is in flight can still post twice.
+//!
+//! Queued calls don't contain the token. It's looked up by the sink's name when the call is
+//! sent, so a rotated token applies to calls that were already waiting.
+
+use crate::config::CONFIG;
+use crate::diff::FieldChange;
+use crate::notify::{self, Payload};
+use crate::outbox::{self, Sent};
+use crate::retry::{self, RetryPolicy};
+use crate::scraper::{CLIENT, ShopItem, ShopItemId};
+use color_eyre::{Result, eyre::eyre};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use slack_morphism::prelude::*;
+use sled::{Config, Db, Tree};
+use time_format::TimeStamp;
+
+const SLACK_DB_PATH: &str = "slack.sled";
+
+static SLACK_DB: Lazy<Db> = Lazy::new(|| {
+    Config::new()
+        .path(CONFIG.storage_path.join(SLACK_DB_PATH))
+        .open()
+        .unwrap()
+});
+
+/// Message key -> the message's `ts`, once it's been posted.
+fn posted() -> Result<Tree> {
+    Ok(SLACK_DB.open_tree("posted")?)
+}
+
+/// `channel/item ID` -> the [`Announcement`] of the item's latest change in that channel.
+fn announcements() -> Result<Tree> {
+    Ok(SLACK_DB.open_tree("announcements")?)
+}
+
+/// A posted message about a change of one item, which later runs can correct.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Announcement {
+    pub key: String,
+    pub at: TimeStamp,
+    /// The item before the announced change.
+    pub before: ShopItem,
+    pub changes: Vec<FieldChange>,
+}
+
+fn announcement_key(channel: &str, item_id: ShopItemId) -> String {
+    format!("{channel}/{item_id}")
+}
+
+/// The latest announcement of `item_id` in `channel`. Posts still waiting in the outbox count,
+/// since they go out before anything rendered now, so an edit of one goes out after it.
+pub fn announcement(channel: &str, item_id: ShopItemId) -> Result<Option<Announcement>> {
+    let queued = outbox::queued()?
+        .into_iter()
+        .rev()
+        .find_map(|message| match message.payload {
+            Payload::SlackApi(Call {
+                channel: queued_channel,
+                action:
+                    Action::Post {
+                        key,
+                        record: Some(record),
+                        ..
+                    },
+                ..
+            }) if queued_channel == channel && record.item_id() == item_id => Some((key, record)),
+            _ => None,
+        });
+    if let Some((key, record)) = queued {
+        return Ok(match record {
+            ItemRecord::Announce {
+                at,
+                before,
+                changes,
+                ..
+            } => Some(Announcement {
+                key,
+                at,
+                before: *before,
+                changes,
+            }),
+            ItemRecord::Forget { .. } => None,
+        });
+    }
+
+    announcements()?
+        .get(announcement_key(channel, item_id))?
+        .map(|bytes| Ok(serde_json::from_slice(&bytes)?))
+        .transpose()
+}
+
+/// A key for a message that hasn't been posted yet.
+pub fn new_key() -> Result<String> {
+    Ok(SLACK_DB.generate_id()?.to_string())
+}
+
+fn posted_ts(key: &str) -> Result<Option<SlackTs>> {
+    Ok(posted()?
+        .get(key)?
+        .map(|ts| SlackTs(String::from_utf8_lossy(&ts).into_owned())))
+}
+
+/// What a message means for the item it's about, recorded once it has been posted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ItemRecord {
+    /// The message announces `changes`, so later runs can correct it.
+    Announce {
+        item_id: ShopItemId,
+        at: TimeStamp,
+        before: Box<ShopItem>,
+        changes: Vec<FieldChange>,
+    },
+    /// The item was deleted, so there's nothing left to correct.
+    Forget { item_id: ShopItemId },
+}
+
+impl ItemRecord {
+    const fn item_id(&self) -> ShopItemId {
+        match self {
+            Self::Announce { item_id, .. } | Self::Forget { item_id } => *item_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    /// Posts a new message under `key`, in the thread of the message with the key `thread` if
+    /// there is one.
+    Post {
+        key: String,
+        thread: Option<String>,
+        #[serde(default)]
+        record: Option<ItemRecord>,
+    },
+    /// Replaces the content of the message posted under `key`.
+    Update { key: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Call {
+    pub channel: String,
+    pub action: Action,
+    pub content: SlackMessageContent,
+}
+
+/// The part of a Web API response we care about. Slack answers 200 for failed calls too.
+#[derive(Deserialize)]
+struct ApiResponse {
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    ts: Option<SlackTs>,
+}
+
+async fn call_method(method: &str, token: &str, request: &impl Serialize) -> Result<ApiResponse> {
+    let url = format!(
+        "{}/{method}",
+        CONFIG.slack_api_url.as_str().trim_end_matches('/')
+    );
+    let response = retry::send(&RetryPolicy::SLACK, || {
+        CLIENT.post(url.clone()).bearer_auth(token).json(request)
+    })
+    .await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(eyre!("Slack answered {status} for {method}: {body}"));
+    }
+    Ok(response.json().await?)
+}
+
+fn record(channel: &str, key: &str, record: &ItemRecord) -> Result<()> {
+    match record {
+        ItemRecord::Announce {
+            item_id,
+            at,
+            before,
+            changes,
+        } => {
+            let announcement = Announcement {
+                key: key.to_string(),
+                at: *at,
+                before: (**before).clone(),
+                changes: changes.clone(),
+            };
+            announcements()?.insert(
+                announcement_key(channel, *item_id),
+                serde_json::to_vec(&announcement)?,
+            )?;
+        }
+        ItemRecord::Forget { item_id } => {
+            announcements()?.remove(announcement_key(channel, *item_id))?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends `call` for the sink named `sink`.
+pub async fn send(sink: &str, call: &Call) -> Result<Sent> {
+    let Some(token) = notify::slack_token(sink) else {
+        return Ok(Sent::Rejected(format!(
+            "{sink} has no Slack token any more"
+        )));
+    };
+    let channel = SlackChannelId(call.channel.clone());
+
+    match &call.action {
+        Action::Post {
+            key,
+            thread,
+            record: item_record,
+        } => {
+            // the tracker stopped after Slack took the post but before the outbox heard of it.
+            if posted_ts(key)?.is_some() {
+                info!("A Slack message was already posted, not posting it again");
+                if let Some(item_record) = item_record {
+                    record(&call.channel, key, item_record)?;
+                }
+                SLACK_DB.flush()?;
+                return Ok(Sent::Delivered);
+            }
+            let thread_ts = match thread {
+                Some(thread) => {
+                    let ts = posted_ts(thread)?;
+                    if ts.is_none() {
+                        warn!(
+                            "The thread for a Slack message was never posted - posting it on its own"
+                        );
+                    }
+                    ts
+                }
+                None => None,
+            };
+            let request = SlackApiChatPostMessageRequest::new(channel, call.content.clone())
+                .opt_thread_ts(thread_ts);
+            let response = call_method("chat.postMessage", token, &request).await?;
+            // Slack understood the call and said no, e.g. `channel_not_found` or `invalid_auth`.
+            if !response.ok {
+                return Ok(Sent::Rejected(response.error.unwrap_or_default()));
+            }
+            let ts = response
+                .ts
+                .ok_or_else(|| eyre!("Slack didn't say what it posted the message as"))?;
+            posted()?.insert(key, ts.0.as_bytes())?;
+            if let Some(item_record) = item_record {
+                record(&call.channel, key, item_record)?;
+            }
+            SLACK_DB.flush()?;
+            Ok(Sent::Delivered)
+        }
+        Action::Update { key } => {
+            let Some(ts) = posted_ts(key)? else {
+                return Ok(Sent::Rejected(
+                    "the message to edit was never posted".to_string(),
+                ));
+            };
+            let request = SlackApiChatUpdateRequest::new(channel, call.content.clone(), ts);
+            let response = call_method("chat.update", token, &request).await?;
+            if !response.ok {
+                return Ok(Sent::Rejected(response.error.unwrap_or_default()));
+            }
+            Ok(Sent::Delivered)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::compute_changes;
+    use crate::testing::{self, SLACK_SINK, SLACK_STUB};
+
+    fn post(channel: &str, record: ItemRecord) -> Call {
+        Call {
+            channel: channel.to_string(),
+            action: Action::Post {
+                key: new_key().unwrap(),
+                thread: None,
+                record: Some(record),
+            },
+            content: SlackMessageContent::new().with_text("Sticker".to_string()),
+        }
+    }
+
+    fn announce(before: &ShopItem, after: &ShopItem) -> ItemRecord {
+        ItemRecord::Announce {
+            item_id: before.id,
+            at: time_format::now().unwrap(),
+            before: Box::new(before.clone()),
+            changes: compute_changes(before, after),
+        }
+    }
+
+    #[tokio::test]
+    async fn announcements_are_recorded_once_posted() {
+        testing::init();
+        let channel = "C0RECORD";
+        let v1 = testing::item(1, "Sticker", &[("US", 100)]);
+        let v2 = testing::item(1, "Sticker", &[("US", 80)]);
+        let v3 = testing::item(1, "Sticker", &[("US", 60)]);
+
+        let first = post(channel, announce(&v1, &v2));
+        assert!(announcement(channel, 1).unwrap().is_none());
+        assert!(matches!(
+            send(SLACK_SINK, &first).await.unwrap(),
+            Sent::Delivered
+        ));
+        let recorded = announcement(channel, 1).unwrap().unwrap();
+        let Action::Post { key, .. } = &first.action else {
+            unreachable!()
+        };
+        assert_eq!(&recorded.key, key);
+        assert_eq!(recorded.before, v1);
+
+        // rejected calls, here for a sink whose token is gone, leave the last one in place.
+        let second = post(channel, announce(&v2, &v3));
+        assert!(matches!(
+            send("removed-sink", &second).await.unwrap(),
+            Sent::Rejected(_)
+        ));
+        let forget = post(channel, ItemRecord::Forget { item_id: 1 });
+        assert!(matches!(
+            send("removed-sink", &forget).await.unwrap(),
+            Sent::Rejected(_)
+        ));
+        assert_eq!(&announcement(channel, 1).unwrap().unwrap().key, key);
+
+        assert!(matches!(
+            send(SLACK_SINK, &forget).await.unwrap(),
+            Sent::Delivered
+        ));
+        assert!(announcement(channel, 1).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn posts_are_only_made_once() {
+        testing::init();
+        let channel = "C0ONCE";
+        let v1 = testing::item(2, "Pin", &[("US", 100)]);
+        let v2 = testing::item(2, "Pin", &[("US", 80)]);
+        let call = post(channel, announce(&v1, &v2));
+
+        // sent again, as after the tracker stopped before it could take the call off the outbox.
+        for _ in 0..2 {
+            assert!(matches!(
+                send(SLACK_SINK, &call).await.unwrap(),
+                Sent::Delivered
+            ));
+        }
+        assert_eq!(SLACK_STUB.calls(channel).len(), 1);
+        assert!(announcement(channel, 2).unwrap().is_some());
+    }
+}
@@ -0,0 +1,508 @@
+//! The `sqlite` storage backend: every snapshot and diff in one database, split into a table per
+//! kind of data so history can be queried without loading whole snapshots.
+//!
+//! Each snapshot is written in a single transaction, so a crash leaves either all of it or none.
+//! The layout's version is kept in `PRAGMA user_version`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::CONFIG;
+use crate::diff::ItemDiff;
+use crate::history::PricePoint;
+use crate::scraper::{Accessory, Region, RegionInfo, ShopItem, ShopItemId};
+use crate::storage::{self, Snapshot};
+use crate::store::{ItemHistory, SnapshotStore};
+use color_eyre::{Result, eyre::eyre};
+use log::{info, warn};
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use time_format::TimeStamp;
+
+const DATABASE_PATH: &str = "snapshots.sqlite3";
+const LAYOUT_VERSION: i64 = 1;
+
+const LAYOUT: &str = "
+CREATE TABLE snapshots (
+    taken_at INTEGER PRIMARY KEY,
+    tracker_version TEXT,
+    -- how the run went, as the JSON `ScrapeInfo`
+    scrape TEXT
+);
+CREATE TABLE regions (
+    taken_at INTEGER NOT NULL REFERENCES snapshots ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    code TEXT NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (taken_at, code)
+);
+CREATE TABLE items (
+    taken_at INTEGER NOT NULL REFERENCES snapshots ON DELETE CASCADE,
+    item_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL,
+    long_description TEXT,
+    image_url TEXT NOT NULL,
+    image_id INTEGER NOT NULL,
+    achievement_lock TEXT,
+    PRIMARY KEY (taken_at, item_id)
+);
+CREATE INDEX items_by_id ON items (item_id, taken_at);
+CREATE TABLE prices (
+    taken_at INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    PRIMARY KEY (taken_at, item_id, region),
+    FOREIGN KEY (taken_at, item_id) REFERENCES items ON DELETE CASCADE
+);
+CREATE INDEX prices_by_item ON prices (item_id, taken_at);
+-- one row per item. NULL when the item's page showed no stock count, which is how the shop shows
+-- unlimited stock, or when the page couldn't be read and nothing was carried over.
+CREATE TABLE stock (
+    taken_at INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    remaining_stock INTEGER,
+    PRIMARY KEY (taken_at, item_id),
+    FOREIGN KEY (taken_at, item_id) REFERENCES items ON DELETE CASCADE
+);
+CREATE INDEX stock_by_item ON stock (item_id, taken_at);
+CREATE TABLE accessories (
+    taken_at INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    accessory_id INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    PRIMARY KEY (taken_at, item_id, accessory_id),
+    FOREIGN KEY (taken_at, item_id) REFERENCES items ON DELETE CASCADE
+);
+CREATE TABLE accessory_prices (
+    taken_at INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    accessory_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    PRIMARY KEY (taken_at, item_id, accessory_id, region),
+    FOREIGN KEY (taken_at, item_id, accessory_id) REFERENCES accessories ON DELETE CASCADE
+);
+-- regions whose data for the item was carried over from the previous snapshot
+CREATE TABLE incomplete_regions (
+    taken_at INTEGER NOT NULL,
+    item_id INTEGER NOT NULL,
+    region TEXT NOT NULL,
+    PRIMARY KEY (taken_at, item_id, region),
+    FOREIGN KEY (taken_at, item_id) REFERENCES items ON DELETE CASCADE
+);
+-- the diff that led to each snapshot, as the JSON `ItemDiff`. A diff is written before its
+-- snapshot, so it doesn't reference it.
+CREATE TABLE diffs (
+    taken_at INTEGER PRIMARY KEY,
+    diff TEXT NOT NULL
+);
+";
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+fn region(code: String) -> Result<Region> {
+    Region::from_code(&code).ok_or_else(|| eyre!("invalid region {code:?} in the database"))
+}
+
+impl SqliteStore {
+    pub fn open() -> Result<Self> {
+        let conn = Connection::open(CONFIG.storage_path.join(DATABASE_PATH))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        // `export` and `serve` read the database next to the daemon writing it.
+        conn.busy_timeout(Duration::from_secs(10))?;
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => conn.execute_batch(&format!(
+                "BEGIN; {LAYOUT} PRAGMA user_version = {LAYOUT_VERSION}; COMMIT;"
+            ))?,
+            LAYOUT_VERSION => {}
+            _ => {
+                return Err(eyre!(
+                    "the database has layout version {version}, but this tracker only \
+                     understands up to {LAYOUT_VERSION} - upgrade the tracker"
+                ));
+            }
+        }
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn insert(tx: &Transaction, at: TimeStamp, snapshot: &Snapshot) -> Result<()> {
+        tx.execute(
+            "INSERT INTO snapshots (taken_at, tracker_version, scrape) VALUES (?1, ?2, ?3)",
+            params![
+                at,
+                snapshot.tracker_version,
+                snapshot
+                    .scrape
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?
+            ],
+        )?;
+
+        let mut region_row = tx.prepare_cached(
+            "INSERT INTO regions (taken_at, position, code, name) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (position, info) in snapshot.regions.iter().enumerate() {
+            region_row.execute(params![at, position, info.code, info.name])?;
+        }
+
+        let mut item_row = tx.prepare_cached(
+            "INSERT INTO items (taken_at, item_id, position, title, description, \
+             long_description, image_url, image_id, achievement_lock) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        let mut price_row = tx.prepare_cached(
+            "INSERT INTO prices (taken_at, item_id, region, price) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        let mut stock_row = tx.prepare_cached(
+            "INSERT INTO stock (taken_at, item_id, remaining_stock) VALUES (?1, ?2, ?3)",
+        )?;
+        let mut accessory_row = tx.prepare_cached(
+            "INSERT INTO accessories (taken_at, item_id, accessory_id, position, name) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        let mut accessory_price_row = tx.prepare_cached(
+            "INSERT INTO accessory_prices (taken_at, item_id, accessory_id, region, price) \
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        let mut incomplete_row = tx.prepare_cached(
+            "INSERT INTO incomplete_regions (taken_at, item_id, region) VALUES (?1, ?2, ?3)",
+        )?;
+
+        for (position, item) in snapshot.items.iter().enumerate() {
+            item_row.execute(params![
+                at,
+                item.id,
+                position,
+                item.title,
+                item.description,
+                item.long_description,
+                item.image_url.as_str(),
+                item.image_id,
+                item.achievement_lock,
+            ])?;
+            for (region, price) in &item.prices {
+                price_row.execute(params![at, item.id, region.code(), price])?;
+            }
+            stock_row.execute(params![at, item.id, item.remaining_stock])?;
+            for (position, accessory) in item.accessories.iter().enumerate() {
+                accessory_row.execute(params![
+                    at,
+                    item.id,
+                    accessory.id,
+                    position,
+                    accessory.name
+                ])?;
+                for (region, price) in &accessory.prices {
+                    accessory_price_row.execute(params![
+                        at,
+                        item.id,
+                        accessory.id,
+                        region.code(),
+                        price
+                    ])?;
+                }
+            }
+            for region in &item.incomplete_regions {
+                incomplete_row.execute(params![at, item.id, region.code()])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(conn: &Connection, at: TimeStamp) -> Result<Option<Snapshot>> {
+        let Some((tracker_version, scrape)) = conn
+            .query_row(
+                "SELECT tracker_version, scrape FROM snapshots WHERE taken_at = ?1",
+                [at],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<String>>(0)?,
+                        row.get::<_, Option<String>>(1)?,
+                    ))
+                },
+            )
+            .optional()?
+        else {
+            return Ok(None);
+        };
+
+        let regions = conn
+            .prepare_cached("SELECT code, name FROM regions WHERE taken_at = ?1 ORDER BY position")?
+            .query_map([at], |row| {
+                Ok(RegionInfo {
+                    code: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut prices: HashMap<ShopItemId, HashMap<Region, u32>> = HashMap::new();
+        let mut rows =
+            conn.prepare_cached("SELECT item_id, region, price FROM prices WHERE taken_at = ?1")?;
+        for row in rows.query_map([at], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))? {
+            let (item_id, code, price) = row?;
+            prices
+                .entry(item_id)
+                .or_default()
+                .insert(region(code)?, price);
+        }
+
+        let mut stock: HashMap<ShopItemId, Option<u32>> = HashMap::new();
+        let mut rows =
+            conn.prepare_cached("SELECT item_id, remaining_stock FROM stock WHERE taken_at = ?1")?;
+        for row in rows.query_map([at], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (item_id, remaining) = row?;
+            stock.insert(item_id, remaining);
+        }
+
+        let mut accessory_prices: HashMap<(ShopItemId, usize), HashMap<Region, u32>> =
+            HashMap::new();
+        let mut rows = conn.prepare_cached(
+            "SELECT item_id, accessory_id, region, price FROM accessory_prices WHERE taken_at = ?1",
+        )?;
+        for row in rows.query_map([at], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })? {
+            let (item_id, accessory_id, code, price) = row?;
+            accessory_prices
+                .entry((item_id, accessory_id))
+                .or_default()
+                .insert(region(code)?, price);
+        }
+
+        let mut accessories: HashMap<ShopItemId, Vec<Accessory>> = HashMap::new();
+        let mut rows = conn.prepare_cached(
+            "SELECT item_id, accessory_id, name FROM accessories WHERE taken_at = ?1 \
+             ORDER BY item_id, position",
+        )?;
+        for row in rows.query_map([at], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))? {
+            let (item_id, id, name) = row?;
+            accessories.entry(item_id).or_default().push(Accessory {
+                id,
+                name,
+                prices: accessory_prices.remove(&(item_id, id)).unwrap_or_default(),
+            });
+        }
+
+        let mut incomplete: HashMap<ShopItemId, Vec<Region>> = HashMap::new();
+        let mut rows = conn.prepare_cached(
+            "SELECT item_id, region FROM incomplete_regions WHERE taken_at = ?1 \
+             ORDER BY item_id, region",
+        )?;
+        for row in rows.query_map([at], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (item_id, code) = row?;
+            incomplete.entry(item_id).or_default().push(region(code)?);
+        }
+
+        let mut items = Vec::new();
+        let mut rows = conn.prepare_cached(
+            "SELECT item_id, title, description, long_description, image_url, image_id, \
+             achievement_lock FROM items WHERE taken_at = ?1 ORDER BY position",
+        )?;
+        for row in rows.query_map([at], |row| {
+            Ok((
+                row.get::<_, ShopItemId>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get::<_, String>(4)?,
+                row.get(5)?,
+                row.get(6)?,
+            ))
+        })? {
+            let (id, title, description, long_description, image_url, image_id, achievement_lock) =
+                row?;
+            items.push(ShopItem {
+                title,
+                description,
+                prices: prices.remove(&id).unwrap_or_default(),
+                image_url: image_url.parse()?,
+                image_id,
+                id,
+                long_description,
+                accessories: accessories.remove(&id).unwrap_or_default(),
+                remaining_stock: stock.remove(&id).flatten(),
+                achievement_lock,
+                incomplete_regions: incomplete.remove(&id).unwrap_or_default(),
+            });
+        }
+
+        Ok(Some(Snapshot {
+            tracker_version,
+            scrape: scrape.as_deref().map(serde_json::from_str).transpose()?,
+            regions,
+            items,
+        }))
+    }
+}
+
+impl SnapshotStore for SqliteStore {
+    fn load_latest(&self) -> Result<Option<Snapshot>> {
+        let conn = self.conn.lock().unwrap();
+        let latest: Option<TimeStamp> =
+            conn.query_row("SELECT MAX(taken_at) FROM snapshots", [], |row| row.get(0))?;
+        match latest {
+            Some(at) => Self::read(&conn, at),
+            None => Ok(None),
+        }
+    }
+
+    /// The latest snapshot is simply the newest one, so there's no pointer to move.
+    fn write(&self, at: TimeStamp, snapshot: &Snapshot) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let exists = tx
+            .query_row("SELECT 1 FROM snapshots WHERE taken_at = ?1", [at], |_| {
+                Ok(())
+            })
+            .optional()?
+            .is_some();
+        if !exists {
+            Self::insert(&tx, at, snapshot)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<TimeStamp>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT taken_at FROM snapshots ORDER BY taken_at")?;
+        let snapshots = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(snapshots)
+    }
+
+    fn get(&self, at: TimeStamp) -> Result<Option<Snapshot>> {
+        Self::read(&self.conn.lock().unwrap(), at)
+    }
+
+    fn item_history(&self, item_id: ShopItemId) -> Result<Option<ItemHistory>> {
+        let conn = self.conn.lock().unwrap();
+        let first_seen: Option<TimeStamp> = conn.query_row(
+            "SELECT MIN(taken_at) FROM items WHERE item_id = ?1",
+            [item_id],
+            |row| row.get(0),
+        )?;
+        let Some(first_seen) = first_seen else {
+            return Ok(None);
+        };
+        let last_in_stock = conn.query_row(
+            "SELECT MAX(taken_at) FROM stock WHERE item_id = ?1 \
+             AND (remaining_stock IS NULL OR remaining_stock != 0)",
+            [item_id],
+            |row| row.get(0),
+        )?;
+
+        let mut prices: BTreeMap<Region, Vec<PricePoint>> = BTreeMap::new();
+        let mut statement = conn.prepare(
+            "SELECT region, taken_at, price FROM prices WHERE item_id = ?1 ORDER BY taken_at",
+        )?;
+        for row in
+            statement.query_map([item_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        {
+            let (code, at, price) = row?;
+            prices
+                .entry(region(code)?)
+                .or_default()
+                .push(PricePoint { at, price });
+        }
+
+        Ok(Some(ItemHistory {
+            first_seen,
+            last_in_stock,
+            prices,
+        }))
+    }
+
+    fn write_diff(&self, at: TimeStamp, diff: &ItemDiff) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO diffs (taken_at, diff) VALUES (?1, ?2)",
+            params![at, serde_json::to_string(diff)?],
+        )?;
+        Ok(())
+    }
+
+    fn list_diffs(&self) -> Result<Vec<TimeStamp>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT taken_at FROM diffs ORDER BY taken_at")?;
+        let diffs = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(diffs)
+    }
+
+    fn get_diff(&self, at: TimeStamp) -> Result<Option<ItemDiff>> {
+        let diff: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT diff FROM diffs WHERE taken_at = ?1", [at], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(diff.as_deref().map(serde_json::from_str).transpose()?)
+    }
+}
+
+/// Copies every `snap_*.json` snapshot and stored diff in the storage directory into the
+/// database, so switching to the `sqlite` backend keeps the history and the feeds. Snapshots the
+/// database already has are skipped, so it can be run again after a few more runs on the `files`
+/// backend.
+pub fn import_snapshots() -> Result<()> {
+    let store = SqliteStore::open()?;
+    let existing: HashSet<TimeStamp> = store.list()?.into_iter().collect();
+
+    let mut imported = 0;
+    let mut skipped = 0;
+    let mut damaged = 0;
+    for (at, path) in storage::list_snapshots()? {
+        if existing.contains(&at) {
+            skipped += 1;
+            continue;
+        }
+        match storage::load_snapshot(&path) {
+            Ok(snapshot) => {
+                info!("Importing {}", path.display());
+                store.write(at, &snapshot)?;
+                imported += 1;
+            }
+            Err(e) => {
+                warn!("Not importing a damaged snapshot: {e:#}");
+                damaged += 1;
+            }
+        }
+    }
+
+    let existing: HashSet<TimeStamp> = store.list_diffs()?.into_iter().collect();
+    let mut diffs = 0;
+    for (at, path) in storage::list_diffs()? {
+        if existing.contains(&at) {
+            continue;
+        }
+        match storage::load_diff(&path) {
+            Ok(diff) => {
+                store.write_diff(at, &diff)?;
+                diffs += 1;
+            }
+            Err(e) => warn!("Not importing a damaged diff {}: {e:#}", path.display()),
+        }
+    }
+
+    println!(
+        "Imported {imported} snapshot(s) into {DATABASE_PATH}, {skipped} were already there, \
+         {damaged} damaged. Imported {diffs} diff(s)"
+    );
+    Ok(())
+}
@@ -1,51 +1,507 @@
-use std::fs::{self, File};
-use std::path::Path;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
 
 use crate::config::CONFIG;
-use crate::scraper::{CLIENT, ShopItems};
+use crate::diff::ItemDiff;
+use crate::migrate;
+use crate::retry::{self, RetryPolicy};
+use crate::scraper::{CLIENT, DetailStrategy, RegionInfo, ShopItems, known_regions};
 
-use color_eyre::{Result, eyre::eyre};
+use color_eyre::{
+    Result,
+    eyre::{WrapErr, eyre},
+};
 use dashmap::DashMap;
-use log::debug;
+use flate2::{Compression, read::GzDecoder, write::GzEncoder};
+use log::{debug, warn};
 use once_cell::sync::Lazy;
 use reqwest::{
     Url,
-    blocking::multipart::{Form, Part},
+    multipart::{Form, Part},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
 use sled::{Config, Db};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use time_format::TimeStamp;
 
 const LATEST_SNAPSHOT_POINTER_PATH: &str = "latest-snapshot.ptr";
 const CDN_CACHE_PATH: &str = "cdn-cache.sled";
+const OBJECTS_PATH: &str = "objects.sled";
+const LOCK_PATH: &str = "tracker.lock";
+const DIFFS_PATH: &str = "diffs";
+
+/// Takes an exclusive lock on the storage directory, so a one-off run started next to the daemon
+/// can't scrape and write snapshots at the same time as it. Released when the file is dropped.
+pub fn lock_storage() -> Result<File> {
+    fs::create_dir_all(&CONFIG.storage_path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(CONFIG.storage_path.join(LOCK_PATH))?;
+    file.try_lock().map_err(|e| match e {
+        TryLockError::WouldBlock => eyre!(
+            "another tracker is already using {}",
+            CONFIG.storage_path.display()
+        ),
+        TryLockError::Error(e) => e.into(),
+    })?;
+    Ok(file)
+}
+
+/// Everything one run saw of the shop. On disk it's wrapped in a versioned envelope, see
+/// [`crate::migrate`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The version of the tracker that took the snapshot. Unknown for snapshots older than the
+    /// envelope.
+    #[serde(default)]
+    pub tracker_version: Option<String>,
+    #[serde(default)]
+    pub scrape: Option<ScrapeInfo>,
+    /// The regions the shop had, in dropdown order.
+    #[serde(default = "known_regions")]
+    pub regions: Vec<RegionInfo>,
+    pub items: ShopItems,
+}
+
+/// How the run that took a snapshot went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrapeInfo {
+    pub started_at: TimeStamp,
+    pub duration_ms: u64,
+    pub detail_strategy: DetailStrategy,
+    /// Everything that was scraped around, see `incomplete_regions`.
+    pub errors: Vec<String>,
+}
+
+/// The envelope snapshots are written in.
+#[derive(Serialize)]
+struct SnapshotFile<'a> {
+    schema_version: u32,
+    #[serde(flatten)]
+    snapshot: &'a Snapshot,
+}
+
+/// Replaces `path` with `contents` in one go: they're written and synced to a temp file next to
+/// it, which is then renamed over it. A crash or a full disk leaves either the old file or the
+/// new one, never half of one.
+pub fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
 
-pub fn load_latest_snapshot() -> Result<Option<ShopItems>> {
-    match std::fs::read_to_string(CONFIG.storage_path.join(LATEST_SNAPSHOT_POINTER_PATH)) {
-        Ok(snap_ptr) => Ok(Some(serde_json::from_reader(File::open(
-            CONFIG.storage_path.join(snap_ptr),
-        )?)?)),
-        Err(_) => Ok(None),
+    let written = (|| {
+        let mut file = File::create(&tmp)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_all()
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(eyre!(e).wrap_err(format!("writing {}", path.display())));
     }
+    fs::rename(&tmp, path)?;
+
+    // the rename only survives a crash once the directory entry is on disk too.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
-pub fn write_new_snapshot(items: &ShopItems) -> Result<()> {
-    let ts = time_format::now().unwrap();
-    let snap_path = format!(
-        "snap_{}.json",
-        time_format::strftime_utc("%Y-%m-%d-%H:%M:%S", ts).unwrap()
-    );
+/// Each snapshot has its SHA-256 in a `.sha256` file next to it. Snapshots from before checksums
+/// don't, and are only checked for being valid JSON.
+fn checksum_path(snapshot_path: &Path) -> PathBuf {
+    let mut path = OsString::from(snapshot_path.as_os_str());
+    path.push(".sha256");
+    PathBuf::from(path)
+}
+
+/// Writes a snapshot file and its checksum, compressed if `path` is a `.json.gz` one. The old
+/// checksum goes first, so a crash while rewriting a snapshot can't leave it next to a checksum
+/// that doesn't match.
+pub fn write_snapshot_file(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let json = snapshot_to_json(snapshot)?;
+    let contents = if is_packed(path) {
+        pack(&json)?
+    } else {
+        json.into_bytes()
+    };
+    let checksum = checksum_path(path);
+    if checksum.exists() {
+        fs::remove_file(&checksum)?;
+    }
+    write_atomically(path, &contents)?;
+    write_atomically(&checksum, hex::encode(Sha256::digest(&contents)))
+}
+
+/// Deletes a snapshot file and its checksum.
+pub fn remove_snapshot_file(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    match fs::remove_file(checksum_path(path)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// How long to wait for another tracker to close a [`SharedDb`].
+const SHARED_DB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A sled database that's only open while something in this process uses it. sled locks a
+/// database for as long as it's open, so this is what lets `serve` and `export` read it next to
+/// the daemon. Trees opened from it keep it locked too, so they mustn't outlive the handle.
+pub struct SharedDb {
+    path: &'static str,
+    db: Mutex<Weak<Db>>,
+}
+
+impl SharedDb {
+    pub const fn new(path: &'static str) -> Self {
+        Self {
+            path,
+            db: Mutex::new(Weak::new()),
+        }
+    }
+
+    /// The database, opened unless something else in this process has it open already. Waits
+    /// for another tracker using it to let go.
+    pub fn open(&self) -> Result<Arc<Db>> {
+        let mut shared = self.db.lock().unwrap();
+        if let Some(db) = shared.upgrade() {
+            return Ok(db);
+        }
+        let path = CONFIG.storage_path.join(self.path);
+        let started = Instant::now();
+        let db = loop {
+            match Config::new().path(&path).open() {
+                Ok(db) => break Arc::new(db),
+                // sled reports the lock being held as an I/O error.
+                Err(sled::Error::Io(e)) if started.elapsed() < SHARED_DB_TIMEOUT => {
+                    debug!("Waiting for {}: {e}", path.display());
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => {
+                    return Err(e).wrap_err_with(|| format!("failed to open {}", path.display()));
+                }
+            }
+        };
+        *shared = Arc::downgrade(&db);
+        Ok(db)
+    }
+}
+
+/// Item records of compressed snapshots, keyed by the SHA-256 of their JSON. An item that looks
+/// the same in many snapshots is only stored once.
+pub static OBJECTS_DB: SharedDb = SharedDb::new(OBJECTS_PATH);
+
+fn is_packed(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.ends_with(".json.gz"))
+}
+
+/// Turns a snapshot into its compressed form: the items go into [`OBJECTS_DB`] and the file
+/// only lists their hashes, as `item_refs`.
+fn pack(json: &str) -> Result<Vec<u8>> {
+    let mut snapshot: Value = serde_json::from_str(json)?;
+    let object = snapshot
+        .as_object_mut()
+        .ok_or_else(|| eyre!("snapshot isn't an object"))?;
+    let Some(Value::Array(items)) = object.remove("items") else {
+        return Err(eyre!("snapshot has no items"));
+    };
+
+    let objects = OBJECTS_DB.open()?;
+    let mut refs = Vec::with_capacity(items.len());
+    for item in items {
+        let bytes = serde_json::to_vec(&item)?;
+        let hash = Sha256::digest(&bytes);
+        objects.insert(hash.as_slice(), bytes)?;
+        refs.push(hex::encode(hash));
+    }
+    // the file mustn't refer to records that could still be lost.
+    objects.flush()?;
+    object.insert("item_refs".into(), json!(refs));
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    serde_json::to_writer(&mut encoder, &snapshot)?;
+    Ok(encoder.finish()?)
+}
+
+/// The inverse of [`pack`].
+fn unpack(bytes: &[u8]) -> Result<Value> {
+    let mut snapshot: Value = serde_json::from_reader(GzDecoder::new(bytes))?;
+    if let Some(object) = snapshot.as_object_mut()
+        && let Some(refs) = object.remove("item_refs")
+    {
+        let objects = OBJECTS_DB.open()?;
+        let items = serde_json::from_value::<Vec<String>>(refs)?
+            .iter()
+            .map(|hash| {
+                let record = objects
+                    .get(hex::decode(hash)?)?
+                    .ok_or_else(|| eyre!("item record {hash} is missing"))?;
+                Ok(serde_json::from_slice(&record)?)
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        object.insert("items".into(), Value::Array(items));
+    }
+    Ok(snapshot)
+}
+
+/// Deletes the item records no compressed snapshot refers to any more, returning how many were
+/// removed. Nothing is removed if any compressed snapshot can't be read, as its records might be
+/// the only copy left.
+pub fn remove_unused_objects() -> Result<usize> {
+    let mut used = HashSet::new();
+    for (_, path) in list_snapshots()? {
+        if !is_packed(&path) {
+            continue;
+        }
+        let snapshot: Value = serde_json::from_reader(GzDecoder::new(File::open(&path)?))
+            .wrap_err_with(|| format!("in {}", path.display()))?;
+        if let Some(refs) = snapshot.get("item_refs") {
+            for hash in serde_json::from_value::<Vec<String>>(refs.clone())? {
+                used.insert(hex::decode(hash)?);
+            }
+        }
+    }
+
+    let objects = OBJECTS_DB.open()?;
+    let mut removed = 0;
+    for key in objects.iter().keys() {
+        let key = key?;
+        if !used.contains(key.as_ref()) {
+            objects.remove(key)?;
+            removed += 1;
+        }
+    }
+    objects.flush()?;
+    Ok(removed)
+}
+
+/// The file name the pointer holds, if it can be read.
+pub fn latest_snapshot_name() -> Option<String> {
+    let name = fs::read_to_string(CONFIG.storage_path.join(LATEST_SNAPSHOT_POINTER_PATH)).ok()?;
+    Some(name.trim().to_string())
+}
+
+/// The snapshot named by the pointer. If the pointer is missing or damaged, or names a snapshot
+/// that is, falls back to the newest snapshot that's intact.
+pub fn load_latest_snapshot() -> Result<Option<Snapshot>> {
+    let pointer = CONFIG.storage_path.join(LATEST_SNAPSHOT_POINTER_PATH);
+    match fs::read_to_string(&pointer) {
+        Ok(name) => match load_pointed_snapshot(name.trim()) {
+            Ok(snapshot) => return Ok(Some(snapshot)),
+            Err(e) => warn!("{} is unusable: {e:#}", pointer.display()),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Couldn't read {}: {e}", pointer.display()),
+    }
+    newest_intact_snapshot()
+}
+
+fn load_pointed_snapshot(name: &str) -> Result<Snapshot> {
+    if parse_snapshot_timestamp(name).is_none() {
+        return Err(eyre!("{name:?} isn't a snapshot file name"));
+    }
+    load_snapshot(&CONFIG.storage_path.join(name))
+}
+
+/// Used when the pointer can't be trusted. `None` only if there are no snapshots at all - if
+/// every one of them is damaged, starting over would re-announce the whole shop.
+fn newest_intact_snapshot() -> Result<Option<Snapshot>> {
+    let snapshots = list_snapshots()?;
+    for (_, path) in snapshots.iter().rev() {
+        match load_snapshot(path) {
+            Ok(snapshot) => {
+                warn!(
+                    "Falling back to the newest intact snapshot, {}",
+                    path.display()
+                );
+                return Ok(Some(snapshot));
+            }
+            Err(e) => warn!("Skipping damaged snapshot: {e:#}"),
+        }
+    }
+    if snapshots.is_empty() {
+        Ok(None)
+    } else {
+        Err(eyre!(
+            "none of the {} snapshots in {} can be loaded",
+            snapshots.len(),
+            CONFIG.storage_path.display()
+        ))
+    }
+}
+
+/// Reads a snapshot file as raw JSON, in whatever schema it was written, after checking it
+/// against its checksum. Compressed snapshots are unpacked.
+pub fn read_snapshot_json(path: &Path) -> Result<Value> {
+    let read = || -> Result<Value> {
+        let bytes = fs::read(path)?;
+        match fs::read_to_string(checksum_path(path)) {
+            Ok(expected) => {
+                let actual = hex::encode(Sha256::digest(&bytes));
+                if actual != expected.trim() {
+                    return Err(eyre!("checksum mismatch - the file is damaged"));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if is_packed(path) {
+            unpack(&bytes)
+        } else {
+            Ok(serde_json::from_slice(&bytes)?)
+        }
+    };
+    read().wrap_err_with(|| format!("in {}", path.display()))
+}
+
+/// Reads a snapshot of any schema version, upgrading it to the current one.
+pub fn load_snapshot(path: &Path) -> Result<Snapshot> {
+    let upgraded = migrate::upgrade(read_snapshot_json(path)?)
+        .wrap_err_with(|| format!("in {}", path.display()))?;
+    Ok(serde_json::from_value(upgraded)?)
+}
+
+pub fn snapshot_to_json(snapshot: &Snapshot) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&SnapshotFile {
+        schema_version: migrate::SCHEMA_VERSION,
+        snapshot,
+    })?)
+}
+
+/// Writes the snapshot taken at `ts` and makes it the latest. Writing the same one twice is
+/// harmless. The pointer only moves once the snapshot is safely on disk.
+pub fn write_snapshot(ts: TimeStamp, snapshot: &Snapshot) -> Result<()> {
+    let snap_path = snapshot_file_name(ts);
     fs::create_dir_all(&CONFIG.storage_path)?;
-    fs::write(
-        CONFIG.storage_path.join(&snap_path),
-        serde_json::to_string_pretty(&items)?,
-    )?;
-    fs::write(
-        CONFIG.storage_path.join(LATEST_SNAPSHOT_POINTER_PATH),
+    write_snapshot_file(&CONFIG.storage_path.join(&snap_path), snapshot)?;
+    write_atomically(
+        &CONFIG.storage_path.join(LATEST_SNAPSHOT_POINTER_PATH),
         snap_path,
     )?;
+    crate::history::record_snapshot(ts, &snapshot.items)?;
+    Ok(())
+}
+
+const SNAPSHOT_TIME_FORMAT: &str = "%Y-%m-%d-%H:%M:%S";
+
+fn snapshot_file_name(ts: TimeStamp) -> String {
+    format!(
+        "snap_{}.json",
+        time_format::strftime_utc(SNAPSHOT_TIME_FORMAT, ts).unwrap()
+    )
+}
+
+/// Where the snapshot taken at `ts` goes once it's compressed.
+pub fn packed_snapshot_path(ts: TimeStamp) -> PathBuf {
+    CONFIG
+        .storage_path
+        .join(format!("{}.gz", snapshot_file_name(ts)))
+}
+
+/// Reads the UTC time back out of a `snap_%Y-%m-%d-%H:%M:%S.json` (or `.json.gz`) file name.
+pub fn parse_snapshot_timestamp(file_name: &str) -> Option<TimeStamp> {
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let stamp = file_name.strip_prefix("snap_")?.strip_suffix(".json")?;
+    let (date, time) = stamp.split_at_checked(10)?;
+    let mut date = date.split('-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.strip_prefix('-')?.split(':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    Some(utc_timestamp(year, month, day) + hour * 3600 + minute * 60 + second)
+}
+
+/// The timestamp of midnight UTC on a proleptic Gregorian date (Howard Hinnant's
+/// `days_from_civil`).
+pub fn utc_timestamp(year: i64, month: i64, day: i64) -> TimeStamp {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    days * 86400
+}
+
+/// Keeps the diff that led to the snapshot taken at `ts` next to the snapshots, for the HTTP API.
+pub fn write_diff(ts: TimeStamp, diff: &ItemDiff) -> Result<()> {
+    let dir = CONFIG.storage_path.join(DIFFS_PATH);
+    fs::create_dir_all(&dir)?;
+    write_atomically(
+        &dir.join(format!(
+            "diff_{}.json",
+            time_format::strftime_utc(SNAPSHOT_TIME_FORMAT, ts).unwrap()
+        )),
+        serde_json::to_string_pretty(diff)?,
+    )?;
     Ok(())
 }
 
+pub fn load_latest_diff() -> Result<Option<ItemDiff>> {
+    let dir = CONFIG.storage_path.join(DIFFS_PATH);
+    if !dir.exists() {
+        return Ok(None);
+    }
+    // the timestamp format sorts chronologically, so the largest name is the newest diff.
+    let latest = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .max();
+    latest.map(|path| load_diff(&path)).transpose()
+}
+
+pub fn load_diff(path: &Path) -> Result<ItemDiff> {
+    Ok(serde_json::from_reader(File::open(path)?)?)
+}
+
+/// Every snapshot in the storage directory, oldest first. If a crash while compressing left a
+/// snapshot both plain and compressed, the plain one is listed.
+pub fn list_snapshots() -> Result<Vec<(TimeStamp, PathBuf)>> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(&CONFIG.storage_path)? {
+        let entry = entry?;
+        if let Some(ts) = entry
+            .file_name()
+            .to_str()
+            .and_then(parse_snapshot_timestamp)
+        {
+            snapshots.push((ts, entry.path()));
+        }
+    }
+    snapshots.sort_by_key(|(ts, path)| (*ts, is_packed(path)));
+    snapshots.dedup_by_key(|(ts, _)| *ts);
+    Ok(snapshots)
+}
+
+/// Every stored diff, oldest first.
+pub fn list_diffs() -> Result<Vec<(TimeStamp, PathBuf)>> {
+    let dir = CONFIG.storage_path.join(DIFFS_PATH);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut diffs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(ts) = entry.file_name().to_str().and_then(|name| {
+            parse_snapshot_timestamp(&format!("snap_{}", name.strip_prefix("diff_")?))
+        }) {
+            diffs.push((ts, entry.path()));
+        }
+    }
+    diffs.sort_by_key(|(ts, _)| *ts);
+    Ok(diffs)
+}
+
 pub static CDN_CACHE_DB: Lazy<Db> = Lazy::new(|| {
     Config::new()
         .path(CONFIG.storage_path.join(CDN_CACHE_PATH))
@@ -54,15 +510,14 @@ pub static CDN_CACHE_DB: Lazy<Db> = Lazy::new(|| {
         .unwrap()
 });
 
-static UPLOAD_ONCE: Lazy<DashMap<usize, Arc<once_cell::sync::OnceCell<Url>>>> =
-    Lazy::new(DashMap::new);
+static UPLOAD_ONCE: Lazy<DashMap<usize, Arc<tokio::sync::OnceCell<Url>>>> = Lazy::new(DashMap::new);
 
 #[derive(Deserialize)]
 struct CdnResponse {
     url: Url,
 }
 
-pub fn upload_to_cdn(image_id: usize, image_url: &Url) -> Result<Url> {
+pub async fn upload_to_cdn(image_id: usize, image_url: &Url) -> Result<Url> {
     let key = image_id.to_le_bytes();
 
     if let Some(cached) = CDN_CACHE_DB.get(key)? {
@@ -74,27 +529,40 @@ pub fn upload_to_cdn(image_id: usize, image_url: &Url) -> Result<Url> {
     debug!("Didn't find {image_url} (blob ID: {image_id}) - uploading to CDN.");
     let cell = UPLOAD_ONCE
         .entry(image_id)
-        .or_insert_with(|| Arc::new(once_cell::sync::OnceCell::new()))
+        .or_insert_with(|| Arc::new(tokio::sync::OnceCell::new()))
         .clone();
 
     // only runs once per image_id.
-    let file = CLIENT.get(image_url.clone()).send()?.bytes()?.to_vec();
-    let ext = ext_from_url(image_url).ok_or_else(|| {
-        eyre!("when trying to upload {image_url}, I couldn't get the file extension")
-    })?;
-    let form = Form::new().part("file", Part::bytes(file).file_name(format!("image.{ext}")));
-
-    let cdn_url = cell.get_or_try_init(|| {
-        let json: CdnResponse = CLIENT
-            .post(CONFIG.cdn_base_url.clone())
-            .multipart(form)
-            .bearer_auth(&CONFIG.cdn_key)
-            .send()?
+    let cdn_url = cell
+        .get_or_try_init(|| async {
+            let file = retry::send(&RetryPolicy::FLAVORTOWN, || CLIENT.get(image_url.clone()))
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?
+                .to_vec();
+            let ext = ext_from_url(image_url).ok_or_else(|| {
+                eyre!("when trying to upload {image_url}, I couldn't get the file extension")
+            })?;
+
+            let json: CdnResponse = retry::send(&RetryPolicy::CDN, || {
+                let form = Form::new().part(
+                    "file",
+                    Part::bytes(file.clone()).file_name(format!("image.{ext}")),
+                );
+                CLIENT
+                    .post(CONFIG.cdn_base_url.clone())
+                    .multipart(form)
+                    .bearer_auth(&CONFIG.cdn_key)
+            })
+            .await?
             .error_for_status()?
-            .json()?;
-        CDN_CACHE_DB.insert(key, json.url.as_str().as_bytes())?;
-        Ok::<Url, color_eyre::eyre::ErrReport>(json.url)
-    })?;
+            .json()
+            .await?;
+            CDN_CACHE_DB.insert(key, json.url.as_str().as_bytes())?;
+            Ok::<Url, color_eyre::eyre::ErrReport>(json.url)
+        })
+        .await?;
 
     Ok(cdn_url.clone())
 }
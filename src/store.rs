@@ -0,0 +1,141 @@
+//! Where snapshots and the history derived from them are kept, chosen with `STORAGE_BACKEND`.
+//!
+//! - `files` (default): a `snap_*.json` file per snapshot plus `latest-snapshot.ptr`, with item
+//!   history in the `history.sled` index. See [`crate::storage`] and [`crate::history`].
+//! - `sqlite`: one SQLite database with a table per kind of data, see [`crate::sqlite`].
+//!
+//! Diffs are kept by the backend too. The CDN cache and the outbox are sled databases in the
+//! storage directory either way.
+
+use std::collections::BTreeMap;
+
+use crate::config::CONFIG;
+use crate::diff::ItemDiff;
+use crate::history::{self, PricePoint};
+use crate::scraper::{Region, ShopItemId};
+use crate::sqlite::SqliteStore;
+use crate::storage::{self, Snapshot};
+use color_eyre::Result;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use time_format::TimeStamp;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Files,
+    Sqlite,
+}
+
+/// Everything stored about one item across every snapshot.
+#[derive(Debug, Clone)]
+pub struct ItemHistory {
+    pub first_seen: TimeStamp,
+    /// The latest snapshot in which the item wasn't out of stock. If that's the newest snapshot,
+    /// the item is still in stock now.
+    pub last_in_stock: Option<TimeStamp>,
+    /// The item's price at every snapshot it was listed in, per region, oldest first.
+    pub prices: BTreeMap<Region, Vec<PricePoint>>,
+}
+
+pub trait SnapshotStore: Send + Sync {
+    /// The snapshot the next run is compared against. `None` before the first one.
+    fn load_latest(&self) -> Result<Option<Snapshot>>;
+
+    /// Stores the snapshot taken at `at` and makes it the latest. Writing the same one twice is
+    /// harmless.
+    fn write(&self, at: TimeStamp, snapshot: &Snapshot) -> Result<()>;
+
+    /// When every stored snapshot was taken, oldest first.
+    fn list(&self) -> Result<Vec<TimeStamp>>;
+
+    /// The snapshot taken at exactly `at`.
+    fn get(&self, at: TimeStamp) -> Result<Option<Snapshot>>;
+
+    /// `None` if the item has never been seen.
+    fn item_history(&self, item_id: ShopItemId) -> Result<Option<ItemHistory>>;
+
+    /// Keeps the diff that led to the snapshot taken at `at`, for the API and the feeds.
+    fn write_diff(&self, at: TimeStamp, diff: &ItemDiff) -> Result<()>;
+
+    /// When the snapshot of every stored diff was taken, oldest first.
+    fn list_diffs(&self) -> Result<Vec<TimeStamp>>;
+
+    /// The diff that led to the snapshot taken at exactly `at`.
+    fn get_diff(&self, at: TimeStamp) -> Result<Option<ItemDiff>>;
+
+    /// The newest diff. `None` until a run has found changes or not.
+    fn load_latest_diff(&self) -> Result<Option<ItemDiff>> {
+        match self.list_diffs()?.last() {
+            Some(at) => self.get_diff(*at),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct FileStore;
+
+impl SnapshotStore for FileStore {
+    fn load_latest(&self) -> Result<Option<Snapshot>> {
+        storage::load_latest_snapshot()
+    }
+
+    fn write(&self, at: TimeStamp, snapshot: &Snapshot) -> Result<()> {
+        storage::write_snapshot(at, snapshot)
+    }
+
+    fn list(&self) -> Result<Vec<TimeStamp>> {
+        Ok(storage::list_snapshots()?
+            .into_iter()
+            .map(|(at, _)| at)
+            .collect())
+    }
+
+    fn get(&self, at: TimeStamp) -> Result<Option<Snapshot>> {
+        storage::list_snapshots()?
+            .into_iter()
+            .find(|(ts, _)| *ts == at)
+            .map(|(_, path)| storage::load_snapshot(&path))
+            .transpose()
+    }
+
+    fn item_history(&self, item_id: ShopItemId) -> Result<Option<ItemHistory>> {
+        let Some(first_seen) = history::first_seen(item_id)? else {
+            return Ok(None);
+        };
+        Ok(Some(ItemHistory {
+            first_seen,
+            last_in_stock: history::last_in_stock(item_id)?,
+            prices: history::price_timelines(item_id)?,
+        }))
+    }
+
+    fn write_diff(&self, at: TimeStamp, diff: &ItemDiff) -> Result<()> {
+        storage::write_diff(at, diff)
+    }
+
+    fn list_diffs(&self) -> Result<Vec<TimeStamp>> {
+        Ok(storage::list_diffs()?
+            .into_iter()
+            .map(|(at, _)| at)
+            .collect())
+    }
+
+    fn get_diff(&self, at: TimeStamp) -> Result<Option<ItemDiff>> {
+        storage::list_diffs()?
+            .into_iter()
+            .find(|(ts, _)| *ts == at)
+            .map(|(_, path)| storage::load_diff(&path))
+            .transpose()
+    }
+
+    fn load_latest_diff(&self) -> Result<Option<ItemDiff>> {
+        storage::load_latest_diff()
+    }
+}
+
+pub static STORE: Lazy<Box<dyn SnapshotStore>> = Lazy::new(|| match CONFIG.storage_backend {
+    StorageBackend::Files => Box::new(FileStore),
+    StorageBackend::Sqlite => Box::new(SqliteStore::open().expect("failed to open the database")),
+});
@@ -0,0 +1,81 @@
+//! Short AI-written summaries of long description changes, which are usually too long to show
+//! side by side. Only used when the `OPENAI_*` env vars are set.
+
+use crate::config::CONFIG;
+use crate::diff::{FieldChange, ItemDiff};
+use crate::fetch::FETCHER;
+use crate::retry::{self, RetryPolicy};
+use log::debug;
+
+async fn summarize_long_description_change(
+    item_title: &str,
+    old_desc: Option<&String>,
+    new_desc: Option<&String>,
+) -> Option<String> {
+    if FETCHER.is_offline() {
+        return None;
+    }
+    let api_key = CONFIG.openai_api_key.as_ref()?;
+    let model = CONFIG.openai_model.as_ref()?;
+    let base_url = CONFIG.openai_base_url.as_ref()?;
+
+    let old_text = old_desc.map(|s| s.as_str()).unwrap_or("(empty)");
+    let new_text = new_desc.map(|s| s.as_str()).unwrap_or("(empty)");
+
+    let prompt = format!(
+        "An item called \"{item_title}\" in a shop had its description changed.\n\n\
+         OLD DESCRIPTION:\n{old_text}\n\n\
+         NEW DESCRIPTION:\n{new_text}\n\n\
+         Write 1-2 sentences summarizing what specifically changed. \
+         Be concrete: mention specific names, numbers, specs, vendors, etc. that were added, removed, or changed. \
+         Do NOT say \"the description was updated\" - say WHAT changed. \
+         Keep it short."
+    );
+
+    let url = format!(
+        "{}chat/completions",
+        base_url.as_str().trim_end_matches('/').to_owned() + "/"
+    );
+
+    let body = serde_json::json!({
+        "model": model,
+        "messages": [
+            {"role": "user", "content": prompt}
+        ],
+        "max_tokens": 150,
+        "temperature": 0.3
+    });
+
+    let response = retry::send(&RetryPolicy::OPENAI, || {
+        crate::scraper::CLIENT
+            .post(&url)
+            .header("Authorization", format!("Bearer {api_key}"))
+            .json(&body)
+    })
+    .await
+    .ok()?;
+
+    if !response.status().is_success() {
+        log::warn!("OpenAI API returned status {}", response.status());
+        return None;
+    }
+
+    let json: serde_json::Value = response.json().await.ok()?;
+    json["choices"][0]["message"]["content"]
+        .as_str()
+        .map(|s| s.trim().to_string())
+}
+
+/// Fills in `summary` on every long description change in the diff.
+pub async fn summarize_long_descriptions(diff: &mut ItemDiff) {
+    for item in &mut diff.updated_items {
+        for change in &mut item.changes {
+            if let FieldChange::LongDescription { old, new, summary } = change {
+                *summary =
+                    summarize_long_description_change(&item.new.title, old.as_ref(), new.as_ref())
+                        .await;
+                debug!("Summary for {}: {summary:?}", item.new.title);
+            }
+        }
+    }
+}
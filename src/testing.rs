@@ -0,0 +1,146 @@
+//! Shared setup for tests. `CONFIG` is read from the environment once per process, so every test
+//! that touches it calls [`init`] first, and they all get the same throwaway storage directory,
+//! local Slack stub and replayed Flavortown.
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Mutex, Once};
+
+use crate::scraper::{Region, ShopItem, ShopItemId};
+use once_cell::sync::Lazy;
+use serde_json::{Value, json};
+use tiny_http::{Header, Response, Server};
+
+/// The `NOTIFIERS` sink whose token the Slack stub accepts.
+pub const SLACK_SINK: &str = "slack-test";
+pub const SLACK_TOKEN: &str = "xoxb-test";
+
+/// Two recorded runs, see `fetch::tests`.
+const REPLAY_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/replay");
+
+static INIT: Once = Once::new();
+
+/// The outbox is shared by the whole test binary and delivering sends all of it, so tests that
+/// queue messages take turns.
+pub static OUTBOX: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+static STORAGE: Lazy<PathBuf> = Lazy::new(|| {
+    tempfile::Builder::new()
+        .prefix("flavortown-test-")
+        .tempdir()
+        .unwrap()
+        .keep()
+});
+
+pub fn init() {
+    INIT.call_once(|| {
+        let notifiers = json!([{
+            "name": SLACK_SINK,
+            "type": "slack",
+            "token": SLACK_TOKEN,
+            "channel": "C0TEST",
+        }]);
+        // SAFETY: this runs before anything in the test binary reads the environment.
+        unsafe {
+            std::env::set_var("STORAGE_PATH", &*STORAGE);
+            std::env::set_var("SLACK_API_URL", &SLACK_STUB.url);
+            std::env::set_var("NOTIFIERS", notifiers.to_string());
+            std::env::set_var("MAX_REQUESTS_PER_SECOND", "0");
+            std::env::set_var("REPLAY_DIR", REPLAY_DIR);
+            std::env::set_var("REGION_SWITCH_DELAY_MS", "0");
+        }
+    });
+}
+
+/// A call the Slack stub received, and the `ts` it answered with.
+#[derive(Debug, Clone)]
+pub struct SlackCall {
+    pub method: String,
+    pub body: Value,
+    pub ts: Option<String>,
+}
+
+/// Just enough of Slack's Web API for `chat.postMessage` and `chat.update`.
+pub struct SlackStub {
+    pub url: String,
+    calls: Mutex<Vec<SlackCall>>,
+}
+
+impl SlackStub {
+    /// The calls made to `channel` so far, oldest first.
+    pub fn calls(&self, channel: &str) -> Vec<SlackCall> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| call.body["channel"] == channel)
+            .cloned()
+            .collect()
+    }
+}
+
+pub static SLACK_STUB: Lazy<SlackStub> = Lazy::new(|| {
+    let server = Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/api", server.server_addr().to_ip().unwrap());
+    std::thread::spawn(move || {
+        let mut posted = HashSet::new();
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let body: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+            let method = request.url().rsplit('/').next().unwrap().to_string();
+            let authorized = request.headers().iter().any(|h| {
+                h.field.equiv("Authorization") && h.value == format!("Bearer {SLACK_TOKEN}")
+            });
+
+            let mut ts = None;
+            let answer = match method.as_str() {
+                _ if !authorized => json!({"ok": false, "error": "invalid_auth"}),
+                "chat.postMessage" => {
+                    let new_ts = format!("1700000000.{:06}", posted.len() + 1);
+                    posted.insert(new_ts.clone());
+                    ts = Some(new_ts.clone());
+                    json!({"ok": true, "channel": body["channel"], "ts": new_ts})
+                }
+                "chat.update" if body["ts"].as_str().is_some_and(|ts| posted.contains(ts)) => {
+                    json!({"ok": true, "channel": body["channel"], "ts": body["ts"]})
+                }
+                "chat.update" => json!({"ok": false, "error": "message_not_found"}),
+                _ => json!({"ok": false, "error": "unknown_method"}),
+            };
+            SLACK_STUB
+                .calls
+                .lock()
+                .unwrap()
+                .push(SlackCall { method, body, ts });
+
+            let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+            let _ = request
+                .respond(Response::from_string(answer.to_string()).with_header(content_type));
+        }
+    });
+    SlackStub {
+        url,
+        calls: Mutex::new(Vec::new()),
+    }
+});
+
+/// An item sold in the given regions (by code) at the given prices.
+pub fn item(id: ShopItemId, title: &str, prices: &[(&str, u32)]) -> ShopItem {
+    ShopItem {
+        title: title.to_string(),
+        description: format!("{title}, but in the shop"),
+        prices: prices
+            .iter()
+            .map(|(code, price)| (Region::from_code(code).unwrap(), *price))
+            .collect::<HashMap<_, _>>(),
+        image_url: "https://flavortown.hackclub.com/image.png".parse().unwrap(),
+        image_id: id,
+        id,
+        long_description: None,
+        accessories: Vec::new(),
+        remaining_stock: None,
+        achievement_lock: None,
+        incomplete_regions: Vec::new(),
+    }
+}
@@ -0,0 +1,365 @@
+//! Subscriptions: who wants to hear about which items, and when.
+//!
+//! A subscription names a Slack and/or Discord user, the items it's about (by ID or title), a
+//! region and a condition. Subscriptions are kept in `watchlist.json` in the storage folder and
+//! managed with the `watch` command, which doesn't need the storage lock, so it works while the
+//! daemon is running. Changes take a lock of their own instead, so two at once can't undo each
+//! other. The file is read again for every diff.
+//!
+//! Once anyone has subscribed with a Slack user, Slack messages stop pinging the whole channel and
+//! mention only the users whose conditions the diff meets instead. Discord works the same way with
+//! Discord users.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+
+use crate::config::CONFIG;
+use crate::diff::{ItemDiff, UpdatedItem};
+use crate::notify::SinkType;
+use crate::scraper::{Region, ShopItem, ShopItemId};
+use crate::storage;
+use color_eyre::{Result, eyre::eyre};
+use serde::{Deserialize, Serialize};
+
+pub const WATCHLIST_PATH: &str = "watchlist.json";
+const LOCK_PATH: &str = "watchlist.lock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// The item's price in the subscription's region drops below this many cookies.
+    PriceBelow { price: u32 },
+    /// The item was out of stock and isn't any more.
+    BackInStock,
+    /// The item no longer needs an achievement to be bought.
+    LockRemoved,
+}
+
+/// Parses `--when`: `below:N`, `back-in-stock` or `lock-removed`.
+pub fn parse_condition(s: &str) -> Result<Condition> {
+    match s {
+        "back-in-stock" => Ok(Condition::BackInStock),
+        "lock-removed" => Ok(Condition::LockRemoved),
+        _ => s
+            .strip_prefix("below:")
+            .and_then(|price| price.parse().ok())
+            .map(|price| Condition::PriceBelow { price })
+            .ok_or_else(|| eyre!("expected below:N, back-in-stock or lock-removed")),
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PriceBelow { price } => write!(f, "below:{price}"),
+            Self::BackInStock => write!(f, "back-in-stock"),
+            Self::LockRemoved => write!(f, "lock-removed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: u64,
+    /// Slack member ID, like `U012AB3CD`.
+    #[serde(default)]
+    pub slack_user: Option<String>,
+    #[serde(default)]
+    pub discord_user: Option<String>,
+    /// Every item is watched if this and `title_contains` are both empty.
+    #[serde(default)]
+    pub item_ids: Vec<ShopItemId>,
+    /// Case-insensitive substrings of the item title.
+    #[serde(default)]
+    pub title_contains: Vec<String>,
+    pub region: Region,
+    pub condition: Condition,
+}
+
+/// A subscriber to mention, and why.
+#[derive(Debug, Clone)]
+pub struct Match {
+    pub item_id: ShopItemId,
+    pub slack_user: Option<String>,
+    pub discord_user: Option<String>,
+    pub reason: String,
+}
+
+impl Subscription {
+    /// Whether there's someone to mention in messages to sinks of this kind.
+    pub const fn notifies(&self, kind: SinkType) -> bool {
+        match kind {
+            SinkType::Slack => self.slack_user.is_some(),
+            SinkType::Discord => self.discord_user.is_some(),
+            SinkType::Json => false,
+        }
+    }
+
+    fn watches(&self, item: &ShopItem) -> bool {
+        (self.item_ids.is_empty() && self.title_contains.is_empty())
+            || self.item_ids.contains(&item.id)
+            || self
+                .title_contains
+                .iter()
+                .any(|needle| item.title.to_lowercase().contains(&needle.to_lowercase()))
+    }
+
+    /// The price condition only fires when the price crosses the line, so a cheap item doesn't
+    /// ping on every later change.
+    fn price_below(&self, old: Option<&ShopItem>, new: &ShopItem) -> Option<String> {
+        let Condition::PriceBelow { price: limit } = self.condition else {
+            return None;
+        };
+        let price = *new.prices.get(&self.region)?;
+        let was_below = old
+            .and_then(|old| old.prices.get(&self.region))
+            .is_some_and(|&old| old < limit);
+        (price < limit && !was_below).then(|| {
+            format!(
+                "{} costs {price} cookies in {}, under {limit}",
+                new.title, self.region
+            )
+        })
+    }
+
+    fn reason(&self, old: Option<&ShopItem>, new: &ShopItem) -> Option<String> {
+        if !self.watches(new) {
+            return None;
+        }
+        match self.condition {
+            Condition::PriceBelow { .. } => self.price_below(old, new),
+            Condition::BackInStock => (new.prices.contains_key(&self.region)
+                && old?.remaining_stock == Some(0)
+                && new.remaining_stock != Some(0))
+            .then(|| format!("{} is back in stock", new.title)),
+            Condition::LockRemoved => (new.prices.contains_key(&self.region)
+                && old?
+                    .achievement_lock
+                    .as_deref()
+                    .is_some_and(|l| !l.is_empty())
+                && new.achievement_lock.as_deref().is_none_or(str::is_empty))
+            .then(|| format!("{} no longer needs an achievement", new.title)),
+        }
+    }
+
+    fn matches(&self, diff: &ItemDiff) -> Vec<Match> {
+        let new = diff.new_items.iter().map(|item| (None, item));
+        let updated = diff
+            .updated_items
+            .iter()
+            .map(|UpdatedItem { old, new, .. }| (Some(old), new));
+        new.chain(updated)
+            .filter_map(|(old, new)| Some((new.id, self.reason(old, new)?)))
+            .map(|(item_id, reason)| Match {
+                item_id,
+                slack_user: self.slack_user.clone(),
+                discord_user: self.discord_user.clone(),
+                reason,
+            })
+            .collect()
+    }
+}
+
+/// Every subscriber `diff` meets the condition of, once per item.
+pub fn matches(subscriptions: &[Subscription], diff: &ItemDiff) -> Vec<Match> {
+    subscriptions
+        .iter()
+        .flat_map(|subscription| subscription.matches(diff))
+        .collect()
+}
+
+pub fn load() -> Result<Vec<Subscription>> {
+    let path = CONFIG.storage_path.join(WATCHLIST_PATH);
+    match std::fs::read(&path) {
+        Ok(json) => serde_json::from_slice(&json)
+            .map_err(|e| eyre!("{} is not a valid watchlist: {e}", path.display())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Waits for any other change to the watchlist to finish. Released when the file is dropped.
+fn lock() -> Result<File> {
+    std::fs::create_dir_all(&CONFIG.storage_path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(CONFIG.storage_path.join(LOCK_PATH))?;
+    file.lock()?;
+    Ok(file)
+}
+
+fn save(subscriptions: &[Subscription]) -> Result<()> {
+    storage::write_atomically(
+        &CONFIG.storage_path.join(WATCHLIST_PATH),
+        serde_json::to_vec_pretty(subscriptions)?,
+    )
+}
+
+/// Stores `subscription` under a new ID, which is returned.
+pub fn add(mut subscription: Subscription) -> Result<u64> {
+    let _lock = lock()?;
+    let mut subscriptions = load()?;
+    subscription.id = subscriptions.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+    let id = subscription.id;
+    subscriptions.push(subscription);
+    save(&subscriptions)?;
+    Ok(id)
+}
+
+/// Whether there was a subscription with that ID.
+pub fn remove(id: u64) -> Result<bool> {
+    let _lock = lock()?;
+    let mut subscriptions = load()?;
+    let count = subscriptions.len();
+    subscriptions.retain(|s| s.id != id);
+    if subscriptions.len() == count {
+        return Ok(false);
+    }
+    save(&subscriptions)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn subscription(condition: Condition) -> Subscription {
+        Subscription {
+            id: 1,
+            slack_user: Some("U012AB3CD".to_string()),
+            discord_user: None,
+            item_ids: vec![1],
+            title_contains: Vec::new(),
+            region: Region::from_code("US").unwrap(),
+            condition,
+        }
+    }
+
+    fn changed(old: ShopItem, new: ShopItem) -> ItemDiff {
+        ItemDiff {
+            new_items: Vec::new(),
+            updated_items: vec![UpdatedItem {
+                changes: Vec::new(),
+                old,
+                new,
+            }],
+            deleted_items: Vec::new(),
+            new_regions: Vec::new(),
+            removed_regions: Vec::new(),
+        }
+    }
+
+    fn reasons(condition: Condition, old: ShopItem, new: ShopItem) -> Vec<String> {
+        matches(&[subscription(condition)], &changed(old, new))
+            .into_iter()
+            .map(|m| m.reason)
+            .collect()
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(
+            parse_condition("below:95").unwrap(),
+            Condition::PriceBelow { price: 95 }
+        );
+        assert_eq!(
+            parse_condition("back-in-stock").unwrap(),
+            Condition::BackInStock
+        );
+        assert_eq!(
+            parse_condition("lock-removed").unwrap(),
+            Condition::LockRemoved
+        );
+        for bad in [
+            "below:",
+            "below:-1",
+            "below:cheap",
+            "below 95",
+            "in-stock",
+            "",
+        ] {
+            assert!(parse_condition(bad).is_err(), "{bad}");
+        }
+        for condition in ["below:95", "back-in-stock", "lock-removed"] {
+            assert_eq!(parse_condition(condition).unwrap().to_string(), condition);
+        }
+    }
+
+    #[test]
+    fn matches_when_the_price_drops_below() {
+        let below = Condition::PriceBelow { price: 100 };
+        let item = |price| testing::item(1, "Pen", &[("US", price)]);
+        assert_eq!(
+            reasons(below, item(120), item(90)),
+            ["Pen costs 90 cookies in United States, under 100"]
+        );
+        // already under, or not under.
+        assert!(reasons(below, item(95), item(90)).is_empty());
+        assert!(reasons(below, item(120), item(100)).is_empty());
+        // another item, or another region.
+        let other = testing::item(2, "Pen", &[("US", 90)]);
+        assert!(reasons(below, item(120), other).is_empty());
+        let uk = testing::item(1, "Pen", &[("UK", 90)]);
+        assert!(reasons(below, item(120), uk).is_empty());
+    }
+
+    #[test]
+    fn matches_when_back_in_stock() {
+        let item = |stock| ShopItem {
+            remaining_stock: stock,
+            ..testing::item(1, "Pen", &[("US", 100)])
+        };
+        assert_eq!(
+            reasons(Condition::BackInStock, item(Some(0)), item(Some(3))),
+            ["Pen is back in stock"]
+        );
+        assert_eq!(
+            reasons(Condition::BackInStock, item(Some(0)), item(None)).len(),
+            1
+        );
+        assert!(reasons(Condition::BackInStock, item(Some(2)), item(Some(3))).is_empty());
+        assert!(reasons(Condition::BackInStock, item(Some(0)), item(Some(0))).is_empty());
+    }
+
+    #[test]
+    fn matches_when_the_lock_is_removed() {
+        let item = |lock: Option<&str>| ShopItem {
+            achievement_lock: lock.map(String::from),
+            ..testing::item(1, "Pen", &[("US", 100)])
+        };
+        assert_eq!(
+            reasons(Condition::LockRemoved, item(Some("Chef")), item(None)),
+            ["Pen no longer needs an achievement"]
+        );
+        assert_eq!(
+            reasons(Condition::LockRemoved, item(Some("Chef")), item(Some(""))).len(),
+            1
+        );
+        assert!(reasons(Condition::LockRemoved, item(None), item(None)).is_empty());
+        assert!(
+            reasons(
+                Condition::LockRemoved,
+                item(Some("Chef")),
+                item(Some("Chef"))
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn concurrent_adds_keep_every_subscription() {
+        testing::init();
+        let threads: Vec<_> = (0..8)
+            .map(|_| std::thread::spawn(|| add(subscription(Condition::BackInStock)).unwrap()))
+            .collect();
+        let mut ids: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 8);
+        assert_eq!(load().unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,87 @@
+//! Runs the tracker binary against a storage directory of its own.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+pub fn fixture(path: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(path)
+}
+
+pub struct Tracker {
+    dir: TempDir,
+    env: Vec<(String, String)>,
+}
+
+impl Tracker {
+    pub fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+            env: Vec::new(),
+        }
+    }
+
+    pub fn env(mut self, name: &str, value: impl AsRef<Path>) -> Self {
+        let value = value.as_ref().to_string_lossy().into_owned();
+        self.env.push((name.to_string(), value));
+        self
+    }
+
+    pub fn storage(&self) -> PathBuf {
+        self.dir.path().join("storage")
+    }
+
+    /// The tracker with `args`, ready to run. It runs in the temp directory, so no `.env` is
+    /// picked up.
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_flavortown_tracker"));
+        command
+            .args(args)
+            .current_dir(self.dir.path())
+            .env("STORAGE_PATH", self.storage())
+            .env("RUST_LOG", "info")
+            .env("REGION_SWITCH_DELAY_MS", "0");
+        for name in [
+            "COOKIE",
+            "COOKIES",
+            "NOTIFIERS",
+            "WEBHOOK_URL",
+            "DISCORD_WEBHOOK_URL",
+        ] {
+            command.env_remove(name);
+        }
+        command.envs(self.env.iter().map(|(k, v)| (k, v)));
+        command
+    }
+
+    /// Runs the tracker to completion, failing the test if it fails.
+    pub fn run(&self, args: &[&str]) -> Output {
+        let output = self.command(args).output().unwrap();
+        assert!(
+            output.status.success(),
+            "{args:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Copies a snapshot fixture into storage under the file name for `stamp`
+    /// (`%Y-%m-%d-%H:%M:%S`).
+    pub fn add_snapshot(&self, fixture_path: &str, stamp: &str) -> PathBuf {
+        fs::create_dir_all(self.storage()).unwrap();
+        let path = self.storage().join(format!("snap_{stamp}.json"));
+        fs::copy(fixture(fixture_path), &path).unwrap();
+        path
+    }
+
+    pub fn point_latest_at(&self, snapshot: &Path) {
+        let name = snapshot.file_name().unwrap().to_str().unwrap();
+        fs::write(self.storage().join("latest-snapshot.ptr"), name).unwrap();
+    }
+}
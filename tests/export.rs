@@ -0,0 +1,65 @@
+mod common;
+
+use std::fs::File;
+use std::thread;
+use std::time::Duration;
+
+use common::Tracker;
+
+/// Two snapshots from January, with the older one compressed by `storage gc`.
+fn with_history() -> Tracker {
+    let tracker = Tracker::new().env("COMPRESS_AFTER_DAYS", "1");
+    tracker.add_snapshot("snapshots/v2.json", "2026-01-01-00:00:00");
+    let latest = tracker.add_snapshot("snapshots/v1.json", "2026-01-02-00:00:00");
+    tracker.point_latest_at(&latest);
+    tracker.run(&["storage", "gc"]);
+    assert!(
+        tracker
+            .storage()
+            .join("snap_2026-01-01-00:00:00.json.gz")
+            .exists()
+    );
+    tracker
+}
+
+/// The lock the daemon holds while it runs.
+fn hold_storage_lock(tracker: &Tracker) -> File {
+    let lock = File::create(tracker.storage().join("tracker.lock")).unwrap();
+    lock.try_lock().unwrap();
+    lock
+}
+
+#[test]
+fn exports_next_to_the_daemon() {
+    let tracker = with_history();
+    let expected = tracker.run(&["export"]).stdout;
+
+    let _lock = hold_storage_lock(&tracker);
+    let run = tracker.command(&["run"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&run.stderr).contains("another tracker is already using"));
+
+    // the daemon has the item records open while it's compressing snapshots.
+    let objects = sled::open(tracker.storage().join("objects.sled")).unwrap();
+    let compressing = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        drop(objects);
+    });
+    let output = tracker.run(&["export"]);
+    compressing.join().unwrap();
+
+    assert_eq!(output.stdout, expected);
+    let csv = String::from_utf8(output.stdout).unwrap();
+    assert!(csv.contains("2026-01-01T00:00:00Z"), "{csv}");
+    assert!(csv.contains("2026-01-02T00:00:00Z"), "{csv}");
+}
+
+#[test]
+fn shows_history_next_to_the_daemon() {
+    let tracker = with_history();
+    let expected = tracker.run(&["history", "1", "--region", "US"]).stdout;
+    assert!(!expected.is_empty());
+
+    let _lock = hold_storage_lock(&tracker);
+    let output = tracker.run(&["history", "1", "--region", "US"]);
+    assert_eq!(output.stdout, expected);
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{Tracker, fixture};
+
+/// Kills the server when the test ends, however it ends.
+struct Serving(Child);
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// The status line and body of a GET, or `None` if nothing is listening yet.
+fn get(addr: &str, path: &str) -> Option<(String, String)> {
+    let mut stream = TcpStream::connect(addr).ok()?;
+    write!(stream, "GET {path} HTTP/1.0\r\nHost: {addr}\r\n\r\n").ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    Some((head.lines().next()?.to_string(), body.to_string()))
+}
+
+#[test]
+fn serves_next_to_the_daemon() {
+    let tracker = Tracker::new().env("REPLAY_DIR", fixture("replay"));
+    tracker.run(&["run"]);
+
+    // the lock the daemon holds while it runs.
+    let lock = File::create(tracker.storage().join("tracker.lock")).unwrap();
+    lock.try_lock().unwrap();
+
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let _server = Serving(
+        tracker
+            .command(&["serve"])
+            .env("HTTP_ADDR", &addr)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    let started = Instant::now();
+    while get(&addr, "/snapshots").is_none() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "serve never started"
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // many more clients than workers, all answered.
+    let clients: Vec<_> = (0..32)
+        .map(|_| {
+            let addr = addr.clone();
+            thread::spawn(move || get(&addr, "/items/1/history").unwrap())
+        })
+        .collect();
+    for client in clients {
+        let (status, body) = client.join().unwrap();
+        assert!(status.ends_with(" 200 OK"), "{status}: {body}");
+        assert!(body.contains("\"item_id\":1"), "{body}");
+    }
+}
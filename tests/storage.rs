@@ -0,0 +1,165 @@
+mod common;
+
+use std::fs;
+
+use common::{Tracker, fixture};
+use rusqlite::Connection;
+use serde_json::Value;
+
+/// A tracker that replays the first run of `tests/fixtures/replay`: a pen, a mug and a sticker.
+fn replaying() -> Tracker {
+    Tracker::new().env("REPLAY_DIR", fixture("replay")).env(
+        "NOTIFIERS",
+        r#"[{"type": "json", "url": "http://127.0.0.1:9/hook"}]"#,
+    )
+}
+
+/// The item IDs the run's diff announced as new.
+fn new_item_ids(tracker: &Tracker) -> Vec<u64> {
+    let diffs: Vec<_> = fs::read_dir(tracker.storage().join("diffs"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    let [diff] = diffs.as_slice() else {
+        panic!("expected one diff, got {diffs:?}");
+    };
+    let diff: Value = serde_json::from_slice(&fs::read(diff).unwrap()).unwrap();
+    let mut ids: Vec<_> = diff["new_items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["id"].as_u64().unwrap())
+        .collect();
+    ids.sort();
+    ids
+}
+
+/// Only the pen was in the intact snapshot, so everything else is new compared with it.
+fn assert_fell_back(tracker: &Tracker, stderr: &[u8]) {
+    let stderr = String::from_utf8_lossy(stderr);
+    assert!(
+        stderr.contains("Falling back to the newest intact snapshot"),
+        "{stderr}"
+    );
+    assert_eq!(new_item_ids(tracker), [2, 3]);
+}
+
+#[test]
+fn falls_back_when_the_latest_snapshot_fails_its_checksum() {
+    let tracker = replaying();
+    tracker.add_snapshot("snapshots/v2.json", "2026-01-01-00:00:00");
+    let latest = tracker.add_snapshot("snapshots/v1.json", "2026-01-02-00:00:00");
+    fs::write(
+        latest.with_extension("json.sha256"),
+        "0000000000000000000000000000000000000000000000000000000000000000",
+    )
+    .unwrap();
+    tracker.point_latest_at(&latest);
+
+    let output = tracker.run(&["run"]);
+    assert_fell_back(&tracker, &output.stderr);
+}
+
+#[test]
+fn falls_back_when_the_latest_snapshot_is_truncated() {
+    let tracker = replaying();
+    tracker.add_snapshot("snapshots/v2.json", "2026-01-01-00:00:00");
+    let latest = tracker.add_snapshot("snapshots/v1.json", "2026-01-02-00:00:00");
+    let contents = fs::read(&latest).unwrap();
+    fs::write(&latest, &contents[..contents.len() / 2]).unwrap();
+    tracker.point_latest_at(&latest);
+
+    let output = tracker.run(&["run"]);
+    assert_fell_back(&tracker, &output.stderr);
+}
+
+#[test]
+fn gc_refuses_to_run_on_sqlite() {
+    let tracker = Tracker::new().env("STORAGE_BACKEND", "sqlite");
+    let output = tracker.command(&["storage", "gc"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("STORAGE_BACKEND=sqlite"));
+}
+
+#[test]
+fn migrate_refuses_to_run_on_sqlite() {
+    let tracker = Tracker::new().env("STORAGE_BACKEND", "sqlite");
+    let output = tracker.command(&["migrate"]).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("STORAGE_BACKEND=sqlite"));
+}
+
+fn count(database: &Connection, table: &str) -> i64 {
+    database
+        .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+}
+
+#[test]
+fn sqlite_keeps_diffs_in_the_database() {
+    let tracker = replaying();
+    tracker.add_snapshot("snapshots/v2.json", "2026-01-01-00:00:00");
+    tracker.run(&["storage", "import"]);
+    fs::remove_file(tracker.storage().join("snap_2026-01-01-00:00:00.json")).unwrap();
+
+    let tracker = tracker.env("STORAGE_BACKEND", "sqlite");
+    tracker.run(&["run"]);
+
+    assert!(!tracker.storage().join("diffs").exists());
+    let database = Connection::open(tracker.storage().join("snapshots.sqlite3")).unwrap();
+    assert_eq!(count(&database, "snapshots"), 2);
+    assert_eq!(count(&database, "diffs"), 1);
+    let feed = fs::read_to_string(tracker.storage().join("feed.atom")).unwrap();
+    assert!(feed.contains("New: "), "{feed}");
+}
+
+#[test]
+fn imports_snapshots_and_diffs_into_sqlite() {
+    let tracker = replaying();
+    tracker.add_snapshot("snapshots/v0.json", "2026-01-01-00:00:00");
+    tracker.add_snapshot("snapshots/v2.json", "2026-01-02-00:00:00");
+    let damaged = tracker.add_snapshot("snapshots/v1.json", "2026-01-03-00:00:00");
+    fs::write(&damaged, "{").unwrap();
+    // leaves a third intact snapshot and its diff, so the feed has something to import.
+    tracker.run(&["run"]);
+
+    let output = tracker.run(&["storage", "import"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Imported 3 snapshot(s) into snapshots.sqlite3, 0 were already there, 1 damaged. \
+         Imported 1 diff(s)\n"
+    );
+    let database = Connection::open(tracker.storage().join("snapshots.sqlite3")).unwrap();
+    assert_eq!(count(&database, "snapshots"), 3);
+    assert_eq!(count(&database, "diffs"), 1);
+    // the v0 snapshot was upgraded on the way in, so its legacy regions have their codes.
+    let regions: Vec<String> = database
+        .prepare("SELECT code FROM regions WHERE taken_at = ?1 ORDER BY position")
+        .unwrap()
+        .query_map([1767225600], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert!(regions.contains(&"XX".to_string()), "{regions:?}");
+
+    let output = tracker.run(&["storage", "import"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Imported 0 snapshot(s) into snapshots.sqlite3, 3 were already there, 1 damaged. \
+         Imported 0 diff(s)\n"
+    );
+
+    let sqlite = |args: &[&str]| {
+        let output = tracker
+            .command(args)
+            .env("STORAGE_BACKEND", "sqlite")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{args:?} failed");
+        output.stdout
+    };
+    let files = tracker.run(&["export"]).stdout;
+    assert_eq!(sqlite(&["export"]), files);
+}